@@ -0,0 +1,272 @@
+use actix_web::http::header;
+use actix_web::{web, HttpRequest, HttpResponse};
+use rdkafka::producer::FutureProducer;
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::RwLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::connection::{Connection, ConnectionStatus, Message};
+use crate::notification::Notification;
+use crate::server::{send_to_redpanda, store_connection};
+use crate::session::{ServerEvent, SessionRegistry};
+use crate::store::{self, StoreError};
+
+// The token support staff present as `Authorization: Bearer <token>`
+pub struct AdminToken(String);
+
+impl AdminToken {
+    pub fn new(token: String) -> Self {
+        AdminToken(token)
+    }
+
+    pub fn authorizes(&self, req: &HttpRequest) -> bool {
+        let presented = req
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .unwrap_or("");
+        constant_time_eq(presented.as_bytes(), self.0.as_bytes())
+    }
+}
+
+// Compare every byte so response times don't reveal how much of the token matched
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+pub fn unauthorized() -> HttpResponse {
+    HttpResponse::Unauthorized()
+        .insert_header((header::WWW_AUTHENTICATE, "Bearer"))
+        .json(json!({
+            "error": "Admin token required"
+        }))
+}
+
+// For support routes mounted outside /admin. They need the admin token too,
+// and are refused until one is configured
+pub fn require_admin(req: &HttpRequest, token: Option<&AdminToken>) -> Result<(), HttpResponse> {
+    match token {
+        Some(token) if token.authorizes(req) => Ok(()),
+        Some(_) => {
+            tracing::warn!(path = %req.path(), "Rejected admin request");
+            Err(unauthorized())
+        }
+        None => Err(HttpResponse::ServiceUnavailable().json(json!({
+            "error": "Player data requests need admin.token to be configured"
+        }))),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct ConnectionSearch {
+    pub player_id: Option<String>,
+    // Matches revoked and rotated-away links too, since that's what players report
+    pub link_id: Option<String>,
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64
+}
+
+fn not_found() -> HttpResponse {
+    HttpResponse::NotFound().json(json!({
+        "error": "Connection not found"
+    }))
+}
+
+// A connection with the message counts support needs to see
+fn summary(connection: &Connection, messages: &HashMap<String, Vec<Message>>) -> serde_json::Value {
+    let history = messages.get(&connection.id).map(Vec::as_slice).unwrap_or_default();
+    let mut value = json!(connection);
+    value["message_count"] = json!(history.iter().filter(|message| !message.deleted).count());
+    value["deleted_message_count"] = json!(history.iter().filter(|message| message.deleted).count());
+    value["last_message_at"] = json!(history.last().map(|message| message.timestamp));
+    value
+}
+
+pub async fn search_connections(
+    query: web::Query<ConnectionSearch>,
+    connections: web::Data<RwLock<HashMap<String, Connection>>>,
+    messages: web::Data<RwLock<HashMap<String, Vec<Message>>>>,
+) -> Result<HttpResponse, StoreError> {
+    if query.player_id.is_none() && query.link_id.is_none() {
+        return Ok(HttpResponse::BadRequest().json(json!({
+            "error": "player_id or link_id is required"
+        })));
+    }
+
+    let conn_map = store::read(&connections, "connections")?;
+    let messages = store::read(&messages, "messages")?;
+
+    let mut found: Vec<&Connection> = conn_map
+        .iter()
+        .filter(|(key, conn)| {
+            **key == conn.id
+                && query.player_id.iter().all(|player| conn.players.contains(player))
+                && query.link_id.iter().all(|link| conn.link_id.eq_ignore_ascii_case(link))
+        })
+        .map(|(_, conn)| conn)
+        .collect();
+    found.sort_by_key(|conn| conn.created_at);
+
+    Ok(HttpResponse::Ok().json(
+        found
+            .into_iter()
+            .map(|conn| summary(conn, &messages))
+            .collect::<Vec<_>>(),
+    ))
+}
+
+pub async fn get_connection(
+    connection_id: web::Path<String>,
+    connections: web::Data<RwLock<HashMap<String, Connection>>>,
+    messages: web::Data<RwLock<HashMap<String, Vec<Message>>>>,
+) -> Result<HttpResponse, StoreError> {
+    let connection_id = connection_id.into_inner();
+    let conn_map = store::read(&connections, "connections")?;
+
+    match conn_map.get(&connection_id) {
+        Some(conn) if conn.id == connection_id => {
+            Ok(HttpResponse::Ok().json(summary(conn, &*store::read(&messages, "messages")?)))
+        }
+        _ => Ok(not_found()),
+    }
+}
+
+// Move a connection to `status`, revoke its link and tell its members
+fn end_connection(
+    connection_id: &str,
+    status: ConnectionStatus,
+    event: &str,
+    connections: &RwLock<HashMap<String, Connection>>,
+    sessions: &SessionRegistry,
+    producer: Option<web::Data<FutureProducer>>,
+) -> Result<HttpResponse, StoreError> {
+    let connection = {
+        let mut conn_map = store::write(connections, "connections")?;
+        let mut connection = match conn_map.get(connection_id) {
+            Some(conn) if conn.id == connection_id => conn.clone(),
+            _ => return Ok(not_found()),
+        };
+        if connection.status == ConnectionStatus::Closed {
+            return Ok(HttpResponse::BadRequest().json(json!({
+                "error": "Connection is closed"
+            })));
+        }
+
+        if status == ConnectionStatus::Expired {
+            connection.expires_at = connection.expires_at.min(now());
+        }
+        connection.status = status;
+        conn_map.remove(&connection.link_id);
+        connection.revoke_link();
+        store_connection(&mut conn_map, &connection);
+        connection
+    };
+
+    tracing::info!(connection_id = %connection.id, event, "Admin ended connection");
+
+    let notice = ServerEvent::new(event, json!({
+        "connection_id": connection.id,
+        "reason": "admin",
+    }));
+    for player in &connection.players {
+        sessions.send(player, notice.clone());
+    }
+
+    if let Some(producer) = producer {
+        let event = json!({
+            "event": event,
+            "connection_id": connection.id,
+            "reason": "admin",
+            "timestamp": now(),
+        });
+        send_to_redpanda(producer.get_ref(), "connection-events", &connection.id, &event.to_string());
+    }
+
+    Ok(HttpResponse::Ok().json(connection))
+}
+
+pub async fn expire_connection(
+    connection_id: web::Path<String>,
+    connections: web::Data<RwLock<HashMap<String, Connection>>>,
+    sessions: web::Data<SessionRegistry>,
+    producer: Option<web::Data<FutureProducer>>,
+) -> Result<HttpResponse, StoreError> {
+    end_connection(
+        &connection_id,
+        ConnectionStatus::Expired,
+        "connection_expired",
+        &connections,
+        &sessions,
+        producer,
+    )
+}
+
+pub async fn close_connection(
+    connection_id: web::Path<String>,
+    connections: web::Data<RwLock<HashMap<String, Connection>>>,
+    sessions: web::Data<SessionRegistry>,
+    producer: Option<web::Data<FutureProducer>>,
+) -> Result<HttpResponse, StoreError> {
+    end_connection(
+        &connection_id,
+        ConnectionStatus::Closed,
+        "connection_closed",
+        &connections,
+        &sessions,
+        producer,
+    )
+}
+
+pub async fn purge_notifications(
+    player_id: web::Path<String>,
+    notifications: web::Data<RwLock<HashMap<String, Vec<Notification>>>>,
+) -> Result<HttpResponse, StoreError> {
+    let player_id = player_id.into_inner();
+    let purged = store::write(&notifications, "notifications")?
+        .remove(&player_id)
+        .map_or(0, |removed| removed.len());
+
+    tracing::info!(player_id = %player_id, purged, "Admin purged notifications");
+
+    Ok(HttpResponse::Ok().json(json!({
+        "player_id": player_id,
+        "purged": purged,
+    })))
+}
+
+pub async fn list_sessions(sessions: web::Data<SessionRegistry>) -> HttpResponse {
+    let sessions = sessions.list();
+    HttpResponse::Ok().json(json!({
+        "count": sessions.len(),
+        "sessions": sessions,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn test_token_must_match_exactly() {
+        let token = AdminToken::new("0123456789abcdef".to_string());
+        let with = |value: &str| {
+            TestRequest::default()
+                .insert_header((header::AUTHORIZATION, value))
+                .to_http_request()
+        };
+
+        assert!(token.authorizes(&with("Bearer 0123456789abcdef")));
+        assert!(!token.authorizes(&with("Bearer 0123456789abcde")));
+        assert!(!token.authorizes(&with("0123456789abcdef")));
+        assert!(!token.authorizes(&TestRequest::default().to_http_request()));
+    }
+}
@@ -0,0 +1,299 @@
+use actix_web::{web, HttpResponse};
+use rdkafka::producer::FutureProducer;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::{HashMap, HashSet};
+use std::sync::RwLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::connection::{Connection, ConnectionStatus};
+use crate::friend_request::{FriendRequest, FriendRequestStatus};
+use crate::server::{send_to_redpanda, store_connection};
+use crate::store::{self, StoreError};
+
+#[derive(Deserialize)]
+pub struct BlockRequest {
+    // The player being blocked
+    pub player_id: String,
+}
+
+#[derive(Deserialize)]
+pub struct MuteRequest {
+    pub player_id: String,
+    pub muted: bool,
+}
+
+// Players each player has blocked
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BlockList {
+    blocked: HashMap<String, HashSet<String>>,
+}
+
+impl BlockList {
+    pub fn block(&mut self, blocker: &str, other: &str) {
+        self.blocked
+            .entry(blocker.to_string())
+            .or_default()
+            .insert(other.to_string());
+    }
+
+    pub fn unblock(&mut self, blocker: &str, other: &str) {
+        if let Some(blocked) = self.blocked.get_mut(blocker) {
+            blocked.remove(other);
+        }
+    }
+
+    // Whether `blocker` has blocked `other`
+    pub fn has_blocked(&self, blocker: &str, other: &str) -> bool {
+        self.blocked.get(blocker).is_some_and(|blocked| blocked.contains(other))
+    }
+
+    // Whether either player has blocked the other
+    pub fn between(&self, a: &str, b: &str) -> bool {
+        self.has_blocked(a, b) || self.has_blocked(b, a)
+    }
+
+    pub fn blocked_by(&self, blocker: &str) -> Vec<String> {
+        let mut blocked: Vec<String> = self
+            .blocked
+            .get(blocker)
+            .map(|blocked| blocked.iter().cloned().collect())
+            .unwrap_or_default();
+        blocked.sort();
+        blocked
+    }
+
+    // Drop the player's blocks and any blocks against them
+    pub fn forget(&mut self, player_id: &str) {
+        self.blocked.remove(player_id);
+        for blocked in self.blocked.values_mut() {
+            blocked.remove(player_id);
+        }
+    }
+}
+
+// Connections each player has muted
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct MuteList {
+    muted: HashMap<String, HashSet<String>>,
+}
+
+impl MuteList {
+    pub fn set(&mut self, player_id: &str, connection_id: &str, muted: bool) {
+        let player_mutes = self.muted.entry(player_id.to_string()).or_default();
+        if muted {
+            player_mutes.insert(connection_id.to_string());
+        } else {
+            player_mutes.remove(connection_id);
+        }
+    }
+
+    pub fn is_muted(&self, player_id: &str, connection_id: &str) -> bool {
+        self.muted.get(player_id).is_some_and(|muted| muted.contains(connection_id))
+    }
+
+    pub fn muted_by(&self, player_id: &str) -> Vec<String> {
+        let mut muted: Vec<String> = self
+            .muted
+            .get(player_id)
+            .map(|muted| muted.iter().cloned().collect())
+            .unwrap_or_default();
+        muted.sort();
+        muted
+    }
+
+    pub fn forget(&mut self, player_id: &str) {
+        self.muted.remove(player_id);
+    }
+}
+
+// Whether `recipient` should hear about something `sender` did in a connection
+pub fn should_notify(
+    blocks: &BlockList,
+    mutes: &MuteList,
+    recipient: &str,
+    sender: &str,
+    connection_id: &str,
+) -> bool {
+    !blocks.between(recipient, sender) && !mutes.is_muted(recipient, connection_id)
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64
+}
+
+pub async fn block_player(
+    player_id: web::Path<String>,
+    block_req: web::Json<BlockRequest>,
+    connections: web::Data<RwLock<HashMap<String, Connection>>>,
+    friend_requests: web::Data<RwLock<HashMap<String, FriendRequest>>>,
+    blocks: web::Data<RwLock<BlockList>>,
+    producer: Option<web::Data<FutureProducer>>,
+) -> Result<HttpResponse, StoreError> {
+    let player_id = player_id.into_inner();
+    let blocked_id = block_req.player_id.clone();
+
+    if blocked_id.is_empty() || blocked_id == player_id {
+        return Ok(HttpResponse::BadRequest().json(json!({
+            "error": "Cannot block yourself"
+        })));
+    }
+
+    store::write(&blocks, "blocks")?.block(&player_id, &blocked_id);
+
+    // Pending friend requests either way are declined
+    for request in store::write(&friend_requests, "friend_requests")?.values_mut() {
+        let between = (request.from == player_id && request.to == blocked_id)
+            || (request.from == blocked_id && request.to == player_id);
+        if between && request.status == FriendRequestStatus::Pending {
+            request.status = FriendRequestStatus::Declined;
+            request.responded_at = Some(now());
+        }
+    }
+
+    // Close any one-to-one connection the two players share; group connections
+    // stay open with messages between them suppressed
+    let closed: Vec<Connection> = {
+        let mut conn_map = store::write(&connections, "connections")?;
+        let shared: Vec<Connection> = conn_map
+            .iter()
+            .filter(|(key, conn)| {
+                **key == conn.id
+                    && conn.status != ConnectionStatus::Closed
+                    && conn.players.len() == 2
+                    && conn.players.contains(&player_id)
+                    && conn.players.contains(&blocked_id)
+            })
+            .map(|(_, conn)| conn.clone())
+            .collect();
+
+        shared
+            .into_iter()
+            .map(|mut conn| {
+                conn.status = ConnectionStatus::Closed;
+                conn_map.remove(&conn.link_id);
+                conn.revoke_link();
+                store_connection(&mut conn_map, &conn);
+                conn
+            })
+            .collect()
+    };
+
+    if let Some(producer) = producer {
+        let event = json!({
+            "event": "player_blocked",
+            "player_id": player_id,
+            "blocked_player_id": blocked_id,
+            "timestamp": now(),
+        });
+        send_to_redpanda(producer.get_ref(), "connection-events", &player_id, &event.to_string());
+
+        for conn in &closed {
+            let event = json!({
+                "event": "connection_closed",
+                "connection_id": conn.id,
+                "player_id": player_id,
+                "reason": "blocked",
+                "timestamp": now(),
+            });
+            send_to_redpanda(producer.get_ref(), "connection-events", &conn.id, &event.to_string());
+        }
+    }
+
+    Ok(HttpResponse::Ok().json(json!({
+        "blocked_player_id": blocked_id,
+        "closed_connections": closed.iter().map(|conn| &conn.id).collect::<Vec<_>>(),
+    })))
+}
+
+pub async fn unblock_player(
+    path: web::Path<(String, String)>,
+    blocks: web::Data<RwLock<BlockList>>,
+) -> Result<HttpResponse, StoreError> {
+    let (player_id, blocked_id) = path.into_inner();
+
+    store::write(&blocks, "blocks")?.unblock(&player_id, &blocked_id);
+
+    Ok(HttpResponse::Ok().json(json!({"status": "ok"})))
+}
+
+pub async fn list_blocks(
+    player_id: web::Path<String>,
+    blocks: web::Data<RwLock<BlockList>>,
+) -> Result<HttpResponse, StoreError> {
+    let blocked = store::read(&blocks, "blocks")?.blocked_by(&player_id.into_inner());
+
+    Ok(HttpResponse::Ok().json(blocked))
+}
+
+// Keep a connection but stop notifications about it for one player
+pub async fn set_mute(
+    connection_id: web::Path<String>,
+    mute_req: web::Json<MuteRequest>,
+    connections: web::Data<RwLock<HashMap<String, Connection>>>,
+    mutes: web::Data<RwLock<MuteList>>,
+) -> Result<HttpResponse, StoreError> {
+    let connection_id = connection_id.into_inner();
+
+    {
+        let conn_map = store::read(&connections, "connections")?;
+        match conn_map.get(&connection_id) {
+            Some(conn) if conn.id == connection_id => {
+                if !conn.players.contains(&mute_req.player_id) {
+                    return Ok(HttpResponse::BadRequest().json(json!({
+                        "error": "Player not in this connection"
+                    })));
+                }
+            }
+            _ => {
+                return Ok(HttpResponse::NotFound().json(json!({
+                    "error": "Connection not found"
+                })));
+            }
+        }
+    }
+
+    store::write(&mutes, "mutes")?.set(&mute_req.player_id, &connection_id, mute_req.muted);
+
+    Ok(HttpResponse::Ok().json(json!({
+        "connection_id": connection_id,
+        "muted": mute_req.muted,
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_block_applies_in_both_directions() {
+        let mut blocks = BlockList::default();
+        blocks.block("player1", "player2");
+
+        assert!(blocks.has_blocked("player1", "player2"));
+        assert!(!blocks.has_blocked("player2", "player1"));
+        assert!(blocks.between("player2", "player1"));
+
+        blocks.unblock("player1", "player2");
+        assert!(!blocks.between("player1", "player2"));
+    }
+
+    #[test]
+    fn test_should_notify_respects_blocks_and_mutes() {
+        let mut blocks = BlockList::default();
+        let mut mutes = MuteList::default();
+
+        assert!(should_notify(&blocks, &mutes, "player1", "player2", "conn1"));
+
+        mutes.set("player1", "conn1", true);
+        assert!(!should_notify(&blocks, &mutes, "player1", "player2", "conn1"));
+        assert!(should_notify(&blocks, &mutes, "player1", "player2", "conn2"));
+
+        mutes.set("player1", "conn1", false);
+        blocks.block("player2", "player1");
+        assert!(!should_notify(&blocks, &mutes, "player1", "player2", "conn1"));
+    }
+}
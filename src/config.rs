@@ -0,0 +1,828 @@
+use serde::de::{DeserializeOwned, IntoDeserializer};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap};
+use std::env;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::message_limits::MessageLimits;
+use crate::moderation::{FilterChain, LinkFilter, SpamFilter, WordAction, WordListFilter};
+use crate::rate_limit::{parse_limit, Budget, RateLimits};
+use crate::short_code::{ShortCodeConfig, DEFAULT_ALPHABET, DEFAULT_LENGTH};
+use crate::telemetry::LogFormat;
+use crate::websocket::RedpandaConfig;
+use crate::socket_limits::{
+    SessionOverflow, SocketLimits, DEFAULT_CLIENT_TIMEOUT_SECS, DEFAULT_HEARTBEAT_INTERVAL_SECS,
+    DEFAULT_IDLE_TIMEOUT_SECS, DEFAULT_MAX_FRAME_BYTES, DEFAULT_MAX_SESSIONS_PER_PLAYER,
+};
+
+pub const DEFAULT_BIND_ADDRESS: &str = "0.0.0.0:8080";
+pub const DEFAULT_SHUTDOWN_TIMEOUT_SECS: u64 = 30;
+pub const DEFAULT_SNAPSHOT_INTERVAL_SECS: u64 = 60;
+
+// Environment variables and the settings they override, applied in this
+// order; PORT comes after BIND_ADDRESS so it only swaps the port
+const ENV_VARS: [(&str, &str); 41] = [
+    ("BIND_ADDRESS", "server.bind_address"),
+    ("PORT", "server.port"),
+    ("STORAGE_BACKEND", "server.storage"),
+    ("CORS_ALLOWED_ORIGINS", "server.cors_allowed_origins"),
+    ("TRUSTED_PROXIES", "server.trusted_proxies"),
+    ("SHUTDOWN_TIMEOUT_SECS", "server.shutdown_timeout_secs"),
+    ("SHUTDOWN_DRAIN_SECS", "server.shutdown_drain_secs"),
+    ("SNAPSHOT_PATH", "server.snapshot_path"),
+    ("SNAPSHOT_INTERVAL_SECS", "server.snapshot_interval_secs"),
+    ("LOG_FORMAT", "logging.format"),
+    ("ADMIN_TOKEN", "admin.token"),
+    ("EVENT_BUS", "event_bus.kind"),
+    ("REDPANDA_BOOTSTRAP_SERVERS", "event_bus.bootstrap_servers"),
+    ("REDPANDA_SECURITY_PROTOCOL", "event_bus.backend.security_protocol"),
+    ("REDPANDA_SASL_MECHANISM", "event_bus.backend.sasl_mechanism"),
+    ("REDPANDA_USERNAME", "event_bus.backend.username"),
+    ("REDPANDA_PASSWORD", "event_bus.backend.password"),
+    ("REDPANDA_CLIENT_ID", "event_bus.backend.client_id"),
+    ("REDPANDA_PROPERTIES", "event_bus.backend.properties"),
+    ("REDPANDA_WS_SECURITY_PROTOCOL", "event_bus.websocket.security_protocol"),
+    ("REDPANDA_WS_SASL_MECHANISM", "event_bus.websocket.sasl_mechanism"),
+    ("REDPANDA_WS_USERNAME", "event_bus.websocket.username"),
+    ("REDPANDA_WS_PASSWORD", "event_bus.websocket.password"),
+    ("REDPANDA_WS_CLIENT_ID", "event_bus.websocket.client_id"),
+    ("REDPANDA_WS_PROPERTIES", "event_bus.websocket.properties"),
+    ("REDPANDA_WS_INHERIT_BACKEND", "event_bus.websocket_inherits_backend"),
+    ("SHORT_CODE_ALPHABET", "short_codes.alphabet"),
+    ("SHORT_CODE_LENGTH", "short_codes.length"),
+    ("MESSAGE_MAX_BYTES", "messages.max_bytes"),
+    ("MESSAGE_MAX_CHARS", "messages.max_chars"),
+    ("MESSAGE_EDIT_WINDOW_SECS", "messages.edit_window_secs"),
+    ("WS_HEARTBEAT_INTERVAL_SECS", "websocket.heartbeat_interval_secs"),
+    ("WS_CLIENT_TIMEOUT_SECS", "websocket.client_timeout_secs"),
+    ("WS_IDLE_TIMEOUT_SECS", "websocket.idle_timeout_secs"),
+    ("WS_MAX_FRAME_BYTES", "websocket.max_frame_bytes"),
+    ("WS_MAX_SESSIONS_PER_PLAYER", "websocket.max_sessions_per_player"),
+    ("WS_SESSION_OVERFLOW", "websocket.session_overflow"),
+    ("MODERATION_WORDS", "moderation.words"),
+    ("MODERATION_WORD_ACTION", "moderation.word_action"),
+    ("MODERATION_BLOCK_LINKS", "moderation.block_links"),
+    ("MODERATION_SPAM_REPEATS", "moderation.spam_repeats"),
+];
+
+// Where connections, messages and the rest are kept
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageBackend {
+    Memory,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum EventBusKind {
+    Kafka,
+    // Run without publishing or consuming events
+    None,
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SecurityProtocol {
+    Plaintext,
+    Ssl,
+    SaslPlaintext,
+    SaslSsl,
+}
+
+impl SecurityProtocol {
+    // The name librdkafka expects
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SecurityProtocol::Plaintext => "PLAINTEXT",
+            SecurityProtocol::Ssl => "SSL",
+            SecurityProtocol::SaslPlaintext => "SASL_PLAINTEXT",
+            SecurityProtocol::SaslSsl => "SASL_SSL",
+        }
+    }
+
+    pub fn uses_sasl(&self) -> bool {
+        matches!(self, SecurityProtocol::SaslPlaintext | SecurityProtocol::SaslSsl)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq)]
+pub enum SaslMechanism {
+    #[serde(rename = "plain")]
+    Plain,
+    #[default]
+    #[serde(rename = "scram-sha-256")]
+    ScramSha256,
+    #[serde(rename = "scram-sha-512")]
+    ScramSha512,
+}
+
+impl SaslMechanism {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SaslMechanism::Plain => "PLAIN",
+            SaslMechanism::ScramSha256 => "SCRAM-SHA-256",
+            SaslMechanism::ScramSha512 => "SCRAM-SHA-512",
+        }
+    }
+}
+
+// How one role (the backend, or WebSocket sessions) connects to Kafka, so
+// each can have its own ACLs
+#[derive(Clone, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct KafkaClientSettings {
+    // None picks SASL_SSL when there are credentials and PLAINTEXT otherwise
+    pub security_protocol: Option<SecurityProtocol>,
+    pub sasl_mechanism: SaslMechanism,
+    pub username: String,
+    pub password: String,
+    // Defaults to friends-connect-server or friends-connect-ws
+    pub client_id: Option<String>,
+    // Extra librdkafka properties, applied last
+    pub properties: BTreeMap<String, String>,
+}
+
+impl KafkaClientSettings {
+    pub fn has_credentials(&self) -> bool {
+        !self.username.is_empty() || !self.password.is_empty()
+    }
+
+    pub fn effective_security_protocol(&self) -> SecurityProtocol {
+        self.security_protocol.unwrap_or(if self.has_credentials() {
+            SecurityProtocol::SaslSsl
+        } else {
+            SecurityProtocol::Plaintext
+        })
+    }
+
+    fn set(&mut self, key: &str, field: &str, value: &str) -> Result<(), String> {
+        match field {
+            "security_protocol" => self.security_protocol = Some(parse_enum(key, value)?),
+            "sasl_mechanism" => self.sasl_mechanism = parse_enum(key, value)?,
+            "username" => self.username = value.to_string(),
+            "password" => self.password = value.to_string(),
+            "client_id" => self.client_id = Some(value.trim().to_string()).filter(|id| !id.is_empty()),
+            // A comma separated list of `name=value` pairs
+            "properties" => {
+                for pair in parse_list(value) {
+                    let (name, value) = pair
+                        .split_once('=')
+                        .ok_or_else(|| format!("Invalid {}: {} (expected name=value)", key, pair))?;
+                    self.properties.insert(name.trim().to_string(), value.trim().to_string());
+                }
+            }
+            _ => match field.strip_prefix("properties.") {
+                Some(name) if !name.is_empty() => {
+                    self.properties.insert(name.to_string(), value.trim().to_string());
+                }
+                _ => return Err(format!("Unknown setting: {}", key)),
+            },
+        }
+        Ok(())
+    }
+
+    fn validate(&self, role: &str) -> Result<(), String> {
+        if self.username.is_empty() != self.password.is_empty() {
+            return Err(format!("event_bus.{} needs both a username and a password, or neither", role));
+        }
+        let protocol = self.effective_security_protocol();
+        if protocol.uses_sasl() && !self.has_credentials() {
+            return Err(format!(
+                "event_bus.{}.security_protocol {} needs a username and password",
+                role,
+                protocol.as_str()
+            ));
+        }
+        if !protocol.uses_sasl() && self.has_credentials() {
+            return Err(format!(
+                "event_bus.{} has credentials but security_protocol {} does not use SASL",
+                role,
+                protocol.as_str()
+            ));
+        }
+        Ok(())
+    }
+}
+
+// Keep passwords out of logs
+impl fmt::Debug for KafkaClientSettings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KafkaClientSettings")
+            .field("security_protocol", &self.security_protocol)
+            .field("sasl_mechanism", &self.sasl_mechanism)
+            .field("username", &self.username)
+            .field("password", &if self.password.is_empty() { "" } else { "<redacted>" })
+            .field("client_id", &self.client_id)
+            .field("properties", &self.properties)
+            .finish()
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerSettings {
+    pub bind_address: String,
+    pub storage: StorageBackend,
+    // Origins allowed by CORS; empty allows any origin
+    pub cors_allowed_origins: Vec<String>,
+    // Proxy addresses whose X-Forwarded-For is used for per-IP rate limits
+    pub trusted_proxies: Vec<String>,
+    // How long shutdown waits for requests, sockets and Kafka to drain
+    pub shutdown_timeout_secs: u64,
+    // How long to keep serving after failing readiness, so load balancers
+    // stop sending traffic before sockets close
+    pub shutdown_drain_secs: u64,
+    // Where the stores are saved on shutdown and loaded from on startup
+    pub snapshot_path: Option<String>,
+    // How often the snapshot is also saved while running; 0 saves only on
+    // shutdown
+    pub snapshot_interval_secs: u64,
+}
+
+impl Default for ServerSettings {
+    fn default() -> Self {
+        ServerSettings {
+            bind_address: DEFAULT_BIND_ADDRESS.to_string(),
+            storage: StorageBackend::Memory,
+            cors_allowed_origins: Vec::new(),
+            trusted_proxies: Vec::new(),
+            shutdown_timeout_secs: DEFAULT_SHUTDOWN_TIMEOUT_SECS,
+            shutdown_drain_secs: 0,
+            snapshot_path: None,
+            snapshot_interval_secs: DEFAULT_SNAPSHOT_INTERVAL_SECS,
+        }
+    }
+}
+
+// Levels come from RUST_LOG rather than the config
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingSettings {
+    pub format: LogFormat,
+}
+
+// Minimum admin token length, so a short guessable token can't be configured
+pub const MIN_ADMIN_TOKEN_LEN: usize = 16;
+
+#[derive(Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AdminSettings {
+    // Bearer token for the /admin API; empty turns the API off
+    pub token: String,
+}
+
+// Keep the token out of logs
+impl fmt::Debug for AdminSettings {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AdminSettings")
+            .field("token", &if self.token.is_empty() { "" } else { "<redacted>" })
+            .finish()
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EventBusSettings {
+    pub kind: EventBusKind,
+    pub bootstrap_servers: String,
+    // The server's own producer and notification consumer
+    pub backend: KafkaClientSettings,
+    // Producers opened for WebSocket sessions
+    pub websocket: KafkaClientSettings,
+    // Let WebSocket producers without credentials of their own reuse the
+    // backend's, instead of failing validation
+    pub websocket_inherits_backend: bool,
+}
+
+impl Default for EventBusSettings {
+    fn default() -> Self {
+        EventBusSettings {
+            kind: EventBusKind::Kafka,
+            bootstrap_servers: "localhost:9092".to_string(),
+            backend: KafkaClientSettings::default(),
+            websocket: KafkaClientSettings::default(),
+            websocket_inherits_backend: false,
+        }
+    }
+}
+
+impl EventBusSettings {
+    pub fn backend_config(&self) -> RedpandaConfig {
+        let mut client = self.backend.clone();
+        client.client_id.get_or_insert_with(|| "friends-connect-server".to_string());
+        RedpandaConfig {
+            bootstrap_servers: self.bootstrap_servers.clone(),
+            client,
+        }
+    }
+
+    // Whether the WebSocket role has no credentials of its own while the
+    // backend does; a role set to a non-SASL protocol deliberately goes without
+    fn websocket_lacks_credentials(&self) -> bool {
+        let wants_sasl = self.websocket.security_protocol.is_none_or(|protocol| protocol.uses_sasl());
+        wants_sasl && !self.websocket.has_credentials() && self.backend.has_credentials()
+    }
+
+    pub fn websocket_uses_backend_credentials(&self) -> bool {
+        self.websocket_inherits_backend && self.websocket_lacks_credentials()
+    }
+
+    pub fn websocket_config(&self) -> RedpandaConfig {
+        let mut client = self.websocket.clone();
+        client.client_id.get_or_insert_with(|| "friends-connect-ws".to_string());
+        if self.websocket_uses_backend_credentials() {
+            client.username = self.backend.username.clone();
+            client.password = self.backend.password.clone();
+            client.sasl_mechanism = self.backend.sasl_mechanism;
+            client.security_protocol = client.security_protocol.or(self.backend.security_protocol);
+        }
+        RedpandaConfig {
+            bootstrap_servers: self.bootstrap_servers.clone(),
+            client,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ShortCodeSettings {
+    pub alphabet: String,
+    pub length: usize,
+}
+
+impl Default for ShortCodeSettings {
+    fn default() -> Self {
+        ShortCodeSettings {
+            alphabet: DEFAULT_ALPHABET.to_string(),
+            length: DEFAULT_LENGTH,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebSocketSettings {
+    pub heartbeat_interval_secs: u64,
+    pub client_timeout_secs: u64,
+    // 0 turns the idle cutoff off
+    pub idle_timeout_secs: u64,
+    pub max_frame_bytes: usize,
+    pub max_sessions_per_player: usize,
+    pub session_overflow: SessionOverflow,
+}
+
+impl Default for WebSocketSettings {
+    fn default() -> Self {
+        WebSocketSettings {
+            heartbeat_interval_secs: DEFAULT_HEARTBEAT_INTERVAL_SECS,
+            client_timeout_secs: DEFAULT_CLIENT_TIMEOUT_SECS,
+            idle_timeout_secs: DEFAULT_IDLE_TIMEOUT_SECS,
+            max_frame_bytes: DEFAULT_MAX_FRAME_BYTES,
+            max_sessions_per_player: DEFAULT_MAX_SESSIONS_PER_PLAYER,
+            session_overflow: SessionOverflow::EvictOldest,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ModerationSettings {
+    pub words: Vec<String>,
+    pub word_action: WordAction,
+    pub block_links: bool,
+    // Identical messages allowed per minute; 0 turns the spam filter off
+    pub spam_repeats: usize,
+}
+
+impl Default for ModerationSettings {
+    fn default() -> Self {
+        ModerationSettings {
+            words: Vec::new(),
+            word_action: WordAction::Mask,
+            block_links: true,
+            spam_repeats: 3,
+        }
+    }
+}
+
+// Everything the server can be configured with. Settings are layered:
+// defaults, then the TOML file, then environment variables, then flags.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerSettings,
+    pub logging: LoggingSettings,
+    pub admin: AdminSettings,
+    pub event_bus: EventBusSettings,
+    pub short_codes: ShortCodeSettings,
+    pub messages: MessageLimits,
+    pub websocket: WebSocketSettings,
+    // Budget name to `<requests>/<seconds>`
+    pub rate_limits: HashMap<String, String>,
+    pub moderation: ModerationSettings,
+}
+
+fn parse<T: FromStr>(key: &str, value: &str) -> Result<T, String> {
+    value
+        .trim()
+        .parse()
+        .map_err(|_| format!("Invalid {}: {}", key, value))
+}
+
+// Parse a value the same way the TOML file spells it
+fn parse_enum<T: DeserializeOwned>(key: &str, value: &str) -> Result<T, String> {
+    T::deserialize(value.trim().to_ascii_lowercase().into_deserializer())
+        .map_err(|_: serde::de::value::Error| format!("Invalid {}: {}", key, value))
+}
+
+fn parse_list(value: &str) -> Vec<String> {
+    value
+        .split(',')
+        .map(|item| item.trim().to_string())
+        .filter(|item| !item.is_empty())
+        .collect()
+}
+
+// Split `--key value` and `--key=value` flags; dashes in keys may stand in for underscores
+fn parse_flags(args: impl IntoIterator<Item = String>) -> Result<Vec<(String, String)>, String> {
+    let mut flags = Vec::new();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let flag = arg
+            .strip_prefix("--")
+            .ok_or_else(|| format!("Unexpected argument: {}", arg))?;
+        let (key, value) = match flag.split_once('=') {
+            Some((key, value)) => (key.to_string(), value.to_string()),
+            None => {
+                let value = args.next().ok_or_else(|| format!("Missing value for --{}", flag))?;
+                (flag.to_string(), value)
+            }
+        };
+        let key = match key.replace('-', "_").as_str() {
+            "bind" => "server.bind_address".to_string(),
+            "port" => "server.port".to_string(),
+            other => other.to_string(),
+        };
+        flags.push((key, value));
+    }
+    Ok(flags)
+}
+
+impl Config {
+    // Load from the process arguments and environment, then validate
+    pub fn load() -> Result<Config, String> {
+        Config::load_from(env::args().skip(1), |name| env::var(name).ok())
+    }
+
+    // Load from the environment alone
+    pub fn from_env() -> Result<Config, String> {
+        Config::load_from(Vec::new(), |name| env::var(name).ok())
+    }
+
+    // Defaults, then the file named by --config or CONFIG_FILE, then the
+    // environment, then the remaining flags
+    pub fn load_from(
+        args: impl IntoIterator<Item = String>,
+        env: impl Fn(&str) -> Option<String>,
+    ) -> Result<Config, String> {
+        let flags = parse_flags(args)?;
+        let file = flags
+            .iter()
+            .rev()
+            .find(|(key, _)| key == "config")
+            .map(|(_, path)| path.clone())
+            .or_else(|| env("CONFIG_FILE"));
+
+        let mut config = match file {
+            Some(path) => Config::from_file(Path::new(&path))?,
+            None => Config::default(),
+        };
+
+        for (name, key) in ENV_VARS {
+            if let Some(value) = env(name) {
+                config.set(key, &value).map_err(|err| format!("{} (from {})", err, name))?;
+            }
+        }
+        for budget in Budget::ALL {
+            if let Some(value) = env(budget.env_var()) {
+                config
+                    .set(&format!("rate_limits.{}", budget.name()), &value)
+                    .map_err(|err| format!("{} (from {})", err, budget.env_var()))?;
+            }
+        }
+
+        for (key, value) in flags.iter().filter(|(key, _)| key != "config") {
+            config.set(key, value).map_err(|err| format!("{} (from --{})", err, key))?;
+        }
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn from_file(path: &Path) -> Result<Config, String> {
+        let text = fs::read_to_string(path)
+            .map_err(|err| format!("Could not read config file {}: {}", path.display(), err))?;
+        toml::from_str(&text).map_err(|err| format!("Invalid config file {}: {}", path.display(), err))
+    }
+
+    // Override one setting by its dotted name, as used by env vars and flags
+    pub fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        match key {
+            "server.bind_address" => self.server.bind_address = value.trim().to_string(),
+            "server.port" => {
+                let port: u16 = parse(key, value)?;
+                let host = self
+                    .server
+                    .bind_address
+                    .rsplit_once(':')
+                    .map_or("0.0.0.0", |(host, _)| host);
+                self.server.bind_address = format!("{}:{}", host, port);
+            }
+            "server.storage" => self.server.storage = parse_enum(key, value)?,
+            "server.cors_allowed_origins" => self.server.cors_allowed_origins = parse_list(value),
+            "server.trusted_proxies" => self.server.trusted_proxies = parse_list(value),
+            "server.shutdown_timeout_secs" => self.server.shutdown_timeout_secs = parse(key, value)?,
+            "server.shutdown_drain_secs" => self.server.shutdown_drain_secs = parse(key, value)?,
+            "server.snapshot_interval_secs" => self.server.snapshot_interval_secs = parse(key, value)?,
+            "server.snapshot_path" => {
+                let path = value.trim();
+                self.server.snapshot_path = (!path.is_empty()).then(|| path.to_string());
+            }
+            "logging.format" => self.logging.format = parse_enum(key, value)?,
+            "admin.token" => self.admin.token = value.trim().to_string(),
+            "event_bus.kind" => self.event_bus.kind = parse_enum(key, value)?,
+            "event_bus.bootstrap_servers" => self.event_bus.bootstrap_servers = value.trim().to_string(),
+            "event_bus.websocket_inherits_backend" => self.event_bus.websocket_inherits_backend = parse(key, value)?,
+            "short_codes.alphabet" => self.short_codes.alphabet = value.trim().to_string(),
+            "short_codes.length" => self.short_codes.length = parse(key, value)?,
+            "messages.max_bytes" => self.messages.max_bytes = parse(key, value)?,
+            "messages.max_chars" => self.messages.max_chars = parse(key, value)?,
+            "messages.edit_window_secs" => self.messages.edit_window_secs = parse(key, value)?,
+            "websocket.heartbeat_interval_secs" => self.websocket.heartbeat_interval_secs = parse(key, value)?,
+            "websocket.client_timeout_secs" => self.websocket.client_timeout_secs = parse(key, value)?,
+            "websocket.idle_timeout_secs" => self.websocket.idle_timeout_secs = parse(key, value)?,
+            "websocket.max_frame_bytes" => self.websocket.max_frame_bytes = parse(key, value)?,
+            "websocket.max_sessions_per_player" => self.websocket.max_sessions_per_player = parse(key, value)?,
+            "websocket.session_overflow" => self.websocket.session_overflow = parse_enum(key, value)?,
+            "moderation.words" => self.moderation.words = parse_list(value),
+            "moderation.word_action" => self.moderation.word_action = parse_enum(key, value)?,
+            "moderation.block_links" => self.moderation.block_links = parse(key, value)?,
+            "moderation.spam_repeats" => self.moderation.spam_repeats = parse(key, value)?,
+            _ => {
+                if let Some(name) = key.strip_prefix("rate_limits.") {
+                    if Budget::from_name(name).is_none() {
+                        return Err(format!("Unknown setting: {}", key));
+                    }
+                    self.rate_limits.insert(name.to_string(), value.trim().to_string());
+                } else if let Some(field) = key.strip_prefix("event_bus.backend.") {
+                    self.event_bus.backend.set(key, field, value)?;
+                } else if let Some(field) = key.strip_prefix("event_bus.websocket.") {
+                    self.event_bus.websocket.set(key, field, value)?;
+                } else {
+                    return Err(format!("Unknown setting: {}", key));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Check every setting, so a bad value stops startup instead of being
+    // replaced by a default
+    pub fn validate(&self) -> Result<(), String> {
+        let bind = &self.server.bind_address;
+        match bind.rsplit_once(':') {
+            Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => {}
+            _ => return Err(format!("Invalid server.bind_address: {} (expected host:port)", bind)),
+        }
+        if let Some(origin) = self
+            .server
+            .cors_allowed_origins
+            .iter()
+            .find(|origin| !origin.starts_with("http://") && !origin.starts_with("https://"))
+        {
+            return Err(format!("Invalid server.cors_allowed_origins entry: {}", origin));
+        }
+        if self.server.shutdown_timeout_secs == 0 {
+            return Err("server.shutdown_timeout_secs must be greater than 0".to_string());
+        }
+        if !self.admin.token.is_empty() && self.admin.token.len() < MIN_ADMIN_TOKEN_LEN {
+            return Err(format!("admin.token must be at least {} characters", MIN_ADMIN_TOKEN_LEN));
+        }
+        if self.event_bus.kind == EventBusKind::Kafka && self.event_bus.bootstrap_servers.is_empty() {
+            return Err("event_bus.bootstrap_servers is required when event_bus.kind is kafka".to_string());
+        }
+        self.event_bus.backend.validate("backend")?;
+        if self.event_bus.websocket_lacks_credentials() && !self.event_bus.websocket_inherits_backend {
+            return Err("event_bus.websocket needs its own username and password, a non-SASL security_protocol, \
+                 or event_bus.websocket_inherits_backend = true"
+                .to_string());
+        }
+        self.event_bus.websocket_config().client.validate("websocket")?;
+        if self.messages.max_bytes == 0 {
+            return Err("messages.max_bytes must be greater than 0".to_string());
+        }
+        if self.messages.max_chars == 0 {
+            return Err("messages.max_chars must be greater than 0".to_string());
+        }
+        if self.messages.edit_window_secs < 0 {
+            return Err("messages.edit_window_secs must not be negative".to_string());
+        }
+
+        self.short_codes()?;
+        self.socket_limits().validate()?;
+        self.rate_limits()?;
+        Ok(())
+    }
+
+    pub fn short_codes(&self) -> Result<ShortCodeConfig, String> {
+        ShortCodeConfig::new(&self.short_codes.alphabet, self.short_codes.length)
+    }
+
+    pub fn socket_limits(&self) -> SocketLimits {
+        let ws = &self.websocket;
+        SocketLimits {
+            heartbeat_interval: Duration::from_secs(ws.heartbeat_interval_secs),
+            client_timeout: Duration::from_secs(ws.client_timeout_secs),
+            idle_timeout: Some(Duration::from_secs(ws.idle_timeout_secs)).filter(|t| !t.is_zero()),
+            max_frame_bytes: ws.max_frame_bytes,
+            max_sessions_per_player: ws.max_sessions_per_player,
+            session_overflow: ws.session_overflow,
+        }
+    }
+
+    pub fn rate_limits(&self) -> Result<RateLimits, String> {
+        let mut limits = Vec::new();
+        for (name, value) in &self.rate_limits {
+            let budget = Budget::from_name(name).ok_or_else(|| format!("Unknown rate limit: {}", name))?;
+            let limit = parse_limit(value).ok_or_else(|| {
+                format!("Invalid rate_limits.{}: {} (expected <requests>/<seconds>)", name, value)
+            })?;
+            limits.push((budget, limit));
+        }
+        let trusted_proxies = self
+            .server
+            .trusted_proxies
+            .iter()
+            .map(|proxy| {
+                proxy
+                    .parse()
+                    .map_err(|_| format!("Invalid server.trusted_proxies: {} (expected an IP address)", proxy))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(RateLimits::new(limits).with_trusted_proxies(trusted_proxies))
+    }
+
+    pub fn filter_chain(&self) -> FilterChain {
+        let settings = &self.moderation;
+        let mut chain = FilterChain::new();
+
+        if !settings.words.is_empty() {
+            chain = chain.with(WordListFilter::new(settings.words.clone(), settings.word_action));
+        }
+        if settings.block_links {
+            chain = chain.with(LinkFilter);
+        }
+        if settings.spam_repeats > 0 {
+            chain = chain.with(SpamFilter::new(settings.spam_repeats, Duration::from_secs(60)));
+        }
+
+        chain
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(list: &[&str]) -> Vec<String> {
+        list.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    fn env_from(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let vars: HashMap<String, String> = vars
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect();
+        move |name| vars.get(name).cloned()
+    }
+
+    #[test]
+    fn test_defaults_are_valid() {
+        let config = Config::load_from(Vec::new(), env_from(&[])).unwrap();
+
+        assert_eq!(config.server.bind_address, DEFAULT_BIND_ADDRESS);
+        assert_eq!(config.event_bus.kind, EventBusKind::Kafka);
+    }
+
+    #[test]
+    fn test_file_then_env_then_flags() {
+        let path = env::temp_dir().join(format!("friends-connect-{}.toml", uuid::Uuid::new_v4()));
+        fs::write(
+            &path,
+            r#"
+            [server]
+            bind_address = "127.0.0.1:7000"
+
+            [messages]
+            max_chars = 50
+
+            [websocket]
+            session_overflow = "reject_new"
+
+            [rate_limits]
+            message = "10/60"
+            "#,
+        )
+        .unwrap();
+
+        let env = env_from(&[
+            ("CONFIG_FILE", path.to_str().unwrap()),
+            ("PORT", "7001"),
+            ("MESSAGE_MAX_CHARS", "60"),
+            ("SHUTDOWN_DRAIN_SECS", "10"),
+            ("SNAPSHOT_INTERVAL_SECS", "0"),
+            ("REDPANDA_USERNAME", "backend"),
+            ("REDPANDA_PASSWORD", "secret"),
+            ("REDPANDA_WS_INHERIT_BACKEND", "true"),
+        ]);
+        let config = Config::load_from(args(&["--messages.max-chars", "70", "--event_bus.kind=none"]), env).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(config.server.bind_address, "127.0.0.1:7001");
+        assert_eq!(config.messages.max_chars, 70);
+        assert_eq!(config.server.shutdown_drain_secs, 10);
+        assert_eq!(config.server.snapshot_interval_secs, 0);
+        assert_eq!(config.event_bus.kind, EventBusKind::None);
+        assert_eq!(config.websocket.session_overflow, SessionOverflow::RejectNew);
+        assert_eq!(config.rate_limits["message"], "10/60");
+        // Sessions reuse the backend credentials only when told to
+        assert!(config.event_bus.websocket_uses_backend_credentials());
+        let websocket = config.event_bus.websocket_config().client;
+        assert_eq!(websocket.username, "backend");
+        assert_eq!(websocket.effective_security_protocol(), SecurityProtocol::SaslSsl);
+        assert_eq!(websocket.client_id.as_deref(), Some("friends-connect-ws"));
+    }
+
+    #[test]
+    fn test_kafka_roles_have_their_own_client_settings() {
+        let env = env_from(&[
+            ("REDPANDA_USERNAME", "backend"),
+            ("REDPANDA_PASSWORD", "secret"),
+            ("REDPANDA_SASL_MECHANISM", "SCRAM-SHA-512"),
+            ("REDPANDA_WS_SECURITY_PROTOCOL", "plaintext"),
+            ("REDPANDA_WS_PROPERTIES", "linger.ms=5, acks=1"),
+        ]);
+        let config = Config::load_from(args(&["--event-bus.backend.properties.acks", "all"]), env).unwrap();
+
+        let backend = config.event_bus.backend_config().client_config();
+        assert_eq!(backend.get("security.protocol"), Some("SASL_SSL"));
+        assert_eq!(backend.get("sasl.mechanism"), Some("SCRAM-SHA-512"));
+        assert_eq!(backend.get("client.id"), Some("friends-connect-server"));
+        assert_eq!(backend.get("acks"), Some("all"));
+
+        let websocket = config.event_bus.websocket_config().client_config();
+        assert_eq!(websocket.get("security.protocol"), Some("PLAINTEXT"));
+        assert_eq!(websocket.get("sasl.username"), None);
+        assert_eq!(websocket.get("linger.ms"), Some("5"));
+        assert_eq!(websocket.get("acks"), Some("1"));
+    }
+
+    #[test]
+    fn test_invalid_settings_name_their_source() {
+        let err = Config::load_from(Vec::new(), env_from(&[("WS_MAX_FRAME_BYTES", "lots")])).unwrap_err();
+        assert_eq!(err, "Invalid websocket.max_frame_bytes: lots (from WS_MAX_FRAME_BYTES)");
+
+        let err = Config::load_from(args(&["--websocket.nap_secs", "5"]), env_from(&[])).unwrap_err();
+        assert!(err.contains("Unknown setting: websocket.nap_secs"), "{}", err);
+
+        let err = Config::load_from(args(&["--rate_limits.join", "5"]), env_from(&[])).unwrap_err();
+        assert!(err.contains("rate_limits.join"), "{}", err);
+
+        let err = Config::load_from(Vec::new(), env_from(&[("REDPANDA_WS_USERNAME", "ws")])).unwrap_err();
+        assert!(err.contains("event_bus.websocket"), "{}", err);
+
+        let err = Config::load_from(
+            Vec::new(),
+            env_from(&[("REDPANDA_USERNAME", "backend"), ("REDPANDA_PASSWORD", "secret")]),
+        )
+        .unwrap_err();
+        assert!(err.contains("event_bus.websocket_inherits_backend"), "{}", err);
+
+        let err = Config::load_from(args(&["--event_bus.backend.security_protocol", "sasl_ssl"]), env_from(&[]))
+            .unwrap_err();
+        assert!(err.contains("needs a username and password"), "{}", err);
+
+        let err = Config::load_from(Vec::new(), env_from(&[("ADMIN_TOKEN", "letmein")])).unwrap_err();
+        assert!(err.contains("admin.token must be at least"), "{}", err);
+
+        let err = Config::load_from(Vec::new(), env_from(&[("TRUSTED_PROXIES", "10.0.0.0/8")])).unwrap_err();
+        assert!(err.contains("server.trusted_proxies"), "{}", err);
+
+        let err = Config::load_from(Vec::new(), env_from(&[("SHORT_CODE_ALPHABET", "ABCD")])).unwrap_err();
+        assert!(err.contains("fewer than 1000000 codes"), "{}", err);
+    }
+}
@@ -1,13 +1,72 @@
 use serde::{Serialize, Deserialize};
+use std::collections::BTreeMap;
 use std::time::{SystemTime, UNIX_EPOCH};
 use uuid::Uuid;
 
+// When a message reached, and was read by, one recipient
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq)]
+pub struct Receipt {
+    pub delivered_at: Option<i64>,
+    pub read_at: Option<i64>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Message {
     pub id: String,
     pub from: String,
     pub content: String,
     pub timestamp: i64,
+    #[serde(default)]
+    pub edited_at: Option<i64>,
+    // Deleted messages stay in history as tombstones with no content
+    #[serde(default)]
+    pub deleted: bool,
+    // Emoji to the players who reacted with it
+    #[serde(default)]
+    pub reactions: BTreeMap<String, Vec<String>>,
+    // Delivery state per recipient
+    #[serde(default)]
+    pub receipts: BTreeMap<String, Receipt>,
+}
+
+impl Message {
+    pub fn new(from: String, content: String) -> Self {
+        Message {
+            id: Uuid::new_v4().to_string(),
+            from,
+            content,
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs() as i64,
+            edited_at: None,
+            deleted: false,
+            reactions: BTreeMap::new(),
+            receipts: BTreeMap::new(),
+        }
+    }
+
+    pub fn tombstone(&mut self) {
+        self.deleted = true;
+        self.content.clear();
+        self.reactions.clear();
+    }
+
+    // Add or remove a player's reaction; false if nothing changed
+    pub fn react(&mut self, player_id: &str, emoji: &str, reacted: bool) -> bool {
+        let players = self.reactions.entry(emoji.to_string()).or_default();
+        let present = players.iter().any(|p| p == player_id);
+        let changed = present != reacted;
+        if reacted && !present {
+            players.push(player_id.to_string());
+        } else if !reacted {
+            players.retain(|p| p != player_id);
+        }
+        if players.is_empty() {
+            self.reactions.remove(emoji);
+        }
+        changed
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
@@ -15,6 +74,23 @@ pub enum ConnectionStatus {
     Pending,
     Active,
     Expired,
+    Closed,
+}
+
+// How many times an invite link may be used before it stops working
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+pub enum LinkMode {
+    SingleUse,
+    #[default]
+    MultiUse,
+}
+
+fn default_max_players() -> usize {
+    2
+}
+
+fn default_link_active() -> bool {
+    true
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -25,13 +101,31 @@ pub struct Connection {
     pub created_at: i64,
     pub status: ConnectionStatus,
     pub expires_at: i64,
+    #[serde(default = "default_max_players")]
+    pub max_players: usize,
+    #[serde(default)]
+    pub link_mode: LinkMode,
+    #[serde(default = "default_link_active")]
+    pub link_active: bool,
+    #[serde(default)]
+    pub short_link: bool,
+}
+
+// What someone holding an invite link may see before joining
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LinkPreview {
+    pub creator_display_name: String,
+    pub player_count: usize,
+    pub max_players: usize,
+    pub expires_at: i64,
+    pub status: ConnectionStatus,
 }
 
 impl Connection {
     pub fn new(player_id: String) -> Self {
         let now = SystemTime::now()
             .duration_since(UNIX_EPOCH)
-            .unwrap()
+            .unwrap_or_default()
             .as_secs() as i64;
             
         Connection {
@@ -41,6 +135,40 @@ impl Connection {
             created_at: now,
             status: ConnectionStatus::Pending,
             expires_at: now + 604800, // Expires in 1 week (604800 seconds)
+            max_players: default_max_players(),
+            link_mode: LinkMode::default(),
+            link_active: true,
+            short_link: false,
+        }
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.players.len() >= self.max_players
+    }
+
+    // A link can be used to join while it hasn't been revoked and there is room left
+    pub fn accepts_link(&self, link_id: &str) -> bool {
+        self.link_active && self.link_id == link_id && !self.is_full()
+    }
+
+    // Replace the invite link with a fresh one, returning the old link id
+    pub fn rotate_link(&mut self, new_link_id: String) -> String {
+        let old_link_id = std::mem::replace(&mut self.link_id, new_link_id);
+        self.link_active = true;
+        old_link_id
+    }
+
+    pub fn revoke_link(&mut self) {
+        self.link_active = false;
+    }
+
+    pub fn preview(&self, creator_display_name: String) -> LinkPreview {
+        LinkPreview {
+            creator_display_name,
+            player_count: self.players.len(),
+            max_players: self.max_players,
+            expires_at: self.expires_at,
+            status: self.status.clone(),
         }
     }
  
@@ -51,7 +179,7 @@ impl Connection {
 
         let now = SystemTime::now()
             .duration_since(UNIX_EPOCH)
-            .unwrap()
+            .unwrap_or_default()
             .as_secs() as i64;
             
         self.expires_at <= now
@@ -81,7 +209,7 @@ mod tests {
         let connection = Connection::new("player1".to_string());
         let now = SystemTime::now()
             .duration_since(UNIX_EPOCH)
-            .unwrap()
+            .unwrap_or_default()
             .as_secs() as i64;
         
         // Created timestamp should be close to now
@@ -122,6 +250,52 @@ mod tests {
         assert!(Uuid::parse_str(&connection.link_id).is_ok());
     }
 
+    #[test]
+    fn test_rotate_link_issues_new_link_id() {
+        let mut connection = Connection::new("player1".to_string());
+        connection.revoke_link();
+        let old_link_id = connection.link_id.clone();
+
+        assert!(!connection.accepts_link(&old_link_id));
+
+        let returned = connection.rotate_link(Uuid::new_v4().to_string());
+
+        assert_eq!(returned, old_link_id);
+        assert_ne!(connection.link_id, old_link_id);
+        assert!(!connection.accepts_link(&old_link_id));
+        assert!(connection.accepts_link(&connection.link_id.clone()));
+    }
+
+    #[test]
+    fn test_full_connection_does_not_accept_link() {
+        let mut connection = Connection::new("player1".to_string());
+        connection.max_players = 3;
+        connection.players.push("player2".to_string());
+        assert!(connection.accepts_link(&connection.link_id.clone()));
+
+        connection.players.push("player3".to_string());
+        assert!(connection.is_full());
+        assert!(!connection.accepts_link(&connection.link_id.clone()));
+    }
+
+    #[test]
+    fn test_message_reactions_and_tombstone() {
+        let mut message = Message::new("player1".to_string(), "gg".to_string());
+
+        assert!(message.react("player2", "👍", true));
+        assert!(!message.react("player2", "👍", true));
+        assert_eq!(message.reactions["👍"], vec!["player2".to_string()]);
+
+        assert!(message.react("player2", "👍", false));
+        assert!(message.reactions.is_empty());
+
+        message.react("player2", "🎉", true);
+        message.tombstone();
+        assert!(message.deleted);
+        assert!(message.content.is_empty());
+        assert!(message.reactions.is_empty());
+    }
+
     #[test]
     fn test_new_connection() {
         let connection = Connection::new("player123".to_string());
@@ -139,6 +313,10 @@ mod tests {
             created_at: 0,
             status: ConnectionStatus::Pending,
             expires_at: 0,
+            max_players: 2,
+            link_mode: LinkMode::MultiUse,
+            link_active: true,
+            short_link: false,
         };
         
         assert_eq!(connection.players.len(), 1);
@@ -0,0 +1,119 @@
+use actix_web::HttpRequest;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+// WebSocket subprotocols a client can offer to pick its encoding
+pub const JSON_PROTOCOL: &str = "friends-connect.json";
+pub const MSGPACK_PROTOCOL: &str = "friends-connect.msgpack";
+pub const CBOR_PROTOCOL: &str = "friends-connect.cbor";
+pub const PROTOCOLS: [&str; 3] = [JSON_PROTOCOL, MSGPACK_PROTOCOL, CBOR_PROTOCOL];
+
+// How a session's frames are encoded. JSON goes in text frames; the binary
+// encodings carry the same messages in binary frames.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Encoding {
+    Json,
+    MessagePack,
+    Cbor,
+}
+
+impl Encoding {
+    pub fn from_name(name: &str) -> Option<Encoding> {
+        match name.trim().to_ascii_lowercase().as_str() {
+            "json" | JSON_PROTOCOL => Some(Encoding::Json),
+            "msgpack" | "messagepack" | MSGPACK_PROTOCOL => Some(Encoding::MessagePack),
+            "cbor" | CBOR_PROTOCOL => Some(Encoding::Cbor),
+            _ => None,
+        }
+    }
+
+    // The encoding a client asked for: the first subprotocol we support, then
+    // the `encoding` query parameter, then JSON
+    pub fn negotiate(req: &HttpRequest, query_encoding: Option<&str>) -> Result<Encoding, String> {
+        let offered = req
+            .headers()
+            .get("Sec-WebSocket-Protocol")
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or("");
+        if let Some(encoding) = offered
+            .split(',')
+            .map(str::trim)
+            .find(|protocol| PROTOCOLS.contains(protocol))
+            .and_then(Encoding::from_name)
+        {
+            return Ok(encoding);
+        }
+
+        match query_encoding {
+            Some(name) => Encoding::from_name(name).ok_or_else(|| format!("Unsupported encoding: {}", name)),
+            None => Ok(Encoding::Json),
+        }
+    }
+
+    pub fn is_binary(&self) -> bool {
+        *self != Encoding::Json
+    }
+
+    pub fn encode<T: Serialize>(&self, value: &T) -> Result<Vec<u8>, String> {
+        match self {
+            Encoding::Json => serde_json::to_vec(value).map_err(|err| err.to_string()),
+            // Named so structs become maps rather than positional arrays
+            Encoding::MessagePack => rmp_serde::to_vec_named(value).map_err(|err| err.to_string()),
+            Encoding::Cbor => {
+                let mut bytes = Vec::new();
+                ciborium::ser::into_writer(value, &mut bytes).map_err(|err| err.to_string())?;
+                Ok(bytes)
+            }
+        }
+    }
+
+    pub fn decode<T: DeserializeOwned>(&self, bytes: &[u8]) -> Result<T, String> {
+        match self {
+            Encoding::Json => serde_json::from_slice(bytes).map_err(|err| err.to_string()),
+            Encoding::MessagePack => rmp_serde::from_slice(bytes).map_err(|err| err.to_string()),
+            Encoding::Cbor => ciborium::de::from_reader(bytes).map_err(|err| err.to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Debug, Serialize, Deserialize, PartialEq)]
+    struct Frame {
+        event_type: String,
+        payload: serde_json::Value,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        seq: Option<u64>,
+    }
+
+    #[test]
+    fn test_binary_encodings_round_trip() {
+        let frame = Frame {
+            event_type: "send_message".to_string(),
+            payload: serde_json::json!({"content": "gg", "scores": [3, 1], "final": true}),
+            seq: Some(7),
+        };
+
+        for encoding in [Encoding::Json, Encoding::MessagePack, Encoding::Cbor] {
+            let bytes = encoding.encode(&frame).unwrap();
+            let decoded: Frame = encoding.decode(&bytes).unwrap();
+            assert_eq!(decoded, frame, "{:?}", encoding);
+        }
+    }
+
+    #[test]
+    fn test_negotiation_prefers_subprotocol() {
+        let req = actix_web::test::TestRequest::default()
+            .insert_header(("Sec-WebSocket-Protocol", "chat, friends-connect.cbor"))
+            .to_http_request();
+        assert_eq!(Encoding::negotiate(&req, Some("msgpack")), Ok(Encoding::Cbor));
+
+        let req = actix_web::test::TestRequest::default().to_http_request();
+        assert_eq!(Encoding::negotiate(&req, Some("msgpack")), Ok(Encoding::MessagePack));
+        assert_eq!(Encoding::negotiate(&req, None), Ok(Encoding::Json));
+        assert!(Encoding::negotiate(&req, Some("xml")).is_err());
+    }
+}
@@ -0,0 +1,303 @@
+use actix_web::{web, HttpResponse};
+use rdkafka::producer::FutureProducer;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::RwLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+use uuid::Uuid;
+
+use crate::block::BlockList;
+use crate::connection::{Connection, ConnectionStatus};
+use crate::notification::{push_notification, Notification};
+use crate::profile::{display_name, PlayerProfile};
+use crate::server::{send_to_redpanda, store_connection};
+use crate::store::{self, StoreError};
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub enum FriendRequestStatus {
+    Pending,
+    Accepted,
+    Declined,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FriendRequest {
+    pub id: String,
+    pub from: String,
+    pub to: String,
+    pub status: FriendRequestStatus,
+    pub created_at: i64,
+    pub responded_at: Option<i64>,
+    // Set once the request is accepted
+    pub connection_id: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct FriendRequestBody {
+    pub player_id: String,
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64
+}
+
+fn publish(producer: Option<web::Data<FutureProducer>>, event: &str, request: &FriendRequest) {
+    if let Some(producer) = producer {
+        let event = json!({
+            "event": event,
+            "request_id": request.id,
+            "from": request.from,
+            "to": request.to,
+            "connection_id": request.connection_id,
+            "timestamp": now(),
+        });
+
+        send_to_redpanda(
+            producer.get_ref(),
+            "connection-events",
+            &request.id,
+            &event.to_string(),
+        );
+    }
+}
+
+// Send a friend request from `player_id` in the body to the player in the path
+pub async fn send_friend_request(
+    target_id: web::Path<String>,
+    body: web::Json<FriendRequestBody>,
+    connections: web::Data<RwLock<HashMap<String, Connection>>>,
+    friend_requests: web::Data<RwLock<HashMap<String, FriendRequest>>>,
+    notifications: web::Data<RwLock<HashMap<String, Vec<Notification>>>>,
+    profiles: web::Data<RwLock<HashMap<String, PlayerProfile>>>,
+    blocks: web::Data<RwLock<BlockList>>,
+    producer: Option<web::Data<FutureProducer>>,
+) -> Result<HttpResponse, StoreError> {
+    let target_id = target_id.into_inner();
+    let from = body.player_id.clone();
+
+    if from.is_empty() || from == target_id {
+        return Ok(HttpResponse::BadRequest().json(json!({
+            "error": "Cannot send a friend request to yourself"
+        })));
+    }
+
+    if store::read(&blocks, "blocks")?.between(&from, &target_id) {
+        return Ok(HttpResponse::Forbidden().json(json!({
+            "error": "Cannot send a friend request to this player"
+        })));
+    }
+
+    // Players who already share a two-player connection don't need another
+    {
+        let conn_map = store::read(&connections, "connections")?;
+        // Any connection still in use counts, not just active ones
+        let already_connected = conn_map.values().any(|conn| {
+            !matches!(conn.status, ConnectionStatus::Closed | ConnectionStatus::Expired)
+                && conn.players.len() == 2
+                && conn.players.contains(&from)
+                && conn.players.contains(&target_id)
+        });
+        if already_connected {
+            return Ok(HttpResponse::BadRequest().json(json!({
+                "error": "Players already connected"
+            })));
+        }
+    }
+
+    let request = {
+        let mut requests = store::write(&friend_requests, "friend_requests")?;
+        let pending = requests.values().any(|req| {
+            req.status == FriendRequestStatus::Pending
+                && ((req.from == from && req.to == target_id)
+                    || (req.from == target_id && req.to == from))
+        });
+        if pending {
+            return Ok(HttpResponse::BadRequest().json(json!({
+                "error": "Friend request already pending"
+            })));
+        }
+
+        let request = FriendRequest {
+            id: Uuid::new_v4().to_string(),
+            from: from.clone(),
+            to: target_id.clone(),
+            status: FriendRequestStatus::Pending,
+            created_at: now(),
+            responded_at: None,
+            connection_id: None,
+        };
+        requests.insert(request.id.clone(), request.clone());
+        request
+    };
+
+    {
+        let name = display_name(&*store::read(&profiles, "profiles")?, &from);
+        let mut notifications = store::write(&notifications, "notifications")?;
+        push_notification(
+            &mut notifications,
+            &target_id,
+            Notification {
+                player_id: Some(from.clone()),
+                ..Notification::new(format!("Player {} sent you a friend request", name))
+            },
+        );
+    }
+
+    publish(producer, "friend_request_sent", &request);
+
+    Ok(HttpResponse::Ok().json(request))
+}
+
+// Pending requests the player has received and sent
+pub async fn list_friend_requests(
+    player_id: web::Path<String>,
+    friend_requests: web::Data<RwLock<HashMap<String, FriendRequest>>>,
+) -> Result<HttpResponse, StoreError> {
+    let player_id = player_id.into_inner();
+    let requests = store::read(&friend_requests, "friend_requests")?;
+
+    let mut incoming: Vec<&FriendRequest> = requests
+        .values()
+        .filter(|req| req.status == FriendRequestStatus::Pending && req.to == player_id)
+        .collect();
+    let mut outgoing: Vec<&FriendRequest> = requests
+        .values()
+        .filter(|req| req.status == FriendRequestStatus::Pending && req.from == player_id)
+        .collect();
+    incoming.sort_by_key(|req| req.created_at);
+    outgoing.sort_by_key(|req| req.created_at);
+
+    Ok(HttpResponse::Ok().json(json!({
+        "incoming": incoming,
+        "outgoing": outgoing,
+    })))
+}
+
+// The pending request addressed to `player_id`, or the error response to send
+fn pending_request_for(
+    requests: &HashMap<String, FriendRequest>,
+    request_id: &str,
+    player_id: &str,
+) -> Result<FriendRequest, HttpResponse> {
+    match requests.get(request_id) {
+        Some(req) if req.to != player_id => Err(HttpResponse::Forbidden().json(json!({
+            "error": "Only the recipient can answer a friend request"
+        }))),
+        Some(req) if req.status != FriendRequestStatus::Pending => {
+            Err(HttpResponse::BadRequest().json(json!({
+                "error": "Friend request already answered"
+            })))
+        }
+        Some(req) => Ok(req.clone()),
+        None => Err(HttpResponse::NotFound().json(json!({
+            "error": "Friend request not found"
+        }))),
+    }
+}
+
+pub async fn accept_friend_request(
+    request_id: web::Path<String>,
+    body: web::Json<FriendRequestBody>,
+    connections: web::Data<RwLock<HashMap<String, Connection>>>,
+    friend_requests: web::Data<RwLock<HashMap<String, FriendRequest>>>,
+    notifications: web::Data<RwLock<HashMap<String, Vec<Notification>>>>,
+    profiles: web::Data<RwLock<HashMap<String, PlayerProfile>>>,
+    blocks: web::Data<RwLock<BlockList>>,
+    producer: Option<web::Data<FutureProducer>>,
+) -> Result<HttpResponse, StoreError> {
+    let request_id = request_id.into_inner();
+    let mut requests = store::write(&friend_requests, "friend_requests")?;
+
+    let mut request = match pending_request_for(&requests, &request_id, &body.player_id) {
+        Ok(req) => req,
+        Err(resp) => return Ok(resp),
+    };
+
+    // A block made after the request was sent still keeps the players apart
+    if store::read(&blocks, "blocks")?.between(&request.from, &request.to) {
+        return Ok(HttpResponse::Forbidden().json(json!({
+            "error": "Cannot accept this friend request"
+        })));
+    }
+
+    // Direct connections are active straight away and have no invite link
+    let mut connection = Connection::new(request.from.clone());
+    connection.players.push(request.to.clone());
+    connection.status = ConnectionStatus::Active;
+    connection.revoke_link();
+    store_connection(&mut *store::write(&connections, "connections")?, &connection);
+
+    request.status = FriendRequestStatus::Accepted;
+    request.responded_at = Some(now());
+    request.connection_id = Some(connection.id.clone());
+    requests.insert(request.id.clone(), request.clone());
+
+    {
+        let profiles = store::read(&profiles, "profiles")?;
+        let mut notifications = store::write(&notifications, "notifications")?;
+        push_notification(
+            &mut notifications,
+            &request.from,
+            Notification::about(
+                format!("Player {} accepted your friend request", display_name(&profiles, &request.to)),
+                &request.to,
+                &connection.id,
+            ),
+        );
+        push_notification(
+            &mut notifications,
+            &request.to,
+            Notification::about(
+                format!("You are now connected with {}", display_name(&profiles, &request.from)),
+                &request.from,
+                &connection.id,
+            ),
+        );
+    }
+
+    publish(producer, "friend_request_accepted", &request);
+
+    Ok(HttpResponse::Ok().json(connection))
+}
+
+pub async fn decline_friend_request(
+    request_id: web::Path<String>,
+    body: web::Json<FriendRequestBody>,
+    friend_requests: web::Data<RwLock<HashMap<String, FriendRequest>>>,
+    notifications: web::Data<RwLock<HashMap<String, Vec<Notification>>>>,
+    profiles: web::Data<RwLock<HashMap<String, PlayerProfile>>>,
+    producer: Option<web::Data<FutureProducer>>,
+) -> Result<HttpResponse, StoreError> {
+    let request_id = request_id.into_inner();
+    let mut requests = store::write(&friend_requests, "friend_requests")?;
+
+    let mut request = match pending_request_for(&requests, &request_id, &body.player_id) {
+        Ok(req) => req,
+        Err(resp) => return Ok(resp),
+    };
+
+    request.status = FriendRequestStatus::Declined;
+    request.responded_at = Some(now());
+    requests.insert(request.id.clone(), request.clone());
+
+    {
+        let name = display_name(&*store::read(&profiles, "profiles")?, &request.to);
+        let mut notifications = store::write(&notifications, "notifications")?;
+        push_notification(
+            &mut notifications,
+            &request.from,
+            Notification {
+                player_id: Some(request.to.clone()),
+                ..Notification::new(format!("Player {} declined your friend request", name))
+            },
+        );
+    }
+
+    publish(producer, "friend_request_declined", &request);
+
+    Ok(HttpResponse::Ok().json(request))
+}
@@ -0,0 +1,151 @@
+use actix_web::{web, HttpResponse};
+use rdkafka::producer::{FutureProducer, Producer};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::RwLock;
+use std::time::Duration;
+
+use crate::connection::{Connection, Message};
+use crate::notification::Notification;
+
+// How long readiness waits for the broker to answer a metadata request
+const BROKER_TIMEOUT: Duration = Duration::from_secs(2);
+
+// What readiness needs to know that isn't in the stores
+pub struct Health {
+    event_bus_enabled: bool,
+    consumer_subscribed: AtomicBool,
+    shutting_down: AtomicBool,
+}
+
+impl Health {
+    pub fn new(event_bus_enabled: bool) -> Self {
+        Health {
+            event_bus_enabled,
+            consumer_subscribed: AtomicBool::new(false),
+            shutting_down: AtomicBool::new(false),
+        }
+    }
+
+    pub fn set_consumer_subscribed(&self, subscribed: bool) {
+        self.consumer_subscribed.store(subscribed, Ordering::SeqCst);
+    }
+
+    // Take the server out of rotation while it drains
+    pub fn begin_shutdown(&self) {
+        self.shutting_down.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutting_down.load(Ordering::SeqCst)
+    }
+}
+
+fn check(result: Result<(), String>) -> Value {
+    match result {
+        Ok(()) => json!({ "status": "ok" }),
+        Err(err) => json!({ "status": "error", "error": err }),
+    }
+}
+
+// A poisoned lock means a handler panicked mid-update and the store can't be trusted
+fn check_storage(
+    connections: &RwLock<HashMap<String, Connection>>,
+    messages: &RwLock<HashMap<String, Vec<Message>>>,
+    notifications: &RwLock<HashMap<String, Vec<Notification>>>,
+) -> Result<(), String> {
+    let poisoned = [
+        ("connections", connections.is_poisoned()),
+        ("messages", messages.is_poisoned()),
+        ("notifications", notifications.is_poisoned()),
+    ];
+    match poisoned.iter().find(|(_, poisoned)| *poisoned) {
+        Some((store, _)) => Err(format!("The {} store is poisoned", store)),
+        None => Ok(()),
+    }
+}
+
+async fn check_producer(producer: Option<web::Data<FutureProducer>>) -> Result<(), String> {
+    let producer = producer.ok_or_else(|| "No producer was created".to_string())?;
+    // Fetching metadata blocks, so keep it off the worker thread
+    web::block(move || {
+        producer
+            .client()
+            .fetch_metadata(None, BROKER_TIMEOUT)
+            .map(|_| ())
+            .map_err(|err| err.to_string())
+    })
+    .await
+    .map_err(|err| err.to_string())?
+}
+
+// Liveness: the process is up and serving requests
+pub async fn healthz() -> HttpResponse {
+    HttpResponse::Ok().json(json!({ "status": "ok" }))
+}
+
+// Readiness: everything a request might need is working
+pub async fn readyz(
+    health: web::Data<Health>,
+    connections: web::Data<RwLock<HashMap<String, Connection>>>,
+    messages: web::Data<RwLock<HashMap<String, Vec<Message>>>>,
+    notifications: web::Data<RwLock<HashMap<String, Vec<Notification>>>>,
+    producer: Option<web::Data<FutureProducer>>,
+) -> HttpResponse {
+    let storage = check(check_storage(&connections, &messages, &notifications));
+    let (producer, consumer) = if health.event_bus_enabled {
+        let subscribed = health.consumer_subscribed.load(Ordering::SeqCst);
+        (
+            check(check_producer(producer).await),
+            check(if subscribed {
+                Ok(())
+            } else {
+                Err("Not subscribed to user-notifications".to_string())
+            }),
+        )
+    } else {
+        (json!({ "status": "disabled" }), json!({ "status": "disabled" }))
+    };
+    let shutting_down = health.is_shutting_down();
+
+    let ready = !shutting_down && [&storage, &producer, &consumer].iter().all(|c| c["status"] != "error");
+    let body = json!({
+        "status": if ready { "ready" } else { "unavailable" },
+        "shutting_down": shutting_down,
+        "checks": {
+            "storage": storage,
+            "event_bus_producer": producer,
+            "notification_consumer": consumer,
+        },
+    });
+
+    if ready {
+        HttpResponse::Ok().json(body)
+    } else {
+        HttpResponse::ServiceUnavailable().json(body)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_poisoned_store_fails_the_storage_check() {
+        let connections = RwLock::new(HashMap::new());
+        let messages = RwLock::new(HashMap::new());
+        let notifications = RwLock::new(HashMap::new());
+        assert!(check_storage(&connections, &messages, &notifications).is_ok());
+
+        let _ = std::panic::catch_unwind(|| {
+            let _guard = messages.write().unwrap();
+            panic!("handler panicked mid-update");
+        });
+
+        assert_eq!(
+            check_storage(&connections, &messages, &notifications),
+            Err("The messages store is poisoned".to_string())
+        );
+    }
+}
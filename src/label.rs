@@ -0,0 +1,76 @@
+use actix_web::{web, HttpResponse};
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+use crate::connection::Connection;
+use crate::store::{self, StoreError};
+
+const MAX_LABEL_CHARS: usize = 64;
+
+#[derive(Deserialize)]
+pub struct LabelRequest {
+    pub player_id: String,
+    // An empty label clears it
+    pub label: String,
+}
+
+// Private label a player gave a connection, keyed by player id then connection id
+pub fn label_for(
+    labels: &HashMap<String, HashMap<String, String>>,
+    player_id: &str,
+    connection_id: &str,
+) -> Option<String> {
+    labels
+        .get(player_id)
+        .and_then(|player_labels| player_labels.get(connection_id))
+        .cloned()
+}
+
+pub async fn set_label(
+    connection_id: web::Path<String>,
+    label_req: web::Json<LabelRequest>,
+    connections: web::Data<RwLock<HashMap<String, Connection>>>,
+    labels: web::Data<RwLock<HashMap<String, HashMap<String, String>>>>,
+) -> Result<HttpResponse, StoreError> {
+    let connection_id = connection_id.into_inner();
+
+    {
+        let conn_map = store::read(&connections, "connections")?;
+        match conn_map.get(&connection_id) {
+            Some(conn) if conn.id == connection_id => {
+                if !conn.players.contains(&label_req.player_id) {
+                    return Ok(HttpResponse::BadRequest().json(json!({
+                        "error": "Player not in this connection"
+                    })));
+                }
+            }
+            _ => {
+                return Ok(HttpResponse::NotFound().json(json!({
+                    "error": "Connection not found"
+                })));
+            }
+        }
+    }
+
+    let label = label_req.label.trim();
+    if label.chars().count() > MAX_LABEL_CHARS {
+        return Ok(HttpResponse::BadRequest().json(json!({
+            "error": "Label is too long"
+        })));
+    }
+
+    let mut labels = store::write(&labels, "labels")?;
+    let player_labels = labels.entry(label_req.player_id.clone()).or_default();
+    if label.is_empty() {
+        player_labels.remove(&connection_id);
+    } else {
+        player_labels.insert(connection_id.clone(), label.to_string());
+    }
+
+    Ok(HttpResponse::Ok().json(json!({
+        "connection_id": connection_id,
+        "label": player_labels.get(&connection_id),
+    })))
+}
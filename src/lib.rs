@@ -3,10 +3,36 @@ use actix_files as fs;
 use std::net::TcpListener;
 use serde_json::json;
 
+pub mod admin;
+pub mod block;
+pub mod config;
 pub mod connection;
+pub mod encoding;
+pub mod friend_request;
+pub mod health;
+pub mod label;
+pub mod message;
+pub mod message_limits;
+pub mod metrics;
+pub mod moderation;
+pub mod notification;
+pub mod player_data;
+pub mod presence;
+pub mod profile;
+pub mod rate_limit;
 pub mod server; 
+pub mod session;
+pub mod short_code;
+pub mod shutdown;
+pub mod snapshot;
+pub mod socket_limits;
+pub mod store;
+pub mod telemetry;
 pub mod websocket; 
 
-pub use connection::{Connection, ConnectionStatus};
+pub use config::Config;
+pub use connection::{Connection, ConnectionStatus, LinkMode, LinkPreview};
+pub use notification::Notification;
+pub use profile::PlayerProfile;
 pub use server::Server;
 pub use websocket::{ws_route, RedpandaConfig, setup_notification_consumer};
\ No newline at end of file
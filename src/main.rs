@@ -1,12 +1,25 @@
-use friends_connect::Server;
+use friends_connect::{telemetry, Config, Server};
 use dotenv::dotenv;
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     dotenv().ok();
-    let port = std::env::var("PORT").unwrap_or_else(|_| "8080".to_string());
-    let address = format!("0.0.0.0:{}", port);
-    let server = Server::new(&address);
-    println!("Server running at http://{}", address);
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("Configuration error: {}", err);
+            std::process::exit(2);
+        }
+    };
+    telemetry::init(config.logging.format);
+
+    let server = match Server::from_config(&config) {
+        Ok(server) => server,
+        Err(err) => {
+            tracing::error!(error = %err, "Configuration error");
+            std::process::exit(2);
+        }
+    };
+    tracing::info!(address = %server.address, "Server running");
     server.run().await
-}
\ No newline at end of file
+}
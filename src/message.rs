@@ -0,0 +1,1037 @@
+use actix_web::{web, HttpResponse, ResponseError};
+use rdkafka::producer::FutureProducer;
+use serde::Deserialize;
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::RwLock;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::block::{should_notify, BlockList, MuteList};
+use crate::connection::{Connection, ConnectionStatus, Message};
+use crate::message_limits::{MessageError, MessageLimits};
+use crate::metrics::metrics;
+use crate::moderation::{FilterChain, MessageContext};
+use crate::notification::{push_notification, Notification};
+use crate::profile::{display_name, PlayerProfile};
+use crate::rate_limit::{too_many_requests, Budget, RateLimits};
+use crate::server::send_to_redpanda;
+use crate::session::{ServerEvent, SessionRegistry};
+use crate::store::{self, StoreError};
+
+const MAX_REACTION_CHARS: usize = 8;
+
+#[derive(Deserialize)]
+pub struct SendMessageRequest {
+    pub player_id: String,
+    pub content: String,
+}
+
+#[derive(Deserialize)]
+pub struct EditMessageRequest {
+    pub player_id: String,
+    pub content: String,
+}
+
+#[derive(Deserialize)]
+pub struct DeleteMessageRequest {
+    pub player_id: String,
+}
+
+#[derive(Deserialize)]
+pub struct ReactionRequest {
+    pub player_id: String,
+    pub emoji: String,
+    // false removes the player's reaction
+    pub reacted: bool,
+}
+
+#[derive(Deserialize)]
+pub struct HistoryQuery {
+    pub player_id: String,
+}
+
+#[derive(Deserialize)]
+pub struct ReadRequest {
+    pub player_id: String,
+    // The newest message the player has read
+    pub message_id: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ReceiptKind {
+    Delivered,
+    Read,
+}
+
+impl ReceiptKind {
+    fn as_str(&self) -> &'static str {
+        match self {
+            ReceiptKind::Delivered => "delivered",
+            ReceiptKind::Read => "read",
+        }
+    }
+}
+
+// Why a read marker couldn't be moved
+#[derive(Debug, Clone, PartialEq)]
+pub enum ReadError {
+    ConnectionNotFound,
+    NotMember,
+    MessageNotFound,
+    Store(StoreError),
+}
+
+impl From<StoreError> for ReadError {
+    fn from(err: StoreError) -> Self {
+        ReadError::Store(err)
+    }
+}
+
+impl ReadError {
+    pub fn message(&self) -> &'static str {
+        match self {
+            ReadError::ConnectionNotFound => "Connection not found",
+            ReadError::NotMember => "Player not in this connection",
+            ReadError::MessageNotFound => "Message not found",
+            ReadError::Store(_) => "Internal server error",
+        }
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        json!({
+            "error": self.message()
+        })
+    }
+
+    fn to_response(&self) -> HttpResponse {
+        match self {
+            ReadError::NotMember => HttpResponse::BadRequest().json(self.to_json()),
+            ReadError::Store(err) => err.error_response(),
+            _ => HttpResponse::NotFound().json(self.to_json()),
+        }
+    }
+}
+
+// Why a message couldn't be sent
+#[derive(Debug, Clone, PartialEq)]
+pub enum SendError {
+    Invalid(MessageError),
+    ConnectionNotFound,
+    NotMember,
+    Closed,
+    Rejected(String),
+    // How long until the player's message budget has room again
+    RateLimited(Duration),
+    Store(StoreError),
+}
+
+impl From<StoreError> for SendError {
+    fn from(err: StoreError) -> Self {
+        SendError::Store(err)
+    }
+}
+
+impl SendError {
+    pub fn to_json(&self) -> serde_json::Value {
+        match self {
+            SendError::Invalid(err) => err.to_json(),
+            SendError::ConnectionNotFound => json!({ "error": "Connection not found" }),
+            SendError::NotMember => json!({ "error": "Player not in this connection" }),
+            SendError::Closed => json!({ "error": "Connection is closed" }),
+            SendError::Rejected(reason) => json!({ "error": reason, "code": "message_rejected" }),
+            SendError::RateLimited(retry_after) => json!({
+                "error": "Too many requests",
+                "code": "rate_limited",
+                "retry_after": retry_after.as_secs().max(1),
+            }),
+            SendError::Store(_) => json!({ "error": "Internal server error" }),
+        }
+    }
+
+    fn to_response(&self) -> HttpResponse {
+        match self {
+            SendError::ConnectionNotFound => HttpResponse::NotFound().json(self.to_json()),
+            SendError::RateLimited(retry_after) => too_many_requests(*retry_after),
+            SendError::Store(err) => err.error_response(),
+            _ => HttpResponse::BadRequest().json(self.to_json()),
+        }
+    }
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64
+}
+
+// The connection if `player_id` is one of its players, or the error response to send
+fn member_connection(
+    conn_map: &HashMap<String, Connection>,
+    connection_id: &str,
+    player_id: &str,
+) -> Result<Connection, HttpResponse> {
+    match conn_map.get(connection_id) {
+        Some(conn) if conn.id == connection_id => {
+            if conn.players.iter().any(|p| p == player_id) {
+                Ok(conn.clone())
+            } else {
+                Err(HttpResponse::BadRequest().json(json!({
+                    "error": "Player not in this connection"
+                })))
+            }
+        }
+        _ => Err(HttpResponse::NotFound().json(json!({
+            "error": "Connection not found"
+        }))),
+    }
+}
+
+fn reject_closed(connection: &Connection) -> Result<(), HttpResponse> {
+    if connection.status == ConnectionStatus::Closed {
+        return Err(HttpResponse::BadRequest().json(json!({
+            "error": "Connection is closed"
+        })));
+    }
+    Ok(())
+}
+
+// Run the moderation filters, publishing anything they did
+fn moderate(
+    moderation: &FilterChain,
+    producer: Option<&FutureProducer>,
+    player_id: &str,
+    connection_id: &str,
+    content: &str,
+) -> Result<String, SendError> {
+    let ctx = MessageContext {
+        player_id,
+        connection_id,
+    };
+    let outcome = moderation.apply(&ctx, content);
+    if let Some(producer) = producer {
+        for decision in &outcome.decisions {
+            send_to_redpanda(
+                producer,
+                "connection-events",
+                connection_id,
+                &decision.to_event(&ctx).to_string(),
+            );
+        }
+    }
+
+    outcome.result.map_err(SendError::Rejected)
+}
+
+// Push an event to the open sockets of every other member the sender hasn't
+// been blocked by, returning the members who had a socket to take it
+pub(crate) fn broadcast_to_members(
+    sessions: &SessionRegistry,
+    blocks: &BlockList,
+    connection: &Connection,
+    sender: &str,
+    event: ServerEvent,
+) -> Vec<String> {
+    connection
+        .players
+        .iter()
+        .filter(|player| *player != sender && !blocks.between(player, sender))
+        .filter(|player| sessions.send(player, event.clone()))
+        .cloned()
+        .collect()
+}
+
+// Mark other players' messages as delivered to or read by `player_id`, up to and
+// including `up_to` (the whole history if None), skipping senders either of them
+// blocked. Returns the newly marked message ids by sender.
+pub(crate) fn record_receipts(
+    history: &mut [Message],
+    blocks: &BlockList,
+    player_id: &str,
+    up_to: Option<&str>,
+    kind: ReceiptKind,
+    at: i64,
+) -> HashMap<String, Vec<String>> {
+    let end = up_to
+        .and_then(|id| history.iter().position(|m| m.id == id))
+        .map_or(history.len(), |index| index + 1);
+
+    let mut marked: HashMap<String, Vec<String>> = HashMap::new();
+    let visible = |m: &&mut Message| m.from != player_id && !blocks.between(&m.from, player_id);
+    for message in history[..end].iter_mut().filter(visible) {
+        let receipt = message.receipts.entry(player_id.to_string()).or_default();
+        let changed = match kind {
+            ReceiptKind::Delivered => receipt.delivered_at.is_none(),
+            ReceiptKind::Read => receipt.read_at.is_none(),
+        };
+        if !changed {
+            continue;
+        }
+
+        // Reading a message implies it was delivered
+        receipt.delivered_at.get_or_insert(at);
+        if kind == ReceiptKind::Read {
+            receipt.read_at = Some(at);
+        }
+        marked
+            .entry(message.from.clone())
+            .or_default()
+            .push(message.id.clone());
+    }
+    marked
+}
+
+// Tell each sender, live and via Kafka, that `player_id` got or read their messages
+pub(crate) fn send_receipts(
+    sessions: &SessionRegistry,
+    blocks: &BlockList,
+    producer: Option<&FutureProducer>,
+    connection_id: &str,
+    player_id: &str,
+    kind: ReceiptKind,
+    marked: &HashMap<String, Vec<String>>,
+    at: i64,
+) {
+    for (sender, message_ids) in marked {
+        if blocks.between(sender, player_id) {
+            continue;
+        }
+
+        let payload = json!({
+            "connection_id": connection_id,
+            "player_id": player_id,
+            "status": kind.as_str(),
+            "message_ids": message_ids,
+            "timestamp": at,
+        });
+        sessions.send(sender, ServerEvent::new("message_receipt", payload.clone()));
+
+        if let Some(producer) = producer {
+            let mut event = payload;
+            event["event"] = json!(format!("messages_{}", kind.as_str()));
+            send_to_redpanda(
+                producer,
+                "connection-messages",
+                connection_id,
+                &event.to_string(),
+            );
+        }
+    }
+}
+
+// Advance `player_id`'s read marker in a connection to `message_id`
+pub(crate) fn mark_read(
+    connections: &RwLock<HashMap<String, Connection>>,
+    messages: &RwLock<HashMap<String, Vec<Message>>>,
+    blocks: &RwLock<BlockList>,
+    connection_id: &str,
+    player_id: &str,
+    message_id: &str,
+    at: i64,
+) -> Result<HashMap<String, Vec<String>>, ReadError> {
+    match store::read(connections, "connections")?.get(connection_id) {
+        Some(conn) if conn.id == connection_id => {
+            if !conn.players.iter().any(|p| p == player_id) {
+                return Err(ReadError::NotMember);
+            }
+        }
+        _ => return Err(ReadError::ConnectionNotFound),
+    }
+
+    // Messages hidden from the player can't be used as a marker
+    let blocks = store::read(blocks, "blocks")?;
+    let mut messages = store::write(messages, "messages")?;
+    let history = messages
+        .get_mut(connection_id)
+        .filter(|history| {
+            history
+                .iter()
+                .any(|m| m.id == message_id && !blocks.between(&m.from, player_id))
+        })
+        .ok_or(ReadError::MessageNotFound)?;
+
+    Ok(record_receipts(
+        history,
+        &blocks,
+        player_id,
+        Some(message_id),
+        ReceiptKind::Read,
+        at,
+    ))
+}
+
+// Tell the connection's other members and Kafka that a message changed
+fn publish_change(
+    event_type: &str,
+    connection: &Connection,
+    player_id: &str,
+    message: &Message,
+    sessions: &SessionRegistry,
+    blocks: &web::Data<RwLock<BlockList>>,
+    producer: Option<web::Data<FutureProducer>>,
+) -> Result<(), StoreError> {
+    let payload = json!({
+        "connection_id": connection.id,
+        "player_id": player_id,
+        "message": message,
+    });
+    broadcast_to_members(
+        sessions,
+        &*store::read(blocks, "blocks")?,
+        connection,
+        player_id,
+        ServerEvent::new(event_type, payload),
+    );
+
+    if let Some(producer) = producer {
+        let event = json!({
+            "event": event_type,
+            "connection_id": connection.id,
+            "message_id": message.id,
+            "player_id": player_id,
+            "content": message.content,
+            "deleted": message.deleted,
+            "reactions": message.reactions,
+            "timestamp": now(),
+        });
+
+        send_to_redpanda(
+            producer.get_ref(),
+            "connection-messages",
+            &connection.id,
+            &event.to_string(),
+        );
+    }
+    Ok(())
+}
+
+// Everything sending a message touches, borrowed from the HTTP handler's
+// or the WebSocket session's state
+pub(crate) struct Outbox<'a> {
+    pub connections: &'a RwLock<HashMap<String, Connection>>,
+    pub messages: &'a RwLock<HashMap<String, Vec<Message>>>,
+    pub notifications: &'a RwLock<HashMap<String, Vec<Notification>>>,
+    pub profiles: &'a RwLock<HashMap<String, PlayerProfile>>,
+    pub blocks: &'a RwLock<BlockList>,
+    pub mutes: &'a RwLock<MuteList>,
+    pub sessions: &'a SessionRegistry,
+    pub message_limits: &'a MessageLimits,
+    pub moderation: &'a FilterChain,
+    pub rate_limits: &'a RateLimits,
+    pub producer: Option<&'a FutureProducer>,
+}
+
+// Check, store, notify, push and publish one message. `via` labels the
+// metric with the transport it came in on.
+pub(crate) fn deliver_message(
+    outbox: &Outbox,
+    connection_id: &str,
+    player_id: &str,
+    content: &str,
+    via: &str,
+) -> Result<Message, SendError> {
+    outbox
+        .rate_limits
+        .check_player(Budget::Message, player_id)
+        .map_err(SendError::RateLimited)?;
+    let content = outbox
+        .message_limits
+        .sanitize(content)
+        .map_err(SendError::Invalid)?;
+
+    let connection = match store::read(outbox.connections, "connections")?.get(connection_id) {
+        Some(conn) if conn.id == connection_id => conn.clone(),
+        _ => return Err(SendError::ConnectionNotFound),
+    };
+    if !connection.players.iter().any(|p| p == player_id) {
+        return Err(SendError::NotMember);
+    }
+    if connection.status == ConnectionStatus::Closed {
+        return Err(SendError::Closed);
+    }
+
+    let content = moderate(
+        outbox.moderation,
+        outbox.producer,
+        player_id,
+        &connection.id,
+        &content,
+    )?;
+
+    let mut message = Message::new(player_id.to_string(), content.clone());
+
+    // Store it before anyone hears about it, so they can react to or read it straight away
+    store::write(outbox.messages, "messages")?
+        .entry(connection.id.clone())
+        .or_default()
+        .push(message.clone());
+
+    // Notify other players, unless they muted the connection or blocked the sender
+    {
+        let name = display_name(&*store::read(outbox.profiles, "profiles")?, player_id);
+        let blocks = store::read(outbox.blocks, "blocks")?;
+        let mutes = store::read(outbox.mutes, "mutes")?;
+        let mut notifications = store::write(outbox.notifications, "notifications")?;
+        for player in &connection.players {
+            if player != player_id
+                && should_notify(&blocks, &mutes, player, player_id, &connection.id)
+            {
+                push_notification(
+                    &mut notifications,
+                    player,
+                    Notification::about(
+                        format!("Message from {}: {}", name, content),
+                        player_id,
+                        &connection.id,
+                    ),
+                );
+            }
+        }
+    }
+
+    // Anyone with a socket open has the message now
+    let blocks = store::read(outbox.blocks, "blocks")?;
+    let hidden_from: Vec<&String> = connection
+        .players
+        .iter()
+        .filter(|player| *player != player_id && blocks.between(player, player_id))
+        .collect();
+    let delivered_to = broadcast_to_members(
+        outbox.sessions,
+        &blocks,
+        &connection,
+        player_id,
+        ServerEvent::new(
+            "new_message",
+            json!({
+                "connection_id": connection.id,
+                "player_id": player_id,
+                "message": message,
+            }),
+        ),
+    );
+    if !delivered_to.is_empty() {
+        let at = message.timestamp;
+        if let Ok(stored) = update_message(outbox.messages, &connection.id, &message.id, |stored| {
+            for player in &delivered_to {
+                stored
+                    .receipts
+                    .entry(player.clone())
+                    .or_default()
+                    .delivered_at
+                    .get_or_insert(at);
+            }
+            Ok(())
+        }) {
+            message = stored;
+        }
+
+        let marked = HashMap::from([(message.from.clone(), vec![message.id.clone()])]);
+        for player in &delivered_to {
+            send_receipts(
+                outbox.sessions,
+                &blocks,
+                outbox.producer,
+                &connection.id,
+                player,
+                ReceiptKind::Delivered,
+                &marked,
+                at,
+            );
+        }
+    }
+    drop(blocks);
+
+    metrics().message_sent(via);
+
+    // Publish to Redpanda if producer is available
+    if let Some(producer) = outbox.producer {
+        let event = json!({
+            "event": "message_sent",
+            "connection_id": connection.id,
+            "message_id": message.id,
+            "player_id": player_id,
+            "content": content,
+            "timestamp": message.timestamp,
+            // Members who blocked the sender, or were blocked by them, must not be shown it
+            "hidden_from": hidden_from,
+        });
+
+        send_to_redpanda(
+            producer,
+            "connection-messages",
+            &connection.id,
+            &event.to_string(),
+        );
+    }
+
+    Ok(message)
+}
+
+pub async fn send_message(
+    connection_id: web::Path<String>,
+    message_req: web::Json<SendMessageRequest>,
+    connections: web::Data<RwLock<HashMap<String, Connection>>>,
+    messages: web::Data<RwLock<HashMap<String, Vec<Message>>>>,
+    notifications: web::Data<RwLock<HashMap<String, Vec<Notification>>>>,
+    profiles: web::Data<RwLock<HashMap<String, PlayerProfile>>>,
+    blocks: web::Data<RwLock<BlockList>>,
+    mutes: web::Data<RwLock<MuteList>>,
+    sessions: web::Data<SessionRegistry>,
+    message_limits: web::Data<MessageLimits>,
+    moderation: web::Data<FilterChain>,
+    rate_limits: web::Data<RateLimits>,
+    producer: Option<web::Data<FutureProducer>>,
+) -> HttpResponse {
+    let outbox = Outbox {
+        connections: &connections,
+        messages: &messages,
+        notifications: &notifications,
+        profiles: &profiles,
+        blocks: &blocks,
+        mutes: &mutes,
+        sessions: &sessions,
+        message_limits: &message_limits,
+        moderation: &moderation,
+        rate_limits: &rate_limits,
+        producer: producer.as_ref().map(|p| p.get_ref()),
+    };
+
+    match deliver_message(
+        &outbox,
+        &connection_id,
+        &message_req.player_id,
+        &message_req.content,
+        "http",
+    ) {
+        Ok(message) => HttpResponse::Ok().json(message),
+        Err(err) => err.to_response(),
+    }
+}
+
+// Message history for a connection as the player sees it, oldest first,
+// including tombstones.
+// Fetching it counts as delivery of everything in it.
+pub async fn list_messages(
+    connection_id: web::Path<String>,
+    query: web::Query<HistoryQuery>,
+    connections: web::Data<RwLock<HashMap<String, Connection>>>,
+    messages: web::Data<RwLock<HashMap<String, Vec<Message>>>>,
+    blocks: web::Data<RwLock<BlockList>>,
+    sessions: web::Data<SessionRegistry>,
+    producer: Option<web::Data<FutureProducer>>,
+) -> Result<HttpResponse, StoreError> {
+    let connection_id = connection_id.into_inner();
+    if let Err(resp) = member_connection(
+        &*store::read(&connections, "connections")?,
+        &connection_id,
+        &query.player_id,
+    ) {
+        return Ok(resp);
+    }
+
+    // Messages between the viewer and someone either of them blocked are left out
+    let at = now();
+    let blocks = store::read(&blocks, "blocks")?;
+    let (history, marked) = {
+        let mut messages = store::write(&messages, "messages")?;
+        match messages.get_mut(&connection_id) {
+            Some(history) => {
+                let marked = record_receipts(
+                    history,
+                    &blocks,
+                    &query.player_id,
+                    None,
+                    ReceiptKind::Delivered,
+                    at,
+                );
+                let visible: Vec<Message> = history
+                    .iter()
+                    .filter(|m| !blocks.between(&m.from, &query.player_id))
+                    .cloned()
+                    .collect();
+                (visible, marked)
+            }
+            None => (Vec::new(), HashMap::new()),
+        }
+    };
+
+    send_receipts(
+        &sessions,
+        &blocks,
+        producer.as_ref().map(|p| p.get_ref()),
+        &connection_id,
+        &query.player_id,
+        ReceiptKind::Delivered,
+        &marked,
+        at,
+    );
+
+    Ok(HttpResponse::Ok().json(history))
+}
+
+// Move the player's read marker forward to a message
+pub async fn read_messages(
+    connection_id: web::Path<String>,
+    read_req: web::Json<ReadRequest>,
+    connections: web::Data<RwLock<HashMap<String, Connection>>>,
+    messages: web::Data<RwLock<HashMap<String, Vec<Message>>>>,
+    blocks: web::Data<RwLock<BlockList>>,
+    sessions: web::Data<SessionRegistry>,
+    producer: Option<web::Data<FutureProducer>>,
+) -> Result<HttpResponse, StoreError> {
+    let connection_id = connection_id.into_inner();
+    let at = now();
+
+    let marked = match mark_read(
+        &connections,
+        &messages,
+        &blocks,
+        &connection_id,
+        &read_req.player_id,
+        &read_req.message_id,
+        at,
+    ) {
+        Ok(marked) => marked,
+        Err(err) => return Ok(err.to_response()),
+    };
+
+    send_receipts(
+        &sessions,
+        &*store::read(&blocks, "blocks")?,
+        producer.as_ref().map(|p| p.get_ref()),
+        &connection_id,
+        &read_req.player_id,
+        ReceiptKind::Read,
+        &marked,
+        at,
+    );
+
+    Ok(HttpResponse::Ok().json(json!({
+        "connection_id": connection_id,
+        "player_id": read_req.player_id,
+        "last_read_message_id": read_req.message_id,
+        "read_message_ids": marked.values().flatten().collect::<Vec<_>>(),
+    })))
+}
+
+// Apply `change` to a stored message, or return the error response to send
+fn update_message(
+    messages: &RwLock<HashMap<String, Vec<Message>>>,
+    connection_id: &str,
+    message_id: &str,
+    change: impl FnOnce(&mut Message) -> Result<(), HttpResponse>,
+) -> Result<Message, HttpResponse> {
+    let mut messages = store::write(messages, "messages")?;
+    let message = messages
+        .get_mut(connection_id)
+        .and_then(|history| history.iter_mut().find(|m| m.id == message_id))
+        .ok_or_else(|| {
+            HttpResponse::NotFound().json(json!({
+                "error": "Message not found"
+            }))
+        })?;
+
+    change(message)?;
+    Ok(message.clone())
+}
+
+fn check_sender(message: &Message, player_id: &str) -> Result<(), HttpResponse> {
+    if message.from != player_id {
+        return Err(HttpResponse::Forbidden().json(json!({
+            "error": "Only the sender can change this message"
+        })));
+    }
+    if message.deleted {
+        return Err(HttpResponse::BadRequest().json(json!({
+            "error": "Message was deleted"
+        })));
+    }
+    Ok(())
+}
+
+pub async fn edit_message(
+    path: web::Path<(String, String)>,
+    edit_req: web::Json<EditMessageRequest>,
+    connections: web::Data<RwLock<HashMap<String, Connection>>>,
+    messages: web::Data<RwLock<HashMap<String, Vec<Message>>>>,
+    blocks: web::Data<RwLock<BlockList>>,
+    sessions: web::Data<SessionRegistry>,
+    message_limits: web::Data<MessageLimits>,
+    moderation: web::Data<FilterChain>,
+    producer: Option<web::Data<FutureProducer>>,
+) -> Result<HttpResponse, StoreError> {
+    let (connection_id, message_id) = path.into_inner();
+
+    let content = match message_limits.sanitize(&edit_req.content) {
+        Ok(content) => content,
+        Err(err) => return Ok(HttpResponse::BadRequest().json(err.to_json())),
+    };
+
+    let connection = match member_connection(
+        &*store::read(&connections, "connections")?,
+        &connection_id,
+        &edit_req.player_id,
+    ) {
+        Ok(conn) => conn,
+        Err(resp) => return Ok(resp),
+    };
+    if let Err(resp) = reject_closed(&connection) {
+        return Ok(resp);
+    }
+
+    let edit_window = message_limits.edit_window_secs;
+    let check = |message: &Message| {
+        check_sender(message, &edit_req.player_id)?;
+        if now() - message.timestamp > edit_window {
+            return Err(HttpResponse::BadRequest().json(json!({
+                "error": "Edit window has passed",
+                "code": "edit_window_passed"
+            })));
+        }
+        Ok(())
+    };
+
+    // Check before the filters see the new content, so they never run for
+    // someone else's message
+    if let Err(resp) = update_message(&messages, &connection_id, &message_id, |message| {
+        check(message)
+    }) {
+        return Ok(resp);
+    }
+
+    let content = match moderate(
+        &moderation,
+        producer.as_ref().map(|p| p.get_ref()),
+        &edit_req.player_id,
+        &connection.id,
+        &content,
+    ) {
+        Ok(content) => content,
+        Err(err) => return Ok(err.to_response()),
+    };
+
+    // Check again under the same lock as the write, in case the message was
+    // deleted while the filters ran
+    let message = match update_message(&messages, &connection_id, &message_id, |message| {
+        check(message)?;
+        message.content = content;
+        message.edited_at = Some(now());
+        Ok(())
+    }) {
+        Ok(message) => message,
+        Err(resp) => return Ok(resp),
+    };
+
+    publish_change(
+        "message_edited",
+        &connection,
+        &edit_req.player_id,
+        &message,
+        &sessions,
+        &blocks,
+        producer,
+    )?;
+
+    Ok(HttpResponse::Ok().json(message))
+}
+
+// Replace a message with a tombstone; allowed at any time, even on closed connections
+pub async fn delete_message(
+    path: web::Path<(String, String)>,
+    delete_req: web::Json<DeleteMessageRequest>,
+    connections: web::Data<RwLock<HashMap<String, Connection>>>,
+    messages: web::Data<RwLock<HashMap<String, Vec<Message>>>>,
+    blocks: web::Data<RwLock<BlockList>>,
+    sessions: web::Data<SessionRegistry>,
+    producer: Option<web::Data<FutureProducer>>,
+) -> Result<HttpResponse, StoreError> {
+    let (connection_id, message_id) = path.into_inner();
+
+    let connection = match member_connection(
+        &*store::read(&connections, "connections")?,
+        &connection_id,
+        &delete_req.player_id,
+    ) {
+        Ok(conn) => conn,
+        Err(resp) => return Ok(resp),
+    };
+
+    let message = match update_message(&messages, &connection_id, &message_id, |message| {
+        check_sender(message, &delete_req.player_id)?;
+        message.tombstone();
+        Ok(())
+    }) {
+        Ok(message) => message,
+        Err(resp) => return Ok(resp),
+    };
+
+    publish_change(
+        "message_deleted",
+        &connection,
+        &delete_req.player_id,
+        &message,
+        &sessions,
+        &blocks,
+        producer,
+    )?;
+
+    Ok(HttpResponse::Ok().json(message))
+}
+
+pub async fn set_reaction(
+    path: web::Path<(String, String)>,
+    reaction_req: web::Json<ReactionRequest>,
+    connections: web::Data<RwLock<HashMap<String, Connection>>>,
+    messages: web::Data<RwLock<HashMap<String, Vec<Message>>>>,
+    blocks: web::Data<RwLock<BlockList>>,
+    sessions: web::Data<SessionRegistry>,
+    producer: Option<web::Data<FutureProducer>>,
+) -> Result<HttpResponse, StoreError> {
+    let (connection_id, message_id) = path.into_inner();
+
+    // Reactions are emoji, so plain text (and anything long) is refused
+    let emoji = reaction_req.emoji.trim();
+    if emoji.is_empty()
+        || emoji.chars().count() > MAX_REACTION_CHARS
+        || emoji
+            .chars()
+            .any(|c| c.is_ascii() || c.is_whitespace() || c.is_control())
+    {
+        return Ok(HttpResponse::BadRequest().json(json!({
+            "error": "Invalid reaction"
+        })));
+    }
+
+    let connection = match member_connection(
+        &*store::read(&connections, "connections")?,
+        &connection_id,
+        &reaction_req.player_id,
+    ) {
+        Ok(conn) => conn,
+        Err(resp) => return Ok(resp),
+    };
+    if let Err(resp) = reject_closed(&connection) {
+        return Ok(resp);
+    }
+
+    let mut changed = false;
+    let message = match update_message(&messages, &connection_id, &message_id, |message| {
+        if message.deleted {
+            return Err(HttpResponse::BadRequest().json(json!({
+                "error": "Message was deleted"
+            })));
+        }
+        changed = message.react(&reaction_req.player_id, emoji, reaction_req.reacted);
+        Ok(())
+    }) {
+        Ok(message) => message,
+        Err(resp) => return Ok(resp),
+    };
+
+    if changed {
+        publish_change(
+            "message_reaction",
+            &connection,
+            &reaction_req.player_id,
+            &message,
+            &sessions,
+            &blocks,
+            producer,
+        )?;
+    }
+
+    Ok(HttpResponse::Ok().json(message))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reading_marks_up_to_the_marker() {
+        let mut history = vec![
+            Message::new("player1".to_string(), "one".to_string()),
+            Message::new("player2".to_string(), "two".to_string()),
+            Message::new("player1".to_string(), "three".to_string()),
+        ];
+        let marker = history[1].id.clone();
+
+        let marked = record_receipts(
+            &mut history,
+            &BlockList::default(),
+            "player2",
+            Some(&marker),
+            ReceiptKind::Read,
+            100,
+        );
+
+        assert_eq!(marked["player1"], vec![history[0].id.clone()]);
+        let receipt = &history[0].receipts["player2"];
+        assert_eq!(receipt.delivered_at, Some(100));
+        assert_eq!(receipt.read_at, Some(100));
+        assert!(history[2].receipts.is_empty());
+
+        // Already-read messages aren't reported twice
+        let marked = record_receipts(
+            &mut history,
+            &BlockList::default(),
+            "player2",
+            None,
+            ReceiptKind::Read,
+            200,
+        );
+        assert_eq!(marked["player1"], vec![history[2].id.clone()]);
+        assert_eq!(history[0].receipts["player2"].read_at, Some(100));
+    }
+
+    #[test]
+    fn test_delivered_messages_are_stored_and_notified() {
+        let mut connection = Connection::new("player1".to_string());
+        connection.players.push("player2".to_string());
+        connection.status = ConnectionStatus::Active;
+        let connections = RwLock::new(HashMap::from([(connection.id.clone(), connection.clone())]));
+        let messages = RwLock::new(HashMap::new());
+        let notifications = RwLock::new(HashMap::new());
+        let profiles = RwLock::new(HashMap::new());
+        let blocks = RwLock::new(BlockList::default());
+        let mutes = RwLock::new(MuteList::default());
+        let sessions = SessionRegistry::default();
+        let outbox = Outbox {
+            connections: &connections,
+            messages: &messages,
+            notifications: &notifications,
+            profiles: &profiles,
+            blocks: &blocks,
+            mutes: &mutes,
+            sessions: &sessions,
+            message_limits: &MessageLimits::default(),
+            moderation: &FilterChain::default(),
+            rate_limits: &RateLimits::default(),
+            producer: None,
+        };
+
+        let message =
+            deliver_message(&outbox, &connection.id, "player1", "hello", "websocket").unwrap();
+        assert_eq!(message.content, "hello");
+        let stored = &messages.read().unwrap()[&connection.id];
+        assert_eq!(stored.len(), 1);
+        assert_eq!(stored[0].id, message.id);
+        assert_eq!(notifications.read().unwrap()["player2"].len(), 1);
+
+        assert_eq!(
+            deliver_message(&outbox, &connection.id, "player3", "hi", "websocket").unwrap_err(),
+            SendError::NotMember
+        );
+        connections
+            .write()
+            .unwrap()
+            .get_mut(&connection.id)
+            .unwrap()
+            .status = ConnectionStatus::Closed;
+        assert_eq!(
+            deliver_message(&outbox, &connection.id, "player1", "hi", "websocket").unwrap_err(),
+            SendError::Closed
+        );
+    }
+}
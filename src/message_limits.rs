@@ -0,0 +1,118 @@
+use serde::Deserialize;
+
+pub const DEFAULT_MAX_BYTES: usize = 4096;
+pub const DEFAULT_MAX_CHARS: usize = 1000;
+pub const DEFAULT_EDIT_WINDOW_SECS: i64 = 15 * 60;
+
+// Why a message was refused
+#[derive(Debug, Clone, PartialEq)]
+pub enum MessageError {
+    Empty,
+    TooManyBytes(usize),
+    TooManyChars(usize),
+}
+
+impl MessageError {
+    // Stable code clients can switch on
+    pub fn code(&self) -> &'static str {
+        match self {
+            MessageError::Empty => "message_empty",
+            MessageError::TooManyBytes(_) => "message_too_many_bytes",
+            MessageError::TooManyChars(_) => "message_too_many_chars",
+        }
+    }
+
+    pub fn message(&self) -> String {
+        match self {
+            MessageError::Empty => "Message must not be empty".to_string(),
+            MessageError::TooManyBytes(max) => format!("Message is longer than {} bytes", max),
+            MessageError::TooManyChars(max) => format!("Message is longer than {} characters", max),
+        }
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "error": self.message(),
+            "code": self.code(),
+        })
+    }
+}
+
+// Limits applied to message content on both the HTTP and WebSocket paths
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MessageLimits {
+    pub max_bytes: usize,
+    pub max_chars: usize,
+    // How long after sending a message its sender may still edit it
+    pub edit_window_secs: i64,
+}
+
+impl Default for MessageLimits {
+    fn default() -> Self {
+        MessageLimits {
+            max_bytes: DEFAULT_MAX_BYTES,
+            max_chars: DEFAULT_MAX_CHARS,
+            edit_window_secs: DEFAULT_EDIT_WINDOW_SECS,
+        }
+    }
+}
+
+impl MessageLimits {
+    // Strip control characters (keeping newlines and tabs) and enforce the limits
+    pub fn sanitize(&self, content: &str) -> Result<String, MessageError> {
+        let cleaned: String = content
+            .chars()
+            .filter(|c| !c.is_control() || *c == '\n' || *c == '\t')
+            .collect();
+
+        if cleaned.trim().is_empty() {
+            return Err(MessageError::Empty);
+        }
+        if cleaned.len() > self.max_bytes {
+            return Err(MessageError::TooManyBytes(self.max_bytes));
+        }
+        if cleaned.chars().count() > self.max_chars {
+            return Err(MessageError::TooManyChars(self.max_chars));
+        }
+
+        Ok(cleaned)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_control_characters_are_stripped() {
+        let limits = MessageLimits::default();
+
+        let cleaned = limits.sanitize("hi\u{0}\u{7} there\r\nbye\tnow").unwrap();
+
+        assert_eq!(cleaned, "hi there\nbye\tnow");
+    }
+
+    #[test]
+    fn test_empty_and_whitespace_messages_are_rejected() {
+        let limits = MessageLimits::default();
+
+        assert_eq!(limits.sanitize(""), Err(MessageError::Empty));
+        assert_eq!(limits.sanitize("  \n\t "), Err(MessageError::Empty));
+        assert_eq!(limits.sanitize("\u{1b}\u{0}"), Err(MessageError::Empty));
+    }
+
+    #[test]
+    fn test_byte_and_char_limits() {
+        let limits = MessageLimits {
+            max_bytes: 8,
+            max_chars: 3,
+            ..MessageLimits::default()
+        };
+
+        assert!(limits.sanitize("abc").is_ok());
+        assert_eq!(limits.sanitize("abcd"), Err(MessageError::TooManyChars(3)));
+        // Three characters but nine bytes
+        assert_eq!(limits.sanitize("日本語"), Err(MessageError::TooManyBytes(8)));
+    }
+}
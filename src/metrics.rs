@@ -0,0 +1,195 @@
+use actix_web::http::StatusCode;
+use actix_web::{web, HttpResponse};
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use rdkafka::statistics::Statistics;
+use std::collections::HashMap;
+use std::sync::{OnceLock, RwLock};
+use std::time::Duration;
+
+use crate::connection::{Connection, ConnectionStatus};
+use crate::notification::Notification;
+use crate::session::SessionRegistry;
+use crate::store::{self, StoreError};
+
+const STATUSES: [ConnectionStatus; 4] = [
+    ConnectionStatus::Pending,
+    ConnectionStatus::Active,
+    ConnectionStatus::Expired,
+    ConnectionStatus::Closed,
+];
+
+fn status_label(status: &ConnectionStatus) -> &'static str {
+    match status {
+        ConnectionStatus::Pending => "pending",
+        ConnectionStatus::Active => "active",
+        ConnectionStatus::Expired => "expired",
+        ConnectionStatus::Closed => "closed",
+    }
+}
+
+// Everything exported on /metrics. Counters are updated where things happen;
+// gauges over the stores are refreshed when scraped.
+pub struct Metrics {
+    registry: Registry,
+    connections: IntGaugeVec,
+    websocket_sessions: IntGauge,
+    messages_sent: IntCounterVec,
+    notifications_queued: IntGauge,
+    kafka_publish: IntCounterVec,
+    kafka_publish_duration: HistogramVec,
+    consumer_lag: IntGaugeVec,
+    http_request_duration: HistogramVec,
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let registry = Registry::new_custom(Some("friends_connect".to_string()), None)
+            .expect("metric prefix is valid");
+
+        let connections = IntGaugeVec::new(Opts::new("connections", "Connections by status"), &["status"])
+            .expect("metric is valid");
+        let websocket_sessions = IntGauge::new("websocket_sessions", "Open WebSocket sessions")
+            .expect("metric is valid");
+        let messages_sent = IntCounterVec::new(
+            Opts::new("messages_sent_total", "Chat messages accepted"),
+            &["transport"],
+        )
+        .expect("metric is valid");
+        let notifications_queued = IntGauge::new("notifications_queued", "Notifications waiting to be acknowledged")
+            .expect("metric is valid");
+        let kafka_publish = IntCounterVec::new(
+            Opts::new("kafka_publish_total", "Events published to Redpanda"),
+            &["topic", "outcome"],
+        )
+        .expect("metric is valid");
+        let kafka_publish_duration = HistogramVec::new(
+            HistogramOpts::new("kafka_publish_duration_seconds", "Time until Redpanda acknowledged a publish"),
+            &["topic"],
+        )
+        .expect("metric is valid");
+        let consumer_lag = IntGaugeVec::new(
+            Opts::new("kafka_consumer_lag", "Messages the notification consumer is behind"),
+            &["topic", "partition"],
+        )
+        .expect("metric is valid");
+        let http_request_duration = HistogramVec::new(
+            HistogramOpts::new("http_request_duration_seconds", "HTTP request latency"),
+            &["method", "route", "status"],
+        )
+        .expect("metric is valid");
+
+        for collector in [
+            Box::new(connections.clone()) as Box<dyn prometheus::core::Collector>,
+            Box::new(websocket_sessions.clone()),
+            Box::new(messages_sent.clone()),
+            Box::new(notifications_queued.clone()),
+            Box::new(kafka_publish.clone()),
+            Box::new(kafka_publish_duration.clone()),
+            Box::new(consumer_lag.clone()),
+            Box::new(http_request_duration.clone()),
+        ] {
+            registry.register(collector).expect("metric names are unique");
+        }
+
+        Metrics {
+            registry,
+            connections,
+            websocket_sessions,
+            messages_sent,
+            notifications_queued,
+            kafka_publish,
+            kafka_publish_duration,
+            consumer_lag,
+            http_request_duration,
+        }
+    }
+
+    // `transport` is "http" or "websocket"
+    pub fn message_sent(&self, transport: &str) {
+        self.messages_sent.with_label_values(&[transport]).inc();
+    }
+
+    pub fn kafka_published(&self, topic: &str, ok: bool, elapsed: Duration) {
+        let outcome = if ok { "success" } else { "failure" };
+        self.kafka_publish.with_label_values(&[topic, outcome]).inc();
+        self.kafka_publish_duration
+            .with_label_values(&[topic])
+            .observe(elapsed.as_secs_f64());
+    }
+
+    // `route` is the matched pattern, never the raw path, to bound the label values
+    pub fn http_request(&self, method: &str, route: Option<&str>, status: StatusCode, elapsed: Duration) {
+        self.http_request_duration
+            .with_label_values(&[method, route.unwrap_or("unmatched"), status.as_str()])
+            .observe(elapsed.as_secs_f64());
+    }
+
+    // Fed by librdkafka's periodic statistics for the notification consumer
+    pub fn consumer_statistics(&self, statistics: &Statistics) {
+        for (topic, stats) in &statistics.topics {
+            for (partition, stats) in &stats.partitions {
+                // -1 is librdkafka's internal unassigned partition
+                if *partition >= 0 && stats.consumer_lag >= 0 {
+                    self.consumer_lag
+                        .with_label_values(&[topic.as_str(), partition.to_string().as_str()])
+                        .set(stats.consumer_lag);
+                }
+            }
+        }
+    }
+
+    fn refresh(
+        &self,
+        conn_map: &HashMap<String, Connection>,
+        notifications: &HashMap<String, Vec<Notification>>,
+        sessions: &SessionRegistry,
+    ) {
+        let mut counts: HashMap<&'static str, i64> = STATUSES.iter().map(|s| (status_label(s), 0)).collect();
+        for (key, conn) in conn_map {
+            // Connections are also stored under their live link id
+            if *key == conn.id {
+                *counts.entry(status_label(&conn.status)).or_default() += 1;
+            }
+        }
+        for (status, count) in counts {
+            self.connections.with_label_values(&[status]).set(count);
+        }
+
+        self.notifications_queued
+            .set(notifications.values().map(Vec::len).sum::<usize>() as i64);
+        self.websocket_sessions.set(sessions.session_count() as i64);
+    }
+
+    pub fn render(&self) -> String {
+        let mut buffer = Vec::new();
+        if let Err(err) = TextEncoder::new().encode(&self.registry.gather(), &mut buffer) {
+            tracing::error!(error = %err, "Failed to encode metrics");
+        }
+        String::from_utf8(buffer).unwrap_or_default()
+    }
+}
+
+// The process-wide metrics; Kafka publishes happen far from any handler state
+pub fn metrics() -> &'static Metrics {
+    static METRICS: OnceLock<Metrics> = OnceLock::new();
+    METRICS.get_or_init(Metrics::new)
+}
+
+pub async fn get_metrics(
+    connections: web::Data<RwLock<HashMap<String, Connection>>>,
+    notifications: web::Data<RwLock<HashMap<String, Vec<Notification>>>>,
+    sessions: web::Data<SessionRegistry>,
+) -> Result<HttpResponse, StoreError> {
+    let metrics = metrics();
+    metrics.refresh(
+        &*store::read(&connections, "connections")?,
+        &*store::read(&notifications, "notifications")?,
+        &sessions,
+    );
+
+    Ok(HttpResponse::Ok()
+        .content_type(prometheus::TEXT_FORMAT)
+        .body(metrics.render()))
+}
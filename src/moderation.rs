@@ -0,0 +1,361 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::store::recover;
+
+// Who sent a message and where, for filters that need more than the text
+pub struct MessageContext<'a> {
+    pub player_id: &'a str,
+    pub connection_id: &'a str,
+}
+
+// What a filter wants done with a message
+#[derive(Debug, Clone, PartialEq)]
+pub enum FilterDecision {
+    Allow,
+    // Replace the content and keep going
+    Modify { content: String, reason: String },
+    Reject { reason: String },
+}
+
+pub trait MessageFilter: Send + Sync {
+    fn name(&self) -> &'static str;
+    fn check(&self, ctx: &MessageContext, content: &str) -> FilterDecision;
+
+    // Forget state that can no longer affect a decision; called periodically
+    fn prune(&self) {}
+}
+
+// A non-trivial decision, published as a `message_moderated` event
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct ModerationDecision {
+    pub filter: &'static str,
+    pub action: &'static str,
+    pub reason: String,
+}
+
+impl ModerationDecision {
+    // `message_moderated` event for the connection-events topic
+    pub fn to_event(&self, ctx: &MessageContext) -> serde_json::Value {
+        serde_json::json!({
+            "event": "message_moderated",
+            "connection_id": ctx.connection_id,
+            "player_id": ctx.player_id,
+            "filter": self.filter,
+            "action": self.action,
+            "reason": self.reason,
+            "timestamp": chrono::Utc::now().timestamp(),
+        })
+    }
+}
+
+pub struct ModerationOutcome {
+    // The content to deliver, or the reason it was rejected
+    pub result: Result<String, String>,
+    pub decisions: Vec<ModerationDecision>,
+}
+
+// Runs filters in order, feeding each the previous filter's output
+#[derive(Default)]
+pub struct FilterChain {
+    filters: Vec<Box<dyn MessageFilter>>,
+}
+
+impl FilterChain {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with(mut self, filter: impl MessageFilter + 'static) -> Self {
+        self.filters.push(Box::new(filter));
+        self
+    }
+
+    pub fn apply(&self, ctx: &MessageContext, content: &str) -> ModerationOutcome {
+        let mut content = content.to_string();
+        let mut decisions = Vec::new();
+
+        for filter in &self.filters {
+            match filter.check(ctx, &content) {
+                FilterDecision::Allow => {}
+                FilterDecision::Modify { content: modified, reason } => {
+                    decisions.push(ModerationDecision {
+                        filter: filter.name(),
+                        action: "modified",
+                        reason,
+                    });
+                    content = modified;
+                }
+                FilterDecision::Reject { reason } => {
+                    decisions.push(ModerationDecision {
+                        filter: filter.name(),
+                        action: "rejected",
+                        reason: reason.clone(),
+                    });
+                    return ModerationOutcome {
+                        result: Err(reason),
+                        decisions,
+                    };
+                }
+            }
+        }
+
+        ModerationOutcome {
+            result: Ok(content),
+            decisions,
+        }
+    }
+
+    pub fn prune(&self) {
+        for filter in &self.filters {
+            filter.prune();
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum WordAction {
+    Mask,
+    Reject,
+}
+
+// Masks or rejects whole words from a configured list, ignoring case
+pub struct WordListFilter {
+    words: HashSet<String>,
+    action: WordAction,
+}
+
+impl WordListFilter {
+    pub fn new(words: impl IntoIterator<Item = String>, action: WordAction) -> Self {
+        WordListFilter {
+            words: words.into_iter().map(|w| w.to_lowercase()).collect(),
+            action,
+        }
+    }
+}
+
+impl MessageFilter for WordListFilter {
+    fn name(&self) -> &'static str {
+        "word_list"
+    }
+
+    fn check(&self, _ctx: &MessageContext, content: &str) -> FilterDecision {
+        let mut output = String::with_capacity(content.len());
+        let mut word = String::new();
+        let mut matched = false;
+
+        let mut flush = |word: &mut String, output: &mut String| {
+            if self.words.contains(&word.to_lowercase()) {
+                matched = true;
+                output.extend(word.chars().map(|_| '*'));
+            } else {
+                output.push_str(word);
+            }
+            word.clear();
+        };
+
+        for c in content.chars() {
+            if c.is_alphanumeric() {
+                word.push(c);
+            } else {
+                flush(&mut word, &mut output);
+                output.push(c);
+            }
+        }
+        flush(&mut word, &mut output);
+
+        if !matched {
+            return FilterDecision::Allow;
+        }
+
+        match self.action {
+            WordAction::Mask => FilterDecision::Modify {
+                content: output,
+                reason: "Blocked word masked".to_string(),
+            },
+            WordAction::Reject => FilterDecision::Reject {
+                reason: "Message contains a blocked word".to_string(),
+            },
+        }
+    }
+}
+
+// Rejects messages containing URLs or bare domain names
+pub struct LinkFilter;
+
+const LINK_TLDS: &[&str] = &[
+    "com", "net", "org", "io", "gg", "co", "me", "ly", "app", "xyz", "tv", "info",
+];
+
+impl LinkFilter {
+    fn looks_like_link(token: &str) -> bool {
+        let token = token.trim_matches(|c: char| !c.is_alphanumeric() && c != '/' && c != ':');
+        let lower = token.to_lowercase();
+
+        if lower.contains("://") || lower.starts_with("www.") {
+            return true;
+        }
+
+        // example.com, example.com/path
+        let host = lower.split('/').next().unwrap_or("");
+        match host.rsplit_once('.') {
+            Some((name, tld)) => !name.is_empty() && LINK_TLDS.contains(&tld),
+            None => false,
+        }
+    }
+}
+
+impl MessageFilter for LinkFilter {
+    fn name(&self) -> &'static str {
+        "link"
+    }
+
+    fn check(&self, _ctx: &MessageContext, content: &str) -> FilterDecision {
+        if content.split_whitespace().any(Self::looks_like_link) {
+            FilterDecision::Reject {
+                reason: "Links are not allowed".to_string(),
+            }
+        } else {
+            FilterDecision::Allow
+        }
+    }
+}
+
+// Rejects the same message sent too many times in a short window
+pub struct SpamFilter {
+    max_repeats: usize,
+    window: Duration,
+    recent: Mutex<HashMap<String, VecDeque<(String, Instant)>>>,
+}
+
+impl SpamFilter {
+    pub fn new(max_repeats: usize, window: Duration) -> Self {
+        SpamFilter {
+            max_repeats,
+            window,
+            recent: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Drop messages older than the window, oldest first
+    fn expire(&self, history: &mut VecDeque<(String, Instant)>, now: Instant) {
+        while history
+            .front()
+            .is_some_and(|(_, sent)| now.duration_since(*sent) > self.window)
+        {
+            history.pop_front();
+        }
+    }
+}
+
+impl MessageFilter for SpamFilter {
+    fn name(&self) -> &'static str {
+        "spam"
+    }
+
+    fn check(&self, ctx: &MessageContext, content: &str) -> FilterDecision {
+        let now = Instant::now();
+        let normalized = content.trim().to_lowercase();
+        let key = format!("{}:{}", ctx.player_id, ctx.connection_id);
+
+        let mut recent = recover(self.recent.lock());
+        let history = recent.entry(key).or_default();
+        self.expire(history, now);
+
+        let repeats = history.iter().filter(|(text, _)| *text == normalized).count();
+        if repeats >= self.max_repeats {
+            return FilterDecision::Reject {
+                reason: "Message repeated too often".to_string(),
+            };
+        }
+
+        history.push_back((normalized, now));
+        FilterDecision::Allow
+    }
+    // Senders who have been quiet for a whole window leave nothing to compare
+    fn prune(&self) {
+        let now = Instant::now();
+        recover(self.recent.lock()).retain(|_, history| {
+            self.expire(history, now);
+            !history.is_empty()
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CTX: MessageContext = MessageContext {
+        player_id: "player1",
+        connection_id: "conn1",
+    };
+
+    #[test]
+    fn test_word_list_masks_whole_words_only() {
+        let filter = WordListFilter::new(vec!["darn".to_string()], WordAction::Mask);
+
+        let decision = filter.check(&CTX, "Darn it, darnation!");
+
+        assert_eq!(
+            decision,
+            FilterDecision::Modify {
+                content: "**** it, darnation!".to_string(),
+                reason: "Blocked word masked".to_string(),
+            }
+        );
+        assert_eq!(filter.check(&CTX, "all good"), FilterDecision::Allow);
+    }
+
+    #[test]
+    fn test_link_filter_detects_urls_and_domains() {
+        let filter = LinkFilter;
+
+        assert!(matches!(filter.check(&CTX, "see https://x.test"), FilterDecision::Reject { .. }));
+        assert!(matches!(filter.check(&CTX, "go to www.example"), FilterDecision::Reject { .. }));
+        assert!(matches!(filter.check(&CTX, "visit example.com/free"), FilterDecision::Reject { .. }));
+        assert_eq!(filter.check(&CTX, "good game. rematch?"), FilterDecision::Allow);
+    }
+
+    #[test]
+    fn test_spam_filter_rejects_repeats() {
+        let filter = SpamFilter::new(2, Duration::from_secs(60));
+
+        assert_eq!(filter.check(&CTX, "hi"), FilterDecision::Allow);
+        assert_eq!(filter.check(&CTX, "HI "), FilterDecision::Allow);
+        assert!(matches!(filter.check(&CTX, "hi"), FilterDecision::Reject { .. }));
+        assert_eq!(filter.check(&CTX, "something else"), FilterDecision::Allow);
+    }
+
+    #[test]
+    fn test_spam_filter_forgets_quiet_senders() {
+        let filter = SpamFilter::new(2, Duration::from_millis(1));
+        filter.check(&CTX, "hello");
+        std::thread::sleep(Duration::from_millis(5));
+        assert_eq!(filter.recent.lock().unwrap().len(), 1);
+
+        filter.prune();
+
+        assert_eq!(filter.recent.lock().unwrap().len(), 0);
+    }
+
+    #[test]
+    fn test_chain_records_decisions_and_stops_on_reject() {
+        let chain = FilterChain::new()
+            .with(WordListFilter::new(vec!["darn".to_string()], WordAction::Mask))
+            .with(LinkFilter);
+
+        let masked = chain.apply(&CTX, "darn it");
+        assert_eq!(masked.result, Ok("**** it".to_string()));
+        assert_eq!(masked.decisions.len(), 1);
+        assert_eq!(masked.decisions[0].action, "modified");
+
+        let rejected = chain.apply(&CTX, "darn example.com");
+        assert!(rejected.result.is_err());
+        assert_eq!(rejected.decisions.len(), 2);
+        assert_eq!(rejected.decisions[1].filter, "link");
+    }
+}
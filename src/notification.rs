@@ -0,0 +1,46 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// A notification queued for a player until they acknowledge it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Notification {
+    pub content: String,
+    // The player whose action caused the notification, if any
+    pub player_id: Option<String>,
+    pub connection_id: Option<String>,
+    pub timestamp: i64,
+}
+
+impl Notification {
+    pub fn new(content: String) -> Self {
+        Notification {
+            content,
+            player_id: None,
+            connection_id: None,
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs() as i64,
+        }
+    }
+
+    pub fn about(content: String, player_id: &str, connection_id: &str) -> Self {
+        Notification {
+            player_id: Some(player_id.to_string()),
+            connection_id: Some(connection_id.to_string()),
+            ..Notification::new(content)
+        }
+    }
+}
+
+pub fn push_notification(
+    notifications: &mut HashMap<String, Vec<Notification>>,
+    player_id: &str,
+    notification: Notification,
+) {
+    notifications
+        .entry(player_id.to_string())
+        .or_default()
+        .push(notification);
+}
@@ -0,0 +1,264 @@
+use actix_web::http::header;
+use actix_web::{web, HttpRequest, HttpResponse};
+use actix_web_actors::ws::CloseCode;
+use rdkafka::producer::FutureProducer;
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::sync::RwLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::admin::{require_admin, AdminToken};
+use crate::block::{BlockList, MuteList};
+use crate::connection::{Connection, ConnectionStatus, Message};
+use crate::friend_request::FriendRequest;
+use crate::label::label_for;
+use crate::notification::Notification;
+use crate::profile::PlayerProfile;
+use crate::server::{send_to_redpanda, store_connection};
+use crate::session::{CloseSession, ServerEvent, SessionRegistry};
+use crate::store::{self, StoreError};
+
+// Stands in for a deleted player wherever history is kept for the others
+pub const DELETED_PLAYER: &str = "deleted-player";
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64
+}
+
+// Whether any string in an event payload is the player's id
+fn references(value: &Value, player_id: &str) -> bool {
+    match value {
+        Value::String(text) => text == player_id,
+        Value::Array(items) => items.iter().any(|item| references(item, player_id)),
+        Value::Object(fields) => fields.values().any(|field| references(field, player_id)),
+        _ => false,
+    }
+}
+
+// Connections the player is in, listed once each
+fn connections_of(conn_map: &HashMap<String, Connection>, player_id: &str) -> Vec<Connection> {
+    let mut found: Vec<Connection> = conn_map
+        .iter()
+        .filter(|(key, conn)| **key == conn.id && conn.players.iter().any(|p| p == player_id))
+        .map(|(_, conn)| conn.clone())
+        .collect();
+    found.sort_by_key(|conn| conn.created_at);
+    found
+}
+
+// Everything stored about the player, as one JSON download
+pub async fn export_player_data(
+    req: HttpRequest,
+    admin_token: Option<web::Data<AdminToken>>,
+    player_id: web::Path<String>,
+    connections: web::Data<RwLock<HashMap<String, Connection>>>,
+    messages: web::Data<RwLock<HashMap<String, Vec<Message>>>>,
+    notifications: web::Data<RwLock<HashMap<String, Vec<Notification>>>>,
+    profiles: web::Data<RwLock<HashMap<String, PlayerProfile>>>,
+    labels: web::Data<RwLock<HashMap<String, HashMap<String, String>>>>,
+    friend_requests: web::Data<RwLock<HashMap<String, FriendRequest>>>,
+    blocks: web::Data<RwLock<BlockList>>,
+    mutes: web::Data<RwLock<MuteList>>,
+) -> Result<HttpResponse, StoreError> {
+    if let Err(resp) = require_admin(&req, admin_token.as_ref().map(|token| token.get_ref())) {
+        return Ok(resp);
+    }
+    let player_id = player_id.into_inner();
+    let player_connections = connections_of(&*store::read(&connections, "connections")?, &player_id);
+
+    let labels = store::read(&labels, "labels")?;
+    let connection_values: Vec<serde_json::Value> = player_connections
+        .iter()
+        .map(|conn| {
+            let mut value = json!(conn);
+            value["label"] = json!(label_for(&labels, &player_id, &conn.id));
+            value
+        })
+        .collect();
+
+    let history: BTreeMap<&str, Vec<Message>> = {
+        let messages = store::read(&messages, "messages")?;
+        player_connections
+            .iter()
+            .map(|conn| (conn.id.as_str(), messages.get(&conn.id).cloned().unwrap_or_default()))
+            .collect()
+    };
+
+    let mut requests: Vec<FriendRequest> = store::read(&friend_requests, "friend_requests")?
+        .values()
+        .filter(|req| req.from == player_id || req.to == player_id)
+        .cloned()
+        .collect();
+    requests.sort_by_key(|req| req.created_at);
+
+    let archive = json!({
+        "player_id": player_id,
+        "exported_at": now(),
+        "profile": store::read(&profiles, "profiles")?.get(&player_id),
+        "connections": connection_values,
+        "messages": history,
+        "notifications": store::read(&notifications, "notifications")?
+            .get(&player_id)
+            .cloned()
+            .unwrap_or_default(),
+        "friend_requests": requests,
+        "blocked_players": store::read(&blocks, "blocks")?.blocked_by(&player_id),
+        "muted_connections": store::read(&mutes, "mutes")?.muted_by(&player_id),
+    });
+
+    Ok(HttpResponse::Ok()
+        .insert_header((
+            header::CONTENT_DISPOSITION,
+            format!("attachment; filename=\"player-{}.json\"", player_id),
+        ))
+        .json(archive))
+}
+
+// Drop the player's own data, close their connections and anonymise what the
+// other members keep. Kafka can't be rewritten from here, so a `player_deleted`
+// event tells downstream consumers to purge their copies.
+pub async fn delete_player(
+    req: HttpRequest,
+    admin_token: Option<web::Data<AdminToken>>,
+    player_id: web::Path<String>,
+    connections: web::Data<RwLock<HashMap<String, Connection>>>,
+    messages: web::Data<RwLock<HashMap<String, Vec<Message>>>>,
+    notifications: web::Data<RwLock<HashMap<String, Vec<Notification>>>>,
+    profiles: web::Data<RwLock<HashMap<String, PlayerProfile>>>,
+    labels: web::Data<RwLock<HashMap<String, HashMap<String, String>>>>,
+    friend_requests: web::Data<RwLock<HashMap<String, FriendRequest>>>,
+    blocks: web::Data<RwLock<BlockList>>,
+    mutes: web::Data<RwLock<MuteList>>,
+    sessions: web::Data<SessionRegistry>,
+    producer: Option<web::Data<FutureProducer>>,
+) -> Result<HttpResponse, StoreError> {
+    if let Err(resp) = require_admin(&req, admin_token.as_ref().map(|token| token.get_ref())) {
+        return Ok(resp);
+    }
+    let player_id = player_id.into_inner();
+    if player_id == DELETED_PLAYER {
+        return Ok(HttpResponse::BadRequest().json(json!({
+            "error": "Invalid player id"
+        })));
+    }
+
+    // Close every connection and swap the player out of its member list
+    let closed: Vec<Connection> = {
+        let mut conn_map = store::write(&connections, "connections")?;
+        connections_of(&conn_map, &player_id)
+            .into_iter()
+            .map(|mut conn| {
+                conn.status = ConnectionStatus::Closed;
+                conn_map.remove(&conn.link_id);
+                conn.revoke_link();
+                for player in conn.players.iter_mut().filter(|p| **p == player_id) {
+                    *player = DELETED_PLAYER.to_string();
+                }
+                store_connection(&mut conn_map, &conn);
+                conn
+            })
+            .collect()
+    };
+    let connection_ids: HashSet<&str> = closed.iter().map(|conn| conn.id.as_str()).collect();
+
+    // Their messages become tombstones; their reactions and receipts go
+    let mut anonymised_messages = 0;
+    {
+        let mut messages = store::write(&messages, "messages")?;
+        let histories = messages
+            .iter_mut()
+            .filter(|(connection_id, _)| connection_ids.contains(connection_id.as_str()))
+            .map(|(_, history)| history);
+        for history in histories {
+            for message in history.iter_mut() {
+                if message.from == player_id {
+                    message.tombstone();
+                    message.from = DELETED_PLAYER.to_string();
+                    anonymised_messages += 1;
+                }
+                message.receipts.remove(&player_id);
+                for emoji in message.reactions.keys().cloned().collect::<Vec<_>>() {
+                    message.react(&player_id, &emoji, false);
+                }
+            }
+        }
+    }
+
+    // Their inbox, and anything in other inboxes caused by them or about their
+    // connections
+    let deleted_notifications = {
+        let mut notifications = store::write(&notifications, "notifications")?;
+        let mut deleted = notifications.remove(&player_id).map_or(0, |removed| removed.len());
+        for inbox in notifications.values_mut() {
+            let before = inbox.len();
+            inbox.retain(|notification| {
+                notification.player_id.as_deref() != Some(player_id.as_str())
+                    && !notification
+                        .connection_id
+                        .as_deref()
+                        .is_some_and(|id| connection_ids.contains(id))
+            });
+            deleted += before - inbox.len();
+        }
+        deleted
+    };
+
+    store::write(&profiles, "profiles")?.remove(&player_id);
+    store::write(&labels, "labels")?.remove(&player_id);
+    store::write(&friend_requests, "friend_requests")?
+        .retain(|_, req| req.from != player_id && req.to != player_id);
+    store::write(&blocks, "blocks")?.forget(&player_id);
+    store::write(&mutes, "mutes")?.forget(&player_id);
+
+    sessions.forget(
+        &player_id,
+        CloseSession {
+            code: CloseCode::Policy,
+            reason: "Player deleted".to_string(),
+        },
+    );
+    // Other players' replay buffers hold their messages and presence changes
+    sessions.scrub(|event| {
+        references(&event.payload, &player_id)
+            || event.payload["connection_id"]
+                .as_str()
+                .is_some_and(|id| connection_ids.contains(id))
+    });
+    for conn in &closed {
+        let notice = ServerEvent::new("connection_closed", json!({
+            "connection_id": conn.id,
+            "reason": "player_deleted",
+        }));
+        for player in conn.players.iter().filter(|p| *p != DELETED_PLAYER) {
+            sessions.send(player, notice.clone());
+        }
+    }
+
+    tracing::info!(
+        player_id = %player_id,
+        connections = closed.len(),
+        messages = anonymised_messages,
+        notifications = deleted_notifications,
+        "Deleted player data"
+    );
+
+    if let Some(producer) = producer {
+        let event = json!({
+            "event": "player_deleted",
+            "player_id": player_id,
+            "connection_ids": closed.iter().map(|conn| &conn.id).collect::<Vec<_>>(),
+            "timestamp": now(),
+        });
+        send_to_redpanda(producer.get_ref(), "connection-events", &player_id, &event.to_string());
+    }
+
+    Ok(HttpResponse::Ok().json(json!({
+        "player_id": player_id,
+        "closed_connections": closed.iter().map(|conn| &conn.id).collect::<Vec<_>>(),
+        "anonymised_messages": anonymised_messages,
+        "deleted_notifications": deleted_notifications,
+    })))
+}
@@ -0,0 +1,165 @@
+use actix_web::{web, HttpResponse};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::{HashMap, HashSet};
+use std::sync::RwLock;
+use std::time::Duration;
+
+use crate::block::BlockList;
+use crate::connection::{Connection, ConnectionStatus};
+use crate::session::{ServerEvent, SessionRegistry};
+use crate::socket_limits::SocketLimits;
+use crate::store::{self, StoreError};
+
+// Players with no frames for this long show as away
+pub const AWAY_AFTER: Duration = Duration::from_secs(5 * 60);
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Presence {
+    Online,
+    Away,
+    #[default]
+    Offline,
+}
+
+#[derive(Deserialize)]
+pub struct PresenceQuery {
+    // The player asking
+    pub player_id: String,
+}
+
+// Players allowed to see `player_id`'s presence: those sharing an open
+// connection with them, minus anyone on either side of a block
+pub fn presence_contacts(
+    conn_map: &HashMap<String, Connection>,
+    blocks: &BlockList,
+    player_id: &str,
+) -> HashSet<String> {
+    conn_map
+        .iter()
+        .filter(|(key, conn)| {
+            **key == conn.id
+                && conn.status != ConnectionStatus::Closed
+                && conn.players.iter().any(|p| p == player_id)
+        })
+        .flat_map(|(_, conn)| conn.players.iter())
+        .filter(|other| *other != player_id && !blocks.between(other, player_id))
+        .cloned()
+        .collect()
+}
+
+pub fn current_presence(sessions: &SessionRegistry, limits: &SocketLimits, player_id: &str) -> Presence {
+    sessions.presence(player_id, limits.client_timeout, AWAY_AFTER)
+}
+
+// Tell everyone who can see the player that their presence changed
+pub(crate) fn broadcast_presence(
+    sessions: &SessionRegistry,
+    conn_map: &HashMap<String, Connection>,
+    blocks: &BlockList,
+    player_id: &str,
+    presence: Presence,
+) {
+    let event = ServerEvent::new("presence_changed", json!({
+        "player_id": player_id,
+        "status": presence,
+    }));
+    for contact in presence_contacts(conn_map, blocks, player_id) {
+        sessions.send(&contact, event.clone());
+    }
+}
+
+// Tell the player's contacts if their presence changed since they were last told
+pub(crate) fn announce_presence(
+    sessions: &SessionRegistry,
+    limits: &SocketLimits,
+    connections: &RwLock<HashMap<String, Connection>>,
+    blocks: &RwLock<BlockList>,
+    player_id: &str,
+) -> Result<(), StoreError> {
+    if let Some(presence) = sessions.announce(player_id, limits.client_timeout, AWAY_AFTER) {
+        broadcast_presence(
+            sessions,
+            &*store::read(connections, "connections")?,
+            &*store::read(blocks, "blocks")?,
+            player_id,
+            presence,
+        );
+    }
+    Ok(())
+}
+
+// Tell contacts about changes no frame triggered: players going idle, and
+// sockets that stopped answering before they were closed
+pub(crate) fn sweep_presence(
+    sessions: &SessionRegistry,
+    limits: &SocketLimits,
+    connections: &RwLock<HashMap<String, Connection>>,
+    blocks: &RwLock<BlockList>,
+) -> Result<usize, StoreError> {
+    let changes = sessions.presence_changes(limits.client_timeout, AWAY_AFTER);
+    if !changes.is_empty() {
+        let conn_map = store::read(connections, "connections")?;
+        let blocks = store::read(blocks, "blocks")?;
+        for (player_id, presence) in &changes {
+            broadcast_presence(sessions, &conn_map, &blocks, player_id, *presence);
+        }
+    }
+    Ok(changes.len())
+}
+
+pub async fn get_presence(
+    player_id: web::Path<String>,
+    query: web::Query<PresenceQuery>,
+    connections: web::Data<RwLock<HashMap<String, Connection>>>,
+    blocks: web::Data<RwLock<BlockList>>,
+    sessions: web::Data<SessionRegistry>,
+    socket_limits: web::Data<SocketLimits>,
+) -> Result<HttpResponse, StoreError> {
+    let player_id = player_id.into_inner();
+
+    if query.player_id != player_id {
+        let contacts = presence_contacts(
+            &*store::read(&connections, "connections")?,
+            &*store::read(&blocks, "blocks")?,
+            &player_id,
+        );
+        if !contacts.contains(&query.player_id) {
+            return Ok(HttpResponse::Forbidden().json(json!({
+                "error": "Presence is only visible to connection members"
+            })));
+        }
+    }
+
+    Ok(HttpResponse::Ok().json(json!({
+        "player_id": player_id,
+        "status": current_presence(&sessions, &socket_limits, &player_id),
+    })))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::store_connection;
+
+    #[test]
+    fn test_contacts_exclude_closed_connections_and_blocks() {
+        let mut conn_map = HashMap::new();
+        let mut open = Connection::new("player1".to_string());
+        open.players.push("player2".to_string());
+        open.players.push("player3".to_string());
+        store_connection(&mut conn_map, &open);
+        let mut closed = Connection::new("player1".to_string());
+        closed.players.push("player4".to_string());
+        closed.status = ConnectionStatus::Closed;
+        store_connection(&mut conn_map, &closed);
+
+        let mut blocks = BlockList::default();
+        blocks.block("player3", "player1");
+
+        let contacts = presence_contacts(&conn_map, &blocks, "player1");
+
+        assert_eq!(contacts, HashSet::from(["player2".to_string()]));
+    }
+}
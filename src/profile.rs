@@ -0,0 +1,155 @@
+use actix_web::{web, HttpResponse};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashMap;
+use std::sync::RwLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::connection::Connection;
+use crate::store::{self, StoreError};
+
+const MAX_DISPLAY_NAME_CHARS: usize = 32;
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct PlayerProfile {
+    pub player_id: String,
+    pub display_name: String,
+    pub avatar_url: Option<String>,
+    pub locale: Option<String>,
+    pub updated_at: i64,
+}
+
+#[derive(Deserialize)]
+pub struct ProfileRequest {
+    pub display_name: String,
+    pub avatar_url: Option<String>,
+    pub locale: Option<String>,
+}
+
+// `?expand=profiles,labels` asks for extra detail in responses
+#[derive(Deserialize)]
+pub struct ExpandQuery {
+    pub expand: Option<String>,
+}
+
+impl ExpandQuery {
+    fn includes(&self, field: &str) -> bool {
+        self.expand
+            .as_deref()
+            .is_some_and(|expand| expand.split(',').any(|e| e.trim() == field))
+    }
+
+    // The profiles of the players involved
+    pub fn profiles(&self) -> bool {
+        self.includes("profiles")
+    }
+
+    // The requesting player's private connection labels
+    pub fn labels(&self) -> bool {
+        self.includes("labels")
+    }
+}
+
+impl ProfileRequest {
+    fn validate(&self) -> Result<(), &'static str> {
+        let name = self.display_name.trim();
+        if name.is_empty() {
+            return Err("display_name must not be empty");
+        }
+        if name.chars().count() > MAX_DISPLAY_NAME_CHARS {
+            return Err("display_name is too long");
+        }
+
+        if let Some(url) = &self.avatar_url {
+            if !(url.starts_with("https://") || url.starts_with("http://")) {
+                return Err("avatar_url must be an http(s) URL");
+            }
+        }
+
+        // Accept BCP 47 style tags such as "en" or "pt-BR"
+        if let Some(locale) = &self.locale {
+            let valid = !locale.is_empty()
+                && locale.len() <= 35
+                && locale
+                    .split('-')
+                    .all(|part| !part.is_empty() && part.chars().all(|c| c.is_ascii_alphanumeric()));
+            if !valid {
+                return Err("locale must be a language tag like en or pt-BR");
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Name to show for a player, falling back to their id when they have no profile
+pub fn display_name(profiles: &HashMap<String, PlayerProfile>, player_id: &str) -> String {
+    profiles
+        .get(player_id)
+        .map(|profile| profile.display_name.clone())
+        .unwrap_or_else(|| player_id.to_string())
+}
+
+// Profiles of the given players, keyed by player id, skipping players without one
+pub fn profiles_for<'a>(
+    profiles: &HashMap<String, PlayerProfile>,
+    player_ids: impl IntoIterator<Item = &'a String>,
+) -> HashMap<String, PlayerProfile> {
+    player_ids
+        .into_iter()
+        .filter_map(|id| profiles.get(id).map(|profile| (id.clone(), profile.clone())))
+        .collect()
+}
+
+// A connection response with the members' profiles attached
+pub fn expand_connection(
+    connection: &Connection,
+    profiles: &HashMap<String, PlayerProfile>,
+) -> serde_json::Value {
+    let mut value = json!(connection);
+    value["profiles"] = json!(profiles_for(profiles, &connection.players));
+    value
+}
+
+pub async fn get_profile(
+    player_id: web::Path<String>,
+    profiles: web::Data<RwLock<HashMap<String, PlayerProfile>>>,
+) -> Result<HttpResponse, StoreError> {
+    let player_id = player_id.into_inner();
+    let profiles = store::read(&profiles, "profiles")?;
+
+    Ok(match profiles.get(&player_id) {
+        Some(profile) => HttpResponse::Ok().json(profile),
+        None => HttpResponse::NotFound().json(json!({
+            "error": "Profile not found"
+        })),
+    })
+}
+
+pub async fn put_profile(
+    player_id: web::Path<String>,
+    profile_req: web::Json<ProfileRequest>,
+    profiles: web::Data<RwLock<HashMap<String, PlayerProfile>>>,
+) -> Result<HttpResponse, StoreError> {
+    if let Err(error) = profile_req.validate() {
+        return Ok(HttpResponse::BadRequest().json(json!({
+            "error": error
+        })));
+    }
+
+    let profile_req = profile_req.into_inner();
+    let profile = PlayerProfile {
+        player_id: player_id.into_inner(),
+        display_name: profile_req.display_name.trim().to_string(),
+        avatar_url: profile_req.avatar_url,
+        locale: profile_req.locale,
+        updated_at: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs() as i64,
+    };
+
+    store::write(&profiles, "profiles")?.insert(profile.player_id.clone(), profile.clone());
+
+    Ok(HttpResponse::Ok().json(profile))
+}
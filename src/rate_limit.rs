@@ -0,0 +1,322 @@
+use actix_web::dev::ServiceRequest;
+use actix_web::http::Method;
+use actix_web::HttpResponse;
+use serde_json::json;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::store::recover;
+
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+// Token bucket rate limiter keyed by an arbitrary string (player id, IP, ...)
+pub struct RateLimiter {
+    capacity: f64,
+    refill_per_sec: f64,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl RateLimiter {
+    // Allow bursts of `capacity` requests, refilling `capacity` tokens every `per`
+    pub fn new(capacity: u32, per: Duration) -> Self {
+        RateLimiter {
+            capacity: capacity as f64,
+            refill_per_sec: capacity as f64 / per.as_secs_f64(),
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // Take a token for `key`, or return how long to wait until one is available
+    pub fn check(&self, key: &str) -> Result<(), Duration> {
+        let now = Instant::now();
+        let mut buckets = recover(self.buckets.lock());
+        let bucket = buckets.entry(key.to_owned()).or_insert(Bucket {
+            tokens: self.capacity,
+            last_refill: now,
+        });
+
+        let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.refill_per_sec).min(self.capacity);
+        bucket.last_refill = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            let missing = 1.0 - bucket.tokens;
+            Err(Duration::from_secs_f64(missing / self.refill_per_sec))
+        }
+    }
+
+    // Drop buckets that have refilled, since a fresh one is the same; returns
+    // how many went
+    pub fn prune(&self) -> usize {
+        let now = Instant::now();
+        let mut buckets = recover(self.buckets.lock());
+        let before = buckets.len();
+        buckets.retain(|_, bucket| {
+            let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+            bucket.tokens + elapsed * self.refill_per_sec < self.capacity
+        });
+        before - buckets.len()
+    }
+}
+
+// The separate request budgets a client has
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Budget {
+    CreateConnection,
+    Join,
+    Message,
+    LinkPreview,
+    WsFrame,
+}
+
+impl Budget {
+    pub const ALL: [Budget; 5] = [
+        Budget::CreateConnection,
+        Budget::Join,
+        Budget::Message,
+        Budget::LinkPreview,
+        Budget::WsFrame,
+    ];
+
+    // Name used under `[rate_limits]` in the config file
+    pub fn name(&self) -> &'static str {
+        match self {
+            Budget::CreateConnection => "create_connection",
+            Budget::Join => "join",
+            Budget::Message => "message",
+            Budget::LinkPreview => "link_preview",
+            Budget::WsFrame => "ws_frame",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Budget> {
+        Budget::ALL.into_iter().find(|budget| budget.name() == name)
+    }
+
+    // Environment variable overriding the budget, as `<requests>/<seconds>`
+    pub fn env_var(&self) -> &'static str {
+        match self {
+            Budget::CreateConnection => "RATE_LIMIT_CREATE_CONNECTION",
+            Budget::Join => "RATE_LIMIT_JOIN",
+            Budget::Message => "RATE_LIMIT_MESSAGE",
+            Budget::LinkPreview => "RATE_LIMIT_LINK_PREVIEW",
+            Budget::WsFrame => "RATE_LIMIT_WS_FRAME",
+        }
+    }
+
+    pub fn default_limit(&self) -> (u32, Duration) {
+        match self {
+            Budget::CreateConnection => (30, Duration::from_secs(60)),
+            Budget::Join => (30, Duration::from_secs(60)),
+            Budget::Message => (120, Duration::from_secs(60)),
+            // Link previews are cheap to probe, so keep enumeration slow
+            Budget::LinkPreview => (30, Duration::from_secs(60)),
+            Budget::WsFrame => (300, Duration::from_secs(60)),
+        }
+    }
+
+    // Which budget an HTTP request draws from, if any
+    pub fn for_request(method: &Method, path: &str) -> Option<Budget> {
+        let segments: Vec<&str> = path.trim_matches('/').split('/').collect();
+        match (method, segments.as_slice()) {
+            (&Method::POST, ["connections"]) => Some(Budget::CreateConnection),
+            (&Method::POST, ["connections", "link", _, "join"]) => Some(Budget::Join),
+            (&Method::POST, ["connections", _, "join"]) => Some(Budget::Join),
+            (&Method::POST, ["connections", _, "messages"]) => Some(Budget::Message),
+            (&Method::GET, ["connections", "link", _]) => Some(Budget::LinkPreview),
+            _ => None,
+        }
+    }
+}
+
+pub(crate) fn parse_limit(value: &str) -> Option<(u32, Duration)> {
+    let (requests, seconds) = value.split_once('/')?;
+    let requests: u32 = requests.trim().parse().ok()?;
+    let seconds: u64 = seconds.trim().parse().ok()?;
+    if requests == 0 || seconds == 0 {
+        return None;
+    }
+    Some((requests, Duration::from_secs(seconds)))
+}
+
+// One limiter per budget, each keyed by IP and by player id
+pub struct RateLimits {
+    limiters: HashMap<Budget, RateLimiter>,
+    // Peers whose X-Forwarded-For is believed; anyone else could send one
+    trusted_proxies: Vec<IpAddr>,
+}
+
+impl RateLimits {
+    pub fn new(limits: impl IntoIterator<Item = (Budget, (u32, Duration))>) -> Self {
+        let mut configured: HashMap<Budget, (u32, Duration)> = limits.into_iter().collect();
+        let limiters = Budget::ALL
+            .iter()
+            .map(|budget| {
+                let (capacity, per) = configured
+                    .remove(budget)
+                    .unwrap_or_else(|| budget.default_limit());
+                (*budget, RateLimiter::new(capacity, per))
+            })
+            .collect();
+
+        RateLimits {
+            limiters,
+            trusted_proxies: Vec::new(),
+        }
+    }
+
+    pub fn with_trusted_proxies(mut self, trusted_proxies: Vec<IpAddr>) -> Self {
+        self.trusted_proxies = trusted_proxies;
+        self
+    }
+
+    pub fn check(&self, budget: Budget, key: &str) -> Result<(), Duration> {
+        self.limiters[&budget].check(key)
+    }
+
+    // Players are checked by the handlers, which know the id from the path or body
+    pub fn check_player(&self, budget: Budget, player_id: &str) -> Result<(), Duration> {
+        self.check(budget, &format!("player:{}", player_id))
+    }
+
+    // Check the client's IP against the budget the request draws from
+    pub fn check_request(&self, req: &ServiceRequest) -> Result<(), Duration> {
+        let budget = match Budget::for_request(req.method(), req.path()) {
+            Some(budget) => budget,
+            None => return Ok(()),
+        };
+
+        let forwarded_for = req
+            .headers()
+            .get("X-Forwarded-For")
+            .and_then(|value| value.to_str().ok());
+        let ip = self
+            .client_ip(req.peer_addr().map(|addr| addr.ip()), forwarded_for)
+            .map_or_else(|| "unknown".to_string(), |ip| ip.to_string());
+        self.check(budget, &format!("ip:{}", ip))
+    }
+
+    // The peer, unless it's a trusted proxy; then the nearest address in
+    // X-Forwarded-For that isn't one, since entries further left are whatever
+    // the client claimed
+    fn client_ip(&self, peer: Option<IpAddr>, forwarded_for: Option<&str>) -> Option<IpAddr> {
+        let mut ip = peer?;
+        if let Some(forwarded_for) = forwarded_for {
+            for hop in forwarded_for.rsplit(',') {
+                if !self.trusted_proxies.contains(&ip) {
+                    break;
+                }
+                match hop.trim().parse() {
+                    Ok(hop) => ip = hop,
+                    Err(_) => break,
+                }
+            }
+        }
+        Some(ip)
+    }
+
+    // Drop refilled buckets from every budget; returns how many went
+    pub fn prune(&self) -> usize {
+        self.limiters.values().map(RateLimiter::prune).sum()
+    }
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        Self::new(Vec::new())
+    }
+}
+
+pub fn too_many_requests(retry_after: Duration) -> HttpResponse {
+    // Round up so clients never retry before a token is back
+    let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+    HttpResponse::TooManyRequests()
+        .insert_header(("Retry-After", seconds.max(1).to_string()))
+        .json(json!({
+            "error": "Too many requests",
+            "code": "rate_limited",
+            "retry_after": seconds.max(1),
+        }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bucket_allows_burst_then_limits() {
+        let limiter = RateLimiter::new(3, Duration::from_secs(60));
+
+        assert!(limiter.check("1.2.3.4").is_ok());
+        assert!(limiter.check("1.2.3.4").is_ok());
+        assert!(limiter.check("1.2.3.4").is_ok());
+
+        let retry_after = limiter.check("1.2.3.4").unwrap_err();
+        assert!(retry_after > Duration::from_secs(0));
+        assert!(retry_after <= Duration::from_secs(20));
+    }
+
+    #[test]
+    fn test_buckets_are_per_key() {
+        let limiter = RateLimiter::new(1, Duration::from_secs(60));
+
+        assert!(limiter.check("player1").is_ok());
+        assert!(limiter.check("player1").is_err());
+        assert!(limiter.check("player2").is_ok());
+    }
+
+    #[test]
+    fn test_refilled_buckets_are_pruned() {
+        let limiter = RateLimiter::new(2, Duration::from_secs(60));
+        limiter.check("player1").unwrap();
+        limiter.check("player1").unwrap();
+
+        assert_eq!(limiter.prune(), 0);
+
+        let limiter = RateLimiter::new(2, Duration::from_millis(1));
+        limiter.check("player1").unwrap();
+        std::thread::sleep(Duration::from_millis(5));
+        assert_eq!(limiter.prune(), 1);
+    }
+
+    #[test]
+    fn test_forwarded_for_is_only_believed_from_trusted_proxies() {
+        let proxy: IpAddr = "10.0.0.1".parse().unwrap();
+        let client: IpAddr = "203.0.113.7".parse().unwrap();
+        let limits = RateLimits::default().with_trusted_proxies(vec![proxy]);
+
+        // Straight from the client: the header is ignored
+        assert_eq!(limits.client_ip(Some(client), Some("1.1.1.1")), Some(client));
+        // Through the proxy: the address it saw, not what the client claimed
+        assert_eq!(limits.client_ip(Some(proxy), Some("1.1.1.1, 203.0.113.7")), Some(client));
+        assert_eq!(limits.client_ip(Some(proxy), None), Some(proxy));
+    }
+
+    #[test]
+    fn test_requests_map_to_budgets() {
+        assert_eq!(Budget::for_request(&Method::POST, "/connections"), Some(Budget::CreateConnection));
+        assert_eq!(Budget::for_request(&Method::POST, "/connections/link/abc/join"), Some(Budget::Join));
+        assert_eq!(Budget::for_request(&Method::POST, "/connections/abc/messages"), Some(Budget::Message));
+        assert_eq!(Budget::for_request(&Method::GET, "/connections/link/abc"), Some(Budget::LinkPreview));
+        assert_eq!(Budget::for_request(&Method::GET, "/players/abc/notifications"), None);
+    }
+
+    #[test]
+    fn test_budgets_are_separate() {
+        let limits = RateLimits::new(vec![(Budget::Message, (1, Duration::from_secs(60)))]);
+
+        assert!(limits.check(Budget::Message, "ip:1.2.3.4").is_ok());
+        assert!(limits.check(Budget::Message, "ip:1.2.3.4").is_err());
+        assert!(limits.check(Budget::Join, "ip:1.2.3.4").is_ok());
+        assert_eq!(parse_limit("5/10"), Some((5, Duration::from_secs(10))));
+        assert_eq!(parse_limit("5"), None);
+    }
+}
@@ -1,29 +1,57 @@
 use actix_web::{web, App, HttpServer, HttpResponse};
+use actix_web::dev::Service;
 use actix::{Actor, StreamHandler};
 use actix_web_actors::ws;
 use std::collections::HashMap;
-use std::env;
 use actix_files as fs;
 use std::net::TcpListener;
+use std::path::Path;
 use std::sync::RwLock;
 use serde_json::json;
 use actix_cors::Cors;
 use rdkafka::producer::{FutureProducer, FutureRecord};
-use rdkafka::ClientConfig;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+use tracing::Instrument;
+use tracing_actix_web::TracingLogger;
 
-use crate::connection::{Connection, ConnectionStatus, Message};
-use crate::websocket::{RedpandaConfig, ws_route, setup_notification_consumer};
+use crate::admin::{
+    close_connection, expire_connection, get_connection, list_sessions, purge_notifications, search_connections,
+    unauthorized, AdminToken,
+};
+use crate::block::{block_player, list_blocks, set_mute, should_notify, unblock_player, BlockList, MuteList};
+use crate::config::{Config, EventBusKind};
+use crate::connection::{Connection, LinkMode, Message};
+use crate::friend_request::{
+    accept_friend_request, decline_friend_request, list_friend_requests, send_friend_request, FriendRequest,
+};
+use crate::health::{healthz, readyz, Health};
+use crate::label::{label_for, set_label};
+use crate::message::{delete_message, edit_message, list_messages, read_messages, send_message, set_reaction};
+use crate::message_limits::MessageLimits;
+use crate::metrics::{get_metrics, metrics};
+use crate::moderation::FilterChain;
+use crate::notification::{push_notification, Notification};
+use crate::player_data::{delete_player, export_player_data};
+use crate::presence::{get_presence, sweep_presence};
+use crate::profile::{display_name, expand_connection, get_profile, profiles_for, put_profile, ExpandQuery, PlayerProfile};
+use crate::rate_limit::{too_many_requests, Budget, RateLimits};
+use crate::session::{SessionRegistry, REPLAY_TTL};
+use crate::short_code::ShortCodeConfig;
+use crate::shutdown::{flush_producer, going_away_close, going_away_notice, wait_for_publishes, wait_for_signal, PublishGuard};
+use crate::snapshot::Snapshot;
+use crate::socket_limits::SocketLimits;
+use crate::store::{self, StoreError};
+use crate::telemetry::RequestSpan;
+use crate::websocket::{RedpandaConfig, ws_route, setup_notification_consumer, NotificationConsumer};
 use std::time::SystemTime;
 
 #[derive(serde::Deserialize)]
-struct SendMessageRequest {
+struct JoinRequest {
     player_id: String,
-    content: String,
 }
 
 #[derive(serde::Deserialize)]
-struct JoinRequest {
+struct LinkRequest {
     player_id: String,
 }
 
@@ -41,13 +69,133 @@ async fn join_connection(
 pub struct Server {
     pub address: String, 
     connections: web::Data<RwLock<HashMap<String, Connection>>>,
-    notifications: web::Data<RwLock<HashMap<String, Vec<String>>>>, 
+    notifications: web::Data<RwLock<HashMap<String, Vec<Notification>>>>, 
+    messages: web::Data<RwLock<HashMap<String, Vec<Message>>>>,
+    profiles: web::Data<RwLock<HashMap<String, PlayerProfile>>>,
+    labels: web::Data<RwLock<HashMap<String, HashMap<String, String>>>>,
+    friend_requests: web::Data<RwLock<HashMap<String, FriendRequest>>>,
+    blocks: web::Data<RwLock<BlockList>>,
+    mutes: web::Data<RwLock<MuteList>>,
+    sessions: web::Data<SessionRegistry>,
+    health: web::Data<Health>,
+    // Empty allows any origin
+    cors_allowed_origins: Vec<String>,
+    // The notification consumer's settings; None when the event bus is off
+    consumer_config: Option<RedpandaConfig>,
+    // Settings for producers opened by WebSocket sessions
     redpanda_config: web::Data<RedpandaConfig>,
     producer: Option<web::Data<FutureProducer>>,
+    rate_limits: web::Data<RateLimits>,
+    short_codes: web::Data<ShortCodeConfig>,
+    message_limits: web::Data<MessageLimits>,
+    socket_limits: web::Data<SocketLimits>,
+    moderation: web::Data<FilterChain>,
+    // None leaves the /admin API unmounted
+    admin_token: Option<web::Data<AdminToken>>,
+    // How long shutdown may take once a signal arrives
+    shutdown_timeout: Duration,
+    // How long to keep serving after readiness starts failing
+    shutdown_drain: Duration,
+    snapshot_path: Option<String>,
+    // None saves the snapshot only on shutdown
+    snapshot_interval: Option<Duration>,
+}
+
+// Handles to the stores a snapshot copies, so it can be saved from a task
+#[derive(Clone)]
+struct SnapshotStores {
+    connections: web::Data<RwLock<HashMap<String, Connection>>>,
+    notifications: web::Data<RwLock<HashMap<String, Vec<Notification>>>>,
+    messages: web::Data<RwLock<HashMap<String, Vec<Message>>>>,
+    profiles: web::Data<RwLock<HashMap<String, PlayerProfile>>>,
+    labels: web::Data<RwLock<HashMap<String, HashMap<String, String>>>>,
+    friend_requests: web::Data<RwLock<HashMap<String, FriendRequest>>>,
+    blocks: web::Data<RwLock<BlockList>>,
+    mutes: web::Data<RwLock<MuteList>>,
+}
+
+impl SnapshotStores {
+    // Copy the stores out for saving; a poisoned store isn't worth keeping
+    fn capture(&self) -> Result<Snapshot, StoreError> {
+        Ok(Snapshot {
+            connections: store::read(&self.connections, "connections")?
+                .iter()
+                .filter(|(key, conn)| **key == conn.id)
+                .map(|(_, conn)| conn.clone())
+                .collect(),
+            messages: store::read(&self.messages, "messages")?.clone(),
+            notifications: store::read(&self.notifications, "notifications")?.clone(),
+            profiles: store::read(&self.profiles, "profiles")?.clone(),
+            labels: store::read(&self.labels, "labels")?.clone(),
+            friend_requests: store::read(&self.friend_requests, "friend_requests")?.clone(),
+            blocks: store::read(&self.blocks, "blocks")?.clone(),
+            mutes: store::read(&self.mutes, "mutes")?.clone(),
+        })
+    }
+
+    // Copy under the locks, then write the file off the async workers
+    async fn save(&self, path: &str) -> Result<(), String> {
+        let snapshot = self.capture().map_err(|err| err.to_string())?;
+        let path = path.to_string();
+        actix_web::rt::task::spawn_blocking(move || snapshot.save(Path::new(&path)))
+            .await
+            .map_err(|err| err.to_string())?
+    }
+}
+
+// Tries at a free short code before giving up
+const SHORT_CODE_ATTEMPTS: usize = 100;
+
+// How often idle per-player state is swept
+const UPKEEP_INTERVAL: Duration = Duration::from_secs(15);
+
+// Store a connection under its id and, while the link is live, its link_id
+pub(crate) fn store_connection(conn_map: &mut HashMap<String, Connection>, connection: &Connection) {
+    conn_map.insert(connection.id.clone(), connection.clone());
+    if connection.link_active {
+        conn_map.insert(connection.link_id.clone(), connection.clone());
+    }
+}
+
+// Find the connection a live invite link points at; short codes match in any case
+fn find_by_link<'a>(
+    conn_map: &'a HashMap<String, Connection>,
+    link_id: &str,
+) -> Option<&'a Connection> {
+    let live = |key: &str| {
+        conn_map
+            .get(key)
+            .filter(|conn| conn.link_active && conn.link_id == key)
+    };
+    live(link_id).or_else(|| live(&link_id.to_ascii_uppercase()))
+}
+
+// Mint a link id for a connection, checking short codes against live links.
+// None if every short code tried was taken.
+fn new_link_id(
+    conn_map: &HashMap<String, Connection>,
+    short_codes: &ShortCodeConfig,
+    short_link: bool,
+) -> Option<String> {
+    if !short_link {
+        return Some(uuid::Uuid::new_v4().to_string());
+    }
+
+    (0..SHORT_CODE_ATTEMPTS)
+        .map(|_| short_codes.generate())
+        .find(|code| !conn_map.contains_key(code))
+}
+
+fn no_short_code() -> HttpResponse {
+    tracing::error!("Ran out of attempts to mint a free short code");
+    HttpResponse::ServiceUnavailable().json(json!({
+        "error": "No invite code available, try again"
+    }))
 }
 
-// Helper function to send messages to Redpanda
-fn send_to_redpanda(
+// Helper function to send messages to Redpanda; failures are logged in the
+// caller's span so they can be traced back to the request
+pub(crate) fn send_to_redpanda(
     producer: &FutureProducer,
     topic: &str,
     key: &str,
@@ -57,64 +205,133 @@ fn send_to_redpanda(
     let topic = topic.to_owned();
     let key = key.to_owned();
     let payload = payload.to_owned();
+    let guard = PublishGuard::new();
 
     actix_web::rt::spawn(async move {
+        let _guard = guard;
         let record = FutureRecord::to(&topic)
             .key(&key)
             .payload(&payload);
 
-        match producer.send(record, Duration::from_secs(1)).await {
-            Ok(_) => (),
-            Err((err, _)) => eprintln!("Error sending to Redpanda: {:?}", err),
+        let started = Instant::now();
+        let result = producer.send(record, Duration::from_secs(1)).await;
+        metrics().kafka_published(&topic, result.is_ok(), started.elapsed());
+        match result {
+            Ok(_) => tracing::debug!(topic = %topic, key = %key, "Published to Redpanda"),
+            Err((err, _)) => tracing::error!(topic = %topic, key = %key, error = %err, "Failed to publish to Redpanda"),
         }
-    });
+    }.instrument(tracing::Span::current()));
 }
 
 impl Server {
+    // A server on `address`, configured from the environment; a bad setting
+    // stops startup rather than falling back to the defaults
     pub fn new(address: &str) -> Self {
-        // Load Redpanda configuration from environment
-        let bootstrap_servers = env::var("REDPANDA_BOOTSTRAP_SERVERS")
-            .unwrap_or_else(|_| "localhost:9092".to_string());
-        let username = env::var("REDPANDA_USERNAME")
-            .unwrap_or_else(|_| "".to_string());
-        let password = env::var("REDPANDA_PASSWORD")
-            .unwrap_or_else(|_| "".to_string());
-            
-        let redpanda_config = RedpandaConfig {
-            bootstrap_servers: bootstrap_servers.clone(),
-            username: username.clone(),
-            password: password.clone(),
-        };
-        
+        let mut config = Config::from_env().unwrap_or_else(|err| panic!("Invalid configuration: {}", err));
+        config.server.bind_address = address.to_string();
+
+        Server::from_config(&config).unwrap_or_else(|err| panic!("Invalid server configuration: {}", err))
+    }
+
+    pub fn from_config(config: &Config) -> Result<Self, String> {
+        config.validate()?;
+
+        let kafka_enabled = config.event_bus.kind == EventBusKind::Kafka;
+        let backend_config = config.event_bus.backend_config();
+
         // Create Redpanda producer
-        let producer = if !bootstrap_servers.is_empty() {
-            let mut config = ClientConfig::new();
-            config.set("bootstrap.servers", &bootstrap_servers);
-            
-            if !username.is_empty() && !password.is_empty() {
-                config.set("sasl.mechanism", "SCRAM-SHA-256");
-                config.set("security.protocol", "SASL_SSL");
-                config.set("sasl.username", &username);
-                config.set("sasl.password", &password);
-            }
-            
-            match config.create() {
+        let producer = if kafka_enabled {
+            match backend_config.client_config().create() {
                 Ok(producer) => Some(web::Data::new(producer)),
                 Err(err) => {
-                    eprintln!("Failed to create Redpanda producer: {:?}", err);
+                    tracing::error!(error = %err, "Failed to create Redpanda producer");
                     None
                 }
             }
         } else {
             None
         };
-        
-        Server {
-            address: address.to_string(),
-            connections: web::Data::new(RwLock::new(HashMap::new())),
-            notifications: web::Data::new(RwLock::new(HashMap::new())),
-            redpanda_config: web::Data::new(redpanda_config),
+
+        // Pick up where the last run left off
+        let snapshot = match &config.server.snapshot_path {
+            Some(path) => match Snapshot::load(Path::new(path))? {
+                Some(snapshot) => {
+                    tracing::info!(path = %path, connections = snapshot.connections.len(), "Restored snapshot");
+                    snapshot
+                }
+                None => Snapshot::default(),
+            },
+            None => Snapshot::default(),
+        };
+        let mut conn_map = HashMap::new();
+        for connection in &snapshot.connections {
+            store_connection(&mut conn_map, connection);
+        }
+
+        if kafka_enabled && config.event_bus.websocket_uses_backend_credentials() {
+            tracing::info!("WebSocket producers are using the backend Kafka credentials");
+        }
+
+        Ok(Server {
+            address: config.server.bind_address.clone(),
+            connections: web::Data::new(RwLock::new(conn_map)),
+            notifications: web::Data::new(RwLock::new(snapshot.notifications)),
+            messages: web::Data::new(RwLock::new(snapshot.messages)),
+            profiles: web::Data::new(RwLock::new(snapshot.profiles)),
+            labels: web::Data::new(RwLock::new(snapshot.labels)),
+            friend_requests: web::Data::new(RwLock::new(snapshot.friend_requests)),
+            blocks: web::Data::new(RwLock::new(snapshot.blocks)),
+            mutes: web::Data::new(RwLock::new(snapshot.mutes)),
+            sessions: web::Data::new(SessionRegistry::default()),
+            health: web::Data::new(Health::new(kafka_enabled)),
+            cors_allowed_origins: config.server.cors_allowed_origins.clone(),
+            consumer_config: kafka_enabled.then_some(backend_config),
+            redpanda_config: web::Data::new(config.event_bus.websocket_config()),
             producer,
+            rate_limits: web::Data::new(config.rate_limits()?),
+            short_codes: web::Data::new(config.short_codes()?),
+            message_limits: web::Data::new(config.messages.clone()),
+            socket_limits: web::Data::new(config.socket_limits()),
+            moderation: web::Data::new(config.filter_chain()),
+            admin_token: (!config.admin.token.is_empty())
+                .then(|| web::Data::new(AdminToken::new(config.admin.token.clone()))),
+            shutdown_timeout: Duration::from_secs(config.server.shutdown_timeout_secs),
+            shutdown_drain: Duration::from_secs(config.server.shutdown_drain_secs),
+            snapshot_path: config.server.snapshot_path.clone(),
+            snapshot_interval: (config.server.snapshot_interval_secs > 0)
+                .then(|| Duration::from_secs(config.server.snapshot_interval_secs)),
+        })
+    }
+
+    fn snapshot_stores(&self) -> SnapshotStores {
+        SnapshotStores {
+            connections: self.connections.clone(),
+            notifications: self.notifications.clone(),
+            messages: self.messages.clone(),
+            profiles: self.profiles.clone(),
+            labels: self.labels.clone(),
+            friend_requests: self.friend_requests.clone(),
+            blocks: self.blocks.clone(),
+            mutes: self.mutes.clone(),
+        }
+    }
+
+    // Runs once the HTTP server has stopped: get queued events out, commit
+    // what the consumer handled and save the stores, within the shutdown timeout
+    async fn finish_shutdown(&self, consumer: Option<NotificationConsumer>) {
+        let deadline = Instant::now() + self.shutdown_timeout;
+        wait_for_publishes(deadline).await;
+        if let Some(producer) = self.producer.clone() {
+            flush_producer(producer, deadline).await;
+        }
+        if let Some(consumer) = consumer {
+            consumer.shutdown().await;
+        }
+        if let Some(path) = &self.snapshot_path {
+            match self.snapshot_stores().save(path).await {
+                Ok(()) => tracing::info!(path = %path, "Saved snapshot"),
+                Err(err) => tracing::error!(error = %err, "Failed to save snapshot"),
+            }
         }
     }
 
@@ -122,64 +339,294 @@ impl Server {
         let address = self.address.clone(); 
         let connections = self.connections.clone();
         let notifications = self.notifications.clone();
+        let messages = self.messages.clone();
+        let profiles = self.profiles.clone();
+        let labels = self.labels.clone();
+        let friend_requests = self.friend_requests.clone();
+        let blocks = self.blocks.clone();
+        let mutes = self.mutes.clone();
+        let sessions = self.sessions.clone();
         let redpanda_config = self.redpanda_config.clone();
         let producer = self.producer.clone();
+        let rate_limits = self.rate_limits.clone();
+        let short_codes = self.short_codes.clone();
+        let message_limits = self.message_limits.clone();
+        let socket_limits = self.socket_limits.clone();
+        let moderation = self.moderation.clone();
+        let admin_token = self.admin_token.clone();
+        let cors_allowed_origins = self.cors_allowed_origins.clone();
+        
+        let health = self.health.clone();
         
-        setup_notification_consumer(
-            redpanda_config.get_ref().clone(),
-            notifications.clone(),
-        ).await;
+        let consumer = match self.consumer_config.clone() {
+            Some(consumer_config) => {
+                Some(setup_notification_consumer(consumer_config, notifications.clone(), health.clone()).await)
+            }
+            None => None,
+        };
+        let draining = (health.clone(), sessions.clone());
+
+        // Periodic upkeep: presence changes no frame triggered, and state
+        // nobody will ask for again
+        let upkeep = (
+            sessions.clone(),
+            socket_limits.clone(),
+            connections.clone(),
+            blocks.clone(),
+            rate_limits.clone(),
+            moderation.clone(),
+        );
+        actix_web::rt::spawn(async move {
+            let (sessions, socket_limits, connections, blocks, rate_limits, moderation) = upkeep;
+            let mut interval = actix_web::rt::time::interval(UPKEEP_INTERVAL);
+            loop {
+                interval.tick().await;
+                // A poisoned store was already logged; contacts miss this round
+                let _ = sweep_presence(&sessions, &socket_limits, &connections, &blocks);
+                let pruned = sessions.prune(REPLAY_TTL);
+                if pruned > 0 {
+                    tracing::debug!(players = pruned, "Dropped idle replay buffers");
+                }
+                rate_limits.prune();
+                moderation.prune();
+            }
+        });
+
+        // Save the snapshot as we go too, so a crash or kill loses at most
+        // one interval
+        let snapshots = match (self.snapshot_path.clone(), self.snapshot_interval) {
+            (Some(path), Some(every)) => {
+                let stores = self.snapshot_stores();
+                Some(actix_web::rt::spawn(async move {
+                    let mut interval = actix_web::rt::time::interval(every);
+                    // The first tick fires at once; the stores were just loaded
+                    interval.tick().await;
+                    loop {
+                        interval.tick().await;
+                        match stores.save(&path).await {
+                            Ok(()) => tracing::debug!(path = %path, "Saved snapshot"),
+                            Err(err) => tracing::error!(error = %err, "Failed to save snapshot"),
+                        }
+                    }
+                }))
+            }
+            _ => None,
+        };
 
-        HttpServer::new(move || {
-            let cors = Cors::permissive(); 
+        let server = HttpServer::new(move || {
+            let cors = if cors_allowed_origins.is_empty() {
+                Cors::permissive()
+            } else {
+                cors_allowed_origins
+                    .iter()
+                    .fold(Cors::default(), |cors, origin| cors.allowed_origin(origin))
+                    .allow_any_method()
+                    .allow_any_header()
+            };
+            let limits = rate_limits.clone();
             let mut app = App::new()
+                // Reject over-budget requests before they reach a handler
+                .wrap_fn(move |req, srv| {
+                    let allowed = match limits.check_request(&req) {
+                        Ok(()) => Ok(srv.call(req)),
+                        Err(retry_after) => Err(req.into_response(too_many_requests(retry_after))),
+                    };
+                    async move {
+                        match allowed {
+                            Ok(fut) => fut.await.map(|res| res.map_into_left_body()),
+                            Err(res) => Ok(res.map_into_right_body()),
+                        }
+                    }
+                })
+                // Latency by matched route, including rate limited requests
+                .wrap_fn(|req, srv| {
+                    let started = Instant::now();
+                    let method = req.method().to_string();
+                    let fut = srv.call(req);
+                    async move {
+                        let res = fut.await?;
+                        let route = res.request().match_pattern();
+                        metrics().http_request(&method, route.as_deref(), res.status(), started.elapsed());
+                        Ok(res)
+                    }
+                })
                 .wrap(cors)
+                .wrap(TracingLogger::<RequestSpan>::new())
                 .app_data(connections.clone())
                 .app_data(notifications.clone())
-                .app_data(redpanda_config.clone());
+                .app_data(messages.clone())
+                .app_data(profiles.clone())
+                .app_data(labels.clone())
+                .app_data(friend_requests.clone())
+                .app_data(blocks.clone())
+                .app_data(mutes.clone())
+                .app_data(sessions.clone())
+                .app_data(health.clone())
+                .app_data(redpanda_config.clone())
+                .app_data(rate_limits.clone())
+                .app_data(short_codes.clone())
+                .app_data(message_limits.clone())
+                .app_data(socket_limits.clone())
+                .app_data(moderation.clone());
                 
             // Add producer if available
             if let Some(prod) = producer.clone() {
                 app = app.app_data(prod.clone());
             }
-                
+
+            // Support tooling, only mounted when an admin token is configured
+            if let Some(token) = admin_token.clone() {
+                app = app.app_data(token.clone());
+                app = app.service(
+                    web::scope("/admin")
+                        .wrap_fn(move |req, srv| {
+                            let authorized = if token.authorizes(req.request()) {
+                                Ok(srv.call(req))
+                            } else {
+                                tracing::warn!(path = %req.path(), "Rejected admin request");
+                                Err(req.into_response(unauthorized()))
+                            };
+                            async move {
+                                match authorized {
+                                    Ok(fut) => fut.await.map(|res| res.map_into_left_body()),
+                                    Err(res) => Ok(res.map_into_right_body()),
+                                }
+                            }
+                        })
+                        .route("/connections", web::get().to(search_connections))
+                        .route("/connections/{id}", web::get().to(get_connection))
+                        .route("/connections/{id}/expire", web::post().to(expire_connection))
+                        .route("/connections/{id}/close", web::post().to(close_connection))
+                        .route("/players/{player_id}/notifications", web::delete().to(purge_notifications))
+                        .route("/sessions", web::get().to(list_sessions)),
+                );
+            }
+
             app.route("/connections", web::post().to(create_connection))
                 .route("/connections/{id}/join", web::post().to(join_connection))
-                .route(
-                    "/connections/link/{link_id}/join", 
-                    web::post().to(|link_id, req, connections, notifications, producer| {
-                        join_connection_by_link(link_id, req, connections, notifications, producer)
-                    })
-                )
+                .route("/connections/{id}/link/rotate", web::post().to(rotate_link))
+                .route("/connections/{id}/link", web::delete().to(revoke_link))
+                .route("/connections/{id}/label", web::put().to(set_label))
+                .route("/connections/{id}/mute", web::put().to(set_mute))
+                .route("/connections/link/{link_id}", web::get().to(preview_link))
+                .route("/connections/link/{link_id}/join", web::post().to(join_connection_by_link))
+                .route("/players/{player_id}/connections", web::get().to(list_player_connections))
+                // Export and deletion requests; these check the admin token themselves
+                .route("/players/{player_id}/export", web::get().to(export_player_data))
+                .route("/players/{player_id}", web::delete().to(delete_player))
+                .route("/players/{player_id}/friend-requests", web::post().to(send_friend_request))
+                .route("/players/{player_id}/friend-requests", web::get().to(list_friend_requests))
+                .route("/friend-requests/{request_id}/accept", web::post().to(accept_friend_request))
+                .route("/friend-requests/{request_id}/decline", web::post().to(decline_friend_request))
+                .route("/players/{player_id}/blocks", web::post().to(block_player))
+                .route("/players/{player_id}/blocks", web::get().to(list_blocks))
+                .route("/players/{player_id}/blocks/{blocked_id}", web::delete().to(unblock_player))
+                .route("/players/{player_id}/presence", web::get().to(get_presence))
+                .route("/players/{player_id}/profile", web::get().to(get_profile))
+                .route("/players/{player_id}/profile", web::put().to(put_profile))
                 .route("/players/{player_id}/notifications", web::get().to(get_player_notifications))        
                 .route("/players/{player_id}/notifications/ack", web::post().to(acknowledge_notifications))
                 .route("/connections/{id}/messages", web::post().to(send_message))
+                .route("/connections/{id}/messages", web::get().to(list_messages))
+                .route("/connections/{id}/messages/read", web::post().to(read_messages))
+                .route("/connections/{id}/messages/{message_id}", web::patch().to(edit_message))
+                .route("/connections/{id}/messages/{message_id}", web::delete().to(delete_message))
+                .route("/connections/{id}/messages/{message_id}/reactions", web::put().to(set_reaction))
                 .route("/ws", web::get().to(ws_route))
+                .route("/metrics", web::get().to(get_metrics))
+                .route("/healthz", web::get().to(healthz))
+                .route("/readyz", web::get().to(readyz))
                 .service(fs::Files::new("/", "./static")
                 .index_file("index.html"))
         })
+        // Signals are handled below, so sockets hear about the shutdown first
+        .disable_signals()
+        .shutdown_timeout(self.shutdown_timeout.as_secs())
         .bind(address)?
-        .run()
-        .await
+        .run();
+
+        let handle = server.handle();
+        let drain = self.shutdown_drain;
+        actix_web::rt::spawn(async move {
+            let (health, sessions) = draining;
+            wait_for_signal().await;
+            tracing::info!("Shutting down");
+            health.begin_shutdown();
+            // Keep serving until load balancers see readiness fail, so new
+            // requests and reconnecting sockets go to other instances
+            if !drain.is_zero() {
+                tracing::info!(drain_secs = drain.as_secs(), "Draining before closing sessions");
+                actix_web::rt::time::sleep(drain).await;
+            }
+            let closed = sessions.close_all(going_away_notice, going_away_close());
+            tracing::info!(sessions = closed, "Closed WebSocket sessions");
+            // Stops accepting connections and waits for requests in flight
+            handle.stop(true).await;
+        });
+
+        server.await?;
+        // The shutdown save is the last word
+        if let Some(snapshots) = snapshots {
+            snapshots.abort();
+        }
+        self.finish_shutdown(consumer).await;
+        Ok(())
     }
 }
 
 async fn create_connection(
-    player_id: web::Json<serde_json::Value>,
+    body: web::Json<serde_json::Value>,
+    query: web::Query<ExpandQuery>,
     connections: web::Data<RwLock<HashMap<String, Connection>>>,
+    profiles: web::Data<RwLock<HashMap<String, PlayerProfile>>>,
+    short_codes: web::Data<ShortCodeConfig>,
+    rate_limits: web::Data<RateLimits>,
     producer: Option<web::Data<FutureProducer>>,
-) -> HttpResponse {
-    let player_id = player_id.get("player_id")
+) -> Result<HttpResponse, StoreError> {
+    let player_id = body.get("player_id")
         .and_then(|id| id.as_str())
         .unwrap_or("")
         .to_string();
+    if let Err(retry_after) = rate_limits.check_player(Budget::CreateConnection, &player_id) {
+        return Ok(too_many_requests(retry_after));
+    }
         
-    let connection = Connection::new(player_id.clone());
+    let mut connection = Connection::new(player_id.clone());
+    
+    // Group connections can allow more than two players
+    if let Some(max_players) = body.get("max_players").and_then(|m| m.as_u64()) {
+        if max_players < 2 {
+            return Ok(HttpResponse::BadRequest().json(json!({
+                "error": "max_players must be at least 2"
+            })));
+        }
+        connection.max_players = max_players as usize;
+    }
+    
+    if let Some(link_mode) = body.get("link_mode") {
+        match serde_json::from_value::<LinkMode>(link_mode.clone()) {
+            Ok(link_mode) => connection.link_mode = link_mode,
+            Err(_) => {
+                return Ok(HttpResponse::BadRequest().json(json!({
+                    "error": "Invalid link_mode"
+                })));
+            }
+        }
+    }
+    
+    let mut conn_map = store::write(&connections, "connections")?;
+    
+    // Short codes replace the UUID link id when requested
+    if body.get("short_code").and_then(|s| s.as_bool()).unwrap_or(false) {
+        connection.short_link = true;
+        connection.link_id = match new_link_id(&conn_map, &short_codes, true) {
+            Some(link_id) => link_id,
+            None => return Ok(no_short_code()),
+        };
+    }
     
     // Store both id and link_id mappings
-    let mut conn_map = connections.write().unwrap();
-    conn_map.insert(connection.id.clone(), connection.clone());
-    conn_map.insert(connection.link_id.clone(), connection.clone());
+    store_connection(&mut conn_map, &connection);
     
     // Publish to Redpanda if producer is available
     if let Some(producer) = producer {
@@ -189,7 +636,7 @@ async fn create_connection(
             "player_id": player_id,
             "timestamp": SystemTime::now()
                 .duration_since(SystemTime::UNIX_EPOCH)
-                .unwrap()
+                .unwrap_or_default()
                 .as_secs(),
         });
         
@@ -201,60 +648,99 @@ async fn create_connection(
         );
     }
     
-    HttpResponse::Ok().json(connection)
+    if query.profiles() {
+        let profiles = store::read(&profiles, "profiles")?;
+        return Ok(HttpResponse::Ok().json(expand_connection(&connection, &profiles)));
+    }
+    
+    Ok(HttpResponse::Ok().json(connection))
 }
 
 async fn join_connection_by_link(
     link_id: web::Path<String>,
     join_req: web::Json<JoinRequest>,
+    query: web::Query<ExpandQuery>,
     connections: web::Data<RwLock<HashMap<String, Connection>>>,
-    notifications: web::Data<RwLock<HashMap<String, Vec<String>>>>,
+    notifications: web::Data<RwLock<HashMap<String, Vec<Notification>>>>,
+    profiles: web::Data<RwLock<HashMap<String, PlayerProfile>>>,
+    blocks: web::Data<RwLock<BlockList>>,
+    mutes: web::Data<RwLock<MuteList>>,
+    rate_limits: web::Data<RateLimits>,
     producer: Option<web::Data<FutureProducer>>,
-) -> HttpResponse {
+) -> Result<HttpResponse, StoreError> {
     let link_id = link_id.into_inner();
+    if let Err(retry_after) = rate_limits.check_player(Budget::Join, &join_req.player_id) {
+        return Ok(too_many_requests(retry_after));
+    }
     
-    // First get the connection and validate
-    let connection = {
-        let conn_map = connections.read().unwrap();
-        if let Some(conn) = conn_map.get(&link_id) {
-            if conn.players.len() != 1 {
-                return HttpResponse::BadRequest().json(json!({
-                    "error": "Connection already has maximum players"
-                }));
-            }
-            
-            if conn.players.contains(&join_req.player_id) {
-                return HttpResponse::BadRequest().json(json!({
-                    "error": "Player already in connection"
-                }));
-            }
-            conn.clone()
-        } else {
-            return HttpResponse::NotFound().json(json!({
+    // Validate and update under one write lock, so two joins at once can't
+    // both pass the checks
+    let (connection, updated_connection) = {
+        let mut conn_map = store::write(&connections, "connections")?;
+        // Only the current, unrevoked link may be used to join
+        let Some(conn) = find_by_link(&conn_map, &link_id) else {
+            return Ok(HttpResponse::NotFound().json(json!({
                 "error": "Connection not found"
-            }));
+            })));
+        };
+        if conn.is_full() {
+            return Ok(HttpResponse::BadRequest().json(json!({
+                "error": "Connection already has maximum players"
+            })));
+        }
+        
+        if conn.players.contains(&join_req.player_id) {
+            return Ok(HttpResponse::BadRequest().json(json!({
+                "error": "Player already in connection"
+            })));
+        }
+        
+        // Players can't join anyone they blocked or who blocked them
+        let blocks = store::read(&blocks, "blocks")?;
+        if conn.players.iter().any(|player| blocks.between(player, &join_req.player_id)) {
+            return Ok(HttpResponse::Forbidden().json(json!({
+                "error": "Cannot join this connection"
+            })));
+        }
+        let connection = conn.clone();
+        
+        // Update connection with new player
+        let mut updated_connection = connection.clone();
+        updated_connection.players.push(join_req.player_id.clone());
+        
+        // Single-use links stop working once someone has joined through them
+        if updated_connection.link_mode == LinkMode::SingleUse {
+            updated_connection.revoke_link();
+        }
+        
+        // Update both mappings
+        if !updated_connection.link_active {
+            conn_map.remove(&updated_connection.link_id);
         }
+        store_connection(&mut conn_map, &updated_connection);
+        (connection, updated_connection)
     };
     
     // Store notification for first player
-    {
-        let first_player = &connection.players[0];
-        let mut notifications = notifications.write().unwrap();
-        notifications
-            .entry(first_player.clone())
-            .or_insert_with(Vec::new)
-            .push(format!("Player {} joined your connection", join_req.player_id));
-    }
-    
-    // Update connection with new player
-    let mut updated_connection = connection.clone();
-    updated_connection.players.push(join_req.player_id.clone());
-    
-    // Update both mappings
-    {
-        let mut conn_map = connections.write().unwrap();
-        conn_map.insert(connection.id.clone(), updated_connection.clone());
-        conn_map.insert(link_id, updated_connection.clone());
+    let first_player = &connection.players[0];
+    if should_notify(
+        &*store::read(&blocks, "blocks")?,
+        &*store::read(&mutes, "mutes")?,
+        first_player,
+        &join_req.player_id,
+        &connection.id,
+    ) {
+        let name = display_name(&*store::read(&profiles, "profiles")?, &join_req.player_id);
+        let mut notifications = store::write(&notifications, "notifications")?;
+        push_notification(
+            &mut notifications,
+            first_player,
+            Notification::about(
+                format!("Player {} joined your connection", name),
+                &join_req.player_id,
+                &connection.id,
+            ),
+        );
     }
     
     // Publish to Redpanda if producer is available
@@ -265,7 +751,141 @@ async fn join_connection_by_link(
             "player_id": join_req.player_id,
             "timestamp": SystemTime::now()
                 .duration_since(SystemTime::UNIX_EPOCH)
-                .unwrap()
+                .unwrap_or_default()
+                .as_secs(),
+        });
+        
+        send_to_redpanda(
+            producer.get_ref(),
+            "connection-events",
+            &connection.id,
+            &event.to_string(),
+        );
+    }
+    
+    if query.profiles() {
+        let profiles = store::read(&profiles, "profiles")?;
+        return Ok(HttpResponse::Ok().json(expand_connection(&updated_connection, &profiles)));
+    }
+    
+    Ok(HttpResponse::Ok().json(updated_connection))
+}
+
+// Rate limited per IP by the LinkPreview budget to make enumeration slow
+async fn preview_link(
+    link_id: web::Path<String>,
+    connections: web::Data<RwLock<HashMap<String, Connection>>>,
+    profiles: web::Data<RwLock<HashMap<String, PlayerProfile>>>,
+) -> Result<HttpResponse, StoreError> {
+    let link_id = link_id.into_inner();
+    let conn_map = store::read(&connections, "connections")?;
+    
+    // Only the live link resolves, never the connection id itself
+    match find_by_link(&conn_map, &link_id) {
+        Some(conn) => {
+            let creator = conn.players.first().cloned().unwrap_or_default();
+            let creator_name = display_name(&*store::read(&profiles, "profiles")?, &creator);
+            Ok(HttpResponse::Ok().json(conn.preview(creator_name)))
+        }
+        None => Ok(HttpResponse::NotFound().json(json!({
+            "error": "Connection not found"
+        }))),
+    }
+}
+
+// Look up a connection the player created, for link management
+fn creator_connection(
+    conn_map: &HashMap<String, Connection>,
+    connection_id: &str,
+    player_id: &str,
+) -> Result<Connection, HttpResponse> {
+    match conn_map.get(connection_id) {
+        Some(conn) if conn.id != connection_id => Err(HttpResponse::NotFound().json(json!({
+            "error": "Connection not found"
+        }))),
+        Some(conn) if conn.players.first().map(String::as_str) != Some(player_id) => {
+            Err(HttpResponse::Forbidden().json(json!({
+                "error": "Only the connection creator can manage its link"
+            })))
+        }
+        Some(conn) => Ok(conn.clone()),
+        None => Err(HttpResponse::NotFound().json(json!({
+            "error": "Connection not found"
+        }))),
+    }
+}
+
+async fn rotate_link(
+    connection_id: web::Path<String>,
+    link_req: web::Json<LinkRequest>,
+    connections: web::Data<RwLock<HashMap<String, Connection>>>,
+    short_codes: web::Data<ShortCodeConfig>,
+    producer: Option<web::Data<FutureProducer>>,
+) -> Result<HttpResponse, StoreError> {
+    let connection_id = connection_id.into_inner();
+    let mut conn_map = store::write(&connections, "connections")?;
+    
+    let mut connection = match creator_connection(&conn_map, &connection_id, &link_req.player_id) {
+        Ok(conn) => conn,
+        Err(resp) => return Ok(resp),
+    };
+    
+    let link_id = match new_link_id(&conn_map, &short_codes, connection.short_link) {
+        Some(link_id) => link_id,
+        None => return Ok(no_short_code()),
+    };
+    let old_link_id = connection.rotate_link(link_id);
+    conn_map.remove(&old_link_id);
+    store_connection(&mut conn_map, &connection);
+    
+    if let Some(producer) = producer {
+        let event = json!({
+            "event": "link_rotated",
+            "connection_id": connection.id,
+            "player_id": link_req.player_id,
+            "timestamp": SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+        });
+        
+        send_to_redpanda(
+            producer.get_ref(),
+            "connection-events",
+            &connection.id,
+            &event.to_string(),
+        );
+    }
+    
+    Ok(HttpResponse::Ok().json(connection))
+}
+
+async fn revoke_link(
+    connection_id: web::Path<String>,
+    link_req: web::Json<LinkRequest>,
+    connections: web::Data<RwLock<HashMap<String, Connection>>>,
+    producer: Option<web::Data<FutureProducer>>,
+) -> Result<HttpResponse, StoreError> {
+    let connection_id = connection_id.into_inner();
+    let mut conn_map = store::write(&connections, "connections")?;
+    
+    let mut connection = match creator_connection(&conn_map, &connection_id, &link_req.player_id) {
+        Ok(conn) => conn,
+        Err(resp) => return Ok(resp),
+    };
+    
+    connection.revoke_link();
+    conn_map.remove(&connection.link_id);
+    store_connection(&mut conn_map, &connection);
+    
+    if let Some(producer) = producer {
+        let event = json!({
+            "event": "link_revoked",
+            "connection_id": connection.id,
+            "player_id": link_req.player_id,
+            "timestamp": SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
                 .as_secs(),
         });
         
@@ -277,29 +897,88 @@ async fn join_connection_by_link(
         );
     }
     
-    HttpResponse::Ok().json(updated_connection)
+    Ok(HttpResponse::Ok().json(connection))
+}
+
+// Every connection the player is in, with their private label for each
+async fn list_player_connections(
+    player_id: web::Path<String>,
+    query: web::Query<ExpandQuery>,
+    connections: web::Data<RwLock<HashMap<String, Connection>>>,
+    profiles: web::Data<RwLock<HashMap<String, PlayerProfile>>>,
+    labels: web::Data<RwLock<HashMap<String, HashMap<String, String>>>>,
+) -> Result<HttpResponse, StoreError> {
+    let player_id = player_id.into_inner();
+    let conn_map = store::read(&connections, "connections")?;
+    let profiles = store::read(&profiles, "profiles")?;
+    let labels = store::read(&labels, "labels")?;
+    
+    // Skip the link_id entries so each connection is listed once
+    let mut listing: Vec<serde_json::Value> = conn_map
+        .iter()
+        .filter(|(key, conn)| **key == conn.id && conn.players.contains(&player_id))
+        .map(|(_, conn)| {
+            let mut value = if query.profiles() {
+                expand_connection(conn, &profiles)
+            } else {
+                json!(conn)
+            };
+            value["label"] = json!(label_for(&labels, &player_id, &conn.id));
+            value
+        })
+        .collect();
+    listing.sort_by_key(|value| value["created_at"].as_i64());
+    
+    Ok(HttpResponse::Ok().json(listing))
 }
 
 async fn get_player_notifications(
     player_id: web::Path<String>,
-    notifications: web::Data<RwLock<HashMap<String, Vec<String>>>>
-) -> HttpResponse {
+    query: web::Query<ExpandQuery>,
+    notifications: web::Data<RwLock<HashMap<String, Vec<Notification>>>>,
+    profiles: web::Data<RwLock<HashMap<String, PlayerProfile>>>,
+    labels: web::Data<RwLock<HashMap<String, HashMap<String, String>>>>,
+) -> Result<HttpResponse, StoreError> {
     let player_id = player_id.into_inner();
-    let notifications = notifications.read().unwrap();
+    let notifications = store::read(&notifications, "notifications")?;
     if let Some(player_notifications) = notifications.get(&player_id) {
-        HttpResponse::Ok().json(player_notifications)
+        // Plain strings by default; full notifications when anything is expanded
+        if query.profiles() || query.labels() {
+            let profiles = store::read(&profiles, "profiles")?;
+            let labels = store::read(&labels, "labels")?;
+            let expanded: Vec<serde_json::Value> = player_notifications
+                .iter()
+                .map(|notification| {
+                    let mut value = json!(notification);
+                    if query.profiles() {
+                        value["profiles"] = json!(profiles_for(&profiles, &notification.player_id));
+                    }
+                    if query.labels() {
+                        value["label"] = json!(notification
+                            .connection_id
+                            .as_deref()
+                            .and_then(|id| label_for(&labels, &player_id, id)));
+                    }
+                    value
+                })
+                .collect();
+            return Ok(HttpResponse::Ok().json(expanded));
+        }
+        
+        let contents: Vec<&String> = player_notifications.iter().map(|n| &n.content).collect();
+        Ok(HttpResponse::Ok().json(contents))
     } else {
-        HttpResponse::Ok().json(Vec::<String>::new())  // Return empty array instead of 404
+        Ok(HttpResponse::Ok().json(Vec::<String>::new()))  // Return empty array instead of 404
     }
 }
 
 async fn acknowledge_notifications(
     player_id: web::Path<String>,
-    notifications: web::Data<RwLock<HashMap<String, Vec<String>>>>,
+    notifications: web::Data<RwLock<HashMap<String, Vec<Notification>>>>,
     producer: Option<web::Data<FutureProducer>>,
-) -> HttpResponse {
+) -> Result<HttpResponse, StoreError> {
     let player_id = player_id.into_inner();
-    let mut notifications = notifications.write().unwrap();
+    let mut notifications = store::write(&notifications, "notifications")?;
     
     // Check if there were notifications before removing
     let had_notifications = notifications.get(&player_id).map_or(false, |n| !n.is_empty());
@@ -314,7 +993,7 @@ async fn acknowledge_notifications(
                 "player_id": player_id,
                 "timestamp": SystemTime::now()
                     .duration_since(SystemTime::UNIX_EPOCH)
-                    .unwrap()
+                    .unwrap_or_default()
                     .as_secs(),
             });
             
@@ -327,79 +1006,13 @@ async fn acknowledge_notifications(
         }
     }
     
-    HttpResponse::Ok().json(json!({"status": "ok"}))
-}
-
-async fn send_message(
-    connection_id: web::Path<String>,
-    message_req: web::Json<SendMessageRequest>,
-    connections: web::Data<RwLock<HashMap<String, Connection>>>,
-    notifications: web::Data<RwLock<HashMap<String, Vec<String>>>>,
-    producer: Option<web::Data<FutureProducer>>,
-) -> HttpResponse {
-    let conn_map = connections.read().unwrap();
-    let connection_id = connection_id.into_inner();
-    
-    if let Some(connection) = conn_map.get(&connection_id) {
-        // Verify sender is in the connection
-        if !connection.players.contains(&message_req.player_id) {
-            return HttpResponse::BadRequest().json(json!({
-                "error": "Player not in this connection"
-            }));
-        }
-        
-        // Create the message
-        let message = Message {
-            id: uuid::Uuid::new_v4().to_string(),
-            from: message_req.player_id.clone(),
-            content: message_req.content.clone(),
-            timestamp: SystemTime::now()
-                .duration_since(SystemTime::UNIX_EPOCH)
-                .unwrap()
-                .as_secs() as i64,
-        };
-        
-        // Notify other players
-        let mut notifications = notifications.write().unwrap();
-        for player in &connection.players {
-            if player != &message_req.player_id {
-                notifications
-                    .entry(player.clone())
-                    .or_insert_with(Vec::new)
-                    .push(format!("Message from {}: {}", message_req.player_id, message_req.content));
-            }
-        }
-        
-        // Publish to Redpanda if producer is available
-        if let Some(producer) = producer {
-            let event = json!({
-                "event": "message_sent",
-                "connection_id": connection_id,
-                "message_id": message.id,
-                "player_id": message_req.player_id,
-                "content": message_req.content,
-                "timestamp": message.timestamp,
-            });
-            
-            send_to_redpanda(
-                producer.get_ref(),
-                "connection-messages",
-                &connection_id,
-                &event.to_string(),
-            );
-        }
-        
-        HttpResponse::Ok().json(message)
-    } else {
-        HttpResponse::NotFound().json(json!({
-            "error": "Connection not found"
-        }))
-    }
+    Ok(HttpResponse::Ok().json(json!({"status": "ok"})))
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::connection::ConnectionStatus;
     use std::time::Duration;
         
     fn spawn_app() -> String {
@@ -417,6 +1030,28 @@ mod tests {
         server_address
     }
 
+    // A server with no event bus, returned so tests can reach its state
+    fn spawn_app_with_config(mut config: Config) -> (String, Server) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        
+        config.server.bind_address = address.clone();
+        let server = Server::from_config(&config).unwrap();
+        let running = server.clone();
+        
+        actix_web::rt::spawn(async move {
+            running.run().await.unwrap();
+        });
+        
+        (address, server)
+    }
+
+    fn spawn_app_without_event_bus() -> (String, Server) {
+        let mut config = Config::default();
+        config.event_bus.kind = EventBusKind::None;
+        spawn_app_with_config(config)
+    }
+
     #[actix_web::test]
     async fn test_second_player_join_notifies_first_player() {
         // Arrange
@@ -640,33 +1275,1241 @@ mod tests {
         assert!(notifications.iter().any(|n| n.contains("Hello player2!")));
     }
 
-    #[test]
-    fn test_server_new() {
-        let server = Server::new("127.0.0.1:8080");
-        assert_eq!(server.address, "127.0.0.1:8080");
-    }
-
     #[actix_web::test]
-    async fn test_notifications_are_cleared_after_acknowledgment() {
+    async fn test_rotated_and_revoked_links_cannot_be_joined() {
         // Arrange
         let address = spawn_app();
         let client = reqwest::Client::new();
         
-        // Create connection with player1
-        let create_resp = client
+        let connection: Connection = client
             .post(&format!("http://{}/connections", address))
             .json(&json!({
                 "player_id": "player1"
             }))
             .send()
             .await
+            .unwrap()
+            .json()
+            .await
             .unwrap();
         
-        let connection: Connection = create_resp.json().await.unwrap();
-        
-        // Join with player2 and send a message to generate notifications
-        client
-            .post(&format!("http://{}/connections/link/{}/join", address, connection.link_id))
+        // Act - Only the creator may rotate the link
+        let forbidden_resp = client
+            .post(&format!("http://{}/connections/{}/link/rotate", address, connection.id))
+            .json(&json!({
+                "player_id": "player2"
+            }))
+            .send()
+            .await
+            .unwrap();
+        
+        let rotate_resp = client
+            .post(&format!("http://{}/connections/{}/link/rotate", address, connection.id))
+            .json(&json!({
+                "player_id": "player1"
+            }))
+            .send()
+            .await
+            .unwrap();
+        
+        // Assert
+        assert_eq!(forbidden_resp.status(), 403);
+        assert_eq!(rotate_resp.status(), 200);
+        let rotated: Connection = rotate_resp.json().await.unwrap();
+        assert_ne!(rotated.link_id, connection.link_id);
+        
+        let old_link_resp = client
+            .post(&format!("http://{}/connections/link/{}/join", address, connection.link_id))
+            .json(&json!({
+                "player_id": "player2"
+            }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(old_link_resp.status(), 404);
+        
+        // Act - Revoke the new link as well
+        let revoke_resp = client
+            .delete(&format!("http://{}/connections/{}/link", address, connection.id))
+            .json(&json!({
+                "player_id": "player1"
+            }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(revoke_resp.status(), 200);
+        
+        let revoked_link_resp = client
+            .post(&format!("http://{}/connections/link/{}/join", address, rotated.link_id))
+            .json(&json!({
+                "player_id": "player2"
+            }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(revoked_link_resp.status(), 404);
+    }
+
+    #[actix_web::test]
+    async fn test_multi_use_link_fills_group_connection() {
+        // Arrange
+        let address = spawn_app();
+        let client = reqwest::Client::new();
+        
+        let connection: Connection = client
+            .post(&format!("http://{}/connections", address))
+            .json(&json!({
+                "player_id": "player1",
+                "max_players": 3,
+                "link_mode": "MultiUse"
+            }))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        
+        // Act - Three players try to join through the same link
+        let mut statuses = Vec::new();
+        for player in ["player2", "player3", "player4"] {
+            let resp = client
+                .post(&format!("http://{}/connections/link/{}/join", address, connection.link_id))
+                .json(&json!({
+                    "player_id": player
+                }))
+                .send()
+                .await
+                .unwrap();
+            statuses.push(resp.status().as_u16());
+        }
+        
+        // Assert - The link works until the group is full
+        assert_eq!(statuses, vec![200, 200, 400]);
+    }
+
+    #[actix_web::test]
+    async fn test_link_preview_hides_connection_id() {
+        // Arrange
+        let address = spawn_app();
+        let client = reqwest::Client::new();
+        
+        let connection: Connection = client
+            .post(&format!("http://{}/connections", address))
+            .json(&json!({
+                "player_id": "player1"
+            }))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        
+        // Act
+        let preview_resp = client
+            .get(&format!("http://{}/connections/link/{}", address, connection.link_id))
+            .send()
+            .await
+            .unwrap();
+        let by_id_resp = client
+            .get(&format!("http://{}/connections/link/{}", address, connection.id))
+            .send()
+            .await
+            .unwrap();
+        
+        // Assert
+        assert_eq!(preview_resp.status(), 200);
+        let body = preview_resp.text().await.unwrap();
+        assert!(!body.contains(&connection.id));
+        let preview: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(preview["creator_display_name"], "player1");
+        assert_eq!(preview["player_count"], 1);
+        assert_eq!(preview["max_players"], 2);
+        assert_eq!(by_id_resp.status(), 404);
+    }
+
+    #[actix_web::test]
+    async fn test_join_connection_with_short_code() {
+        // Arrange
+        let address = spawn_app();
+        let client = reqwest::Client::new();
+        
+        let connection: Connection = client
+            .post(&format!("http://{}/connections", address))
+            .json(&json!({
+                "player_id": "player1",
+                "short_code": true
+            }))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        
+        assert!(connection.short_link);
+        assert_eq!(connection.link_id.len(), 6);
+        
+        // Act - Codes read out loud may be typed in lower case
+        let join_resp = client
+            .post(&format!(
+                "http://{}/connections/link/{}/join",
+                address,
+                connection.link_id.to_lowercase()
+            ))
+            .json(&json!({
+                "player_id": "player2"
+            }))
+            .send()
+            .await
+            .unwrap();
+        
+        // Assert
+        assert_eq!(join_resp.status(), 200);
+        let joined: Connection = join_resp.json().await.unwrap();
+        assert_eq!(joined.id, connection.id);
+        assert_eq!(joined.players.len(), 2);
+    }
+
+    #[actix_web::test]
+    async fn test_profiles_are_used_in_notifications() {
+        // Arrange
+        let address = spawn_app();
+        let client = reqwest::Client::new();
+        
+        let put_resp = client
+            .put(&format!("http://{}/players/player2/profile", address))
+            .json(&json!({
+                "display_name": "Alice",
+                "avatar_url": "https://example.com/alice.png",
+                "locale": "en-GB"
+            }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(put_resp.status(), 200);
+        
+        let connection: Connection = client
+            .post(&format!("http://{}/connections", address))
+            .json(&json!({
+                "player_id": "player1"
+            }))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        
+        // Act
+        let join_resp = client
+            .post(&format!("http://{}/connections/link/{}/join?expand=profiles", address, connection.link_id))
+            .json(&json!({
+                "player_id": "player2"
+            }))
+            .send()
+            .await
+            .unwrap();
+        
+        // Assert
+        let joined: serde_json::Value = join_resp.json().await.unwrap();
+        assert_eq!(joined["profiles"]["player2"]["display_name"], "Alice");
+        assert!(joined["profiles"].get("player1").is_none());
+        
+        let notifications: Vec<serde_json::Value> = client
+            .get(&format!("http://{}/players/player1/notifications?expand=profiles", address))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(notifications.len(), 1);
+        assert_eq!(notifications[0]["content"], "Player Alice joined your connection");
+        assert_eq!(notifications[0]["profiles"]["player2"]["locale"], "en-GB");
+        
+        let profile: serde_json::Value = client
+            .get(&format!("http://{}/players/player2/profile", address))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(profile["avatar_url"], "https://example.com/alice.png");
+    }
+
+    #[actix_web::test]
+    async fn test_invalid_profile_is_rejected() {
+        // Arrange
+        let address = spawn_app();
+        let client = reqwest::Client::new();
+        
+        // Act
+        let resp = client
+            .put(&format!("http://{}/players/player1/profile", address))
+            .json(&json!({
+                "display_name": "   ",
+                "avatar_url": "javascript:alert(1)"
+            }))
+            .send()
+            .await
+            .unwrap();
+        let missing_resp = client
+            .get(&format!("http://{}/players/player1/profile", address))
+            .send()
+            .await
+            .unwrap();
+        
+        // Assert
+        assert_eq!(resp.status(), 400);
+        assert_eq!(missing_resp.status(), 404);
+    }
+
+    #[actix_web::test]
+    async fn test_connection_labels_are_private() {
+        // Arrange
+        let address = spawn_app();
+        let client = reqwest::Client::new();
+        
+        let connection: Connection = client
+            .post(&format!("http://{}/connections", address))
+            .json(&json!({
+                "player_id": "player1"
+            }))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        
+        client
+            .post(&format!("http://{}/connections/link/{}/join", address, connection.link_id))
+            .json(&json!({
+                "player_id": "player2"
+            }))
+            .send()
+            .await
+            .unwrap();
+        
+        // Act
+        let label_resp = client
+            .put(&format!("http://{}/connections/{}/label", address, connection.id))
+            .json(&json!({
+                "player_id": "player1",
+                "label": "My cousin"
+            }))
+            .send()
+            .await
+            .unwrap();
+        let outsider_resp = client
+            .put(&format!("http://{}/connections/{}/label", address, connection.id))
+            .json(&json!({
+                "player_id": "player3",
+                "label": "Strangers"
+            }))
+            .send()
+            .await
+            .unwrap();
+        
+        // Assert
+        assert_eq!(label_resp.status(), 200);
+        assert_eq!(outsider_resp.status(), 400);
+        
+        let p1_listing: Vec<serde_json::Value> = client
+            .get(&format!("http://{}/players/player1/connections", address))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        let p2_listing: Vec<serde_json::Value> = client
+            .get(&format!("http://{}/players/player2/connections", address))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(p1_listing.len(), 1);
+        assert_eq!(p1_listing[0]["label"], "My cousin");
+        assert_eq!(p2_listing.len(), 1);
+        assert!(p2_listing[0]["label"].is_null());
+        
+        client
+            .post(&format!("http://{}/connections/{}/messages", address, connection.id))
+            .json(&json!({
+                "player_id": "player2",
+                "content": "Hi!"
+            }))
+            .send()
+            .await
+            .unwrap();
+        let notifications: Vec<serde_json::Value> = client
+            .get(&format!("http://{}/players/player1/notifications?expand=labels", address))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert!(notifications.iter().all(|n| n["label"] == "My cousin"));
+    }
+
+    #[actix_web::test]
+    async fn test_friend_request_rejected_for_players_joined_by_link() {
+        // Arrange
+        let address = spawn_app();
+        let client = reqwest::Client::new();
+
+        let connection: Connection = client
+            .post(&format!("http://{}/connections", address))
+            .json(&json!({
+                "player_id": "player1"
+            }))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        let join_resp = client
+            .post(&format!("http://{}/connections/link/{}/join", address, connection.link_id))
+            .json(&json!({
+                "player_id": "player2"
+            }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(join_resp.status(), 200);
+
+        // Act
+        let request_resp = client
+            .post(&format!("http://{}/players/player2/friend-requests", address))
+            .json(&json!({
+                "player_id": "player1"
+            }))
+            .send()
+            .await
+            .unwrap();
+
+        // Assert
+        assert_eq!(request_resp.status(), 400);
+    }
+
+    #[actix_web::test]
+    async fn test_accepted_friend_request_creates_active_connection() {
+        // Arrange
+        let address = spawn_app();
+        let client = reqwest::Client::new();
+        
+        let send_resp = client
+            .post(&format!("http://{}/players/player2/friend-requests", address))
+            .json(&json!({
+                "player_id": "player1"
+            }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(send_resp.status(), 200);
+        let request: serde_json::Value = send_resp.json().await.unwrap();
+        
+        let duplicate_resp = client
+            .post(&format!("http://{}/players/player1/friend-requests", address))
+            .json(&json!({
+                "player_id": "player2"
+            }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(duplicate_resp.status(), 400);
+        
+        let pending: serde_json::Value = client
+            .get(&format!("http://{}/players/player2/friend-requests", address))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(pending["incoming"][0]["id"], request["id"]);
+        
+        // Act - Only the recipient may accept
+        let sender_accept_resp = client
+            .post(&format!("http://{}/friend-requests/{}/accept", address, request["id"].as_str().unwrap()))
+            .json(&json!({
+                "player_id": "player1"
+            }))
+            .send()
+            .await
+            .unwrap();
+        let accept_resp = client
+            .post(&format!("http://{}/friend-requests/{}/accept", address, request["id"].as_str().unwrap()))
+            .json(&json!({
+                "player_id": "player2"
+            }))
+            .send()
+            .await
+            .unwrap();
+        
+        // Assert
+        assert_eq!(sender_accept_resp.status(), 403);
+        assert_eq!(accept_resp.status(), 200);
+        let connection: Connection = accept_resp.json().await.unwrap();
+        assert_eq!(connection.status, ConnectionStatus::Active);
+        assert_eq!(connection.players, vec!["player1".to_string(), "player2".to_string()]);
+        
+        let p1_notifications: Vec<String> = client
+            .get(&format!("http://{}/players/player1/notifications", address))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert!(p1_notifications.iter().any(|n| n.contains("accepted your friend request")));
+        
+        let again_resp = client
+            .post(&format!("http://{}/players/player2/friend-requests", address))
+            .json(&json!({
+                "player_id": "player1"
+            }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(again_resp.status(), 400);
+    }
+
+    #[actix_web::test]
+    async fn test_blocking_declines_pending_friend_requests() {
+        // Arrange
+        let address = spawn_app();
+        let client = reqwest::Client::new();
+        
+        let request: serde_json::Value = client
+            .post(&format!("http://{}/players/player2/friend-requests", address))
+            .json(&json!({
+                "player_id": "player1"
+            }))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        
+        // Act
+        let block_resp = client
+            .post(&format!("http://{}/players/player1/blocks", address))
+            .json(&json!({
+                "player_id": "player2"
+            }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(block_resp.status(), 200);
+        
+        // Assert - The request is no longer pending and can't be accepted
+        let pending: serde_json::Value = client
+            .get(&format!("http://{}/players/player2/friend-requests", address))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(pending["incoming"], json!([]));
+        
+        let accept_resp = client
+            .post(&format!("http://{}/friend-requests/{}/accept", address, request["id"].as_str().unwrap()))
+            .json(&json!({
+                "player_id": "player2"
+            }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(accept_resp.status(), 400);
+        
+        // Nor can a new one be sent while the block stands
+        let resend_resp = client
+            .post(&format!("http://{}/players/player1/friend-requests", address))
+            .json(&json!({
+                "player_id": "player2"
+            }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resend_resp.status(), 403);
+    }
+
+    #[actix_web::test]
+    async fn test_blocking_closes_connection_and_prevents_rejoining() {
+        // Arrange
+        let address = spawn_app();
+        let client = reqwest::Client::new();
+        
+        let connection: Connection = client
+            .post(&format!("http://{}/connections", address))
+            .json(&json!({
+                "player_id": "player1"
+            }))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        
+        client
+            .post(&format!("http://{}/connections/link/{}/join", address, connection.link_id))
+            .json(&json!({
+                "player_id": "player2"
+            }))
+            .send()
+            .await
+            .unwrap();
+        
+        client
+            .post(&format!("http://{}/connections/{}/messages", address, connection.id))
+            .json(&json!({
+                "player_id": "player2",
+                "content": "Before the block"
+            }))
+            .send()
+            .await
+            .unwrap();
+        
+        // Act
+        let block_resp = client
+            .post(&format!("http://{}/players/player1/blocks", address))
+            .json(&json!({
+                "player_id": "player2"
+            }))
+            .send()
+            .await
+            .unwrap();
+        
+        // Assert
+        assert_eq!(block_resp.status(), 200);
+        let blocked: serde_json::Value = block_resp.json().await.unwrap();
+        assert_eq!(blocked["closed_connections"][0], connection.id);
+
+        // The blocked player's messages drop out of the blocker's history only
+        let history = |player: &str| {
+            client
+                .get(&format!("http://{}/connections/{}/messages?player_id={}", address, connection.id, player))
+                .send()
+        };
+        let blocker_history: Vec<serde_json::Value> = history("player1").await.unwrap().json().await.unwrap();
+        assert!(blocker_history.is_empty());
+        let sender_history: Vec<serde_json::Value> = history("player2").await.unwrap().json().await.unwrap();
+        assert_eq!(sender_history.len(), 1);
+        
+        let message_resp = client
+            .post(&format!("http://{}/connections/{}/messages", address, connection.id))
+            .json(&json!({
+                "player_id": "player2",
+                "content": "Let me back in"
+            }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(message_resp.status(), 400);
+        
+        let new_connection: Connection = client
+            .post(&format!("http://{}/connections", address))
+            .json(&json!({
+                "player_id": "player1"
+            }))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        let rejoin_resp = client
+            .post(&format!("http://{}/connections/link/{}/join", address, new_connection.link_id))
+            .json(&json!({
+                "player_id": "player2"
+            }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(rejoin_resp.status(), 403);
+
+        // Blocking works both ways: a player can't join someone they blocked
+        client
+            .post(&format!("http://{}/players/player3/blocks", address))
+            .json(&json!({
+                "player_id": "player1"
+            }))
+            .send()
+            .await
+            .unwrap();
+        let blocker_join_resp = client
+            .post(&format!("http://{}/connections/link/{}/join", address, new_connection.link_id))
+            .json(&json!({
+                "player_id": "player3"
+            }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(blocker_join_resp.status(), 403);
+    }
+
+    #[actix_web::test]
+    async fn test_muted_connection_sends_no_notifications() {
+        // Arrange
+        let address = spawn_app();
+        let client = reqwest::Client::new();
+        
+        let connection: Connection = client
+            .post(&format!("http://{}/connections", address))
+            .json(&json!({
+                "player_id": "player1"
+            }))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        
+        client
+            .post(&format!("http://{}/connections/link/{}/join", address, connection.link_id))
+            .json(&json!({
+                "player_id": "player2"
+            }))
+            .send()
+            .await
+            .unwrap();
+        
+        // Act
+        let mute_resp = client
+            .put(&format!("http://{}/connections/{}/mute", address, connection.id))
+            .json(&json!({
+                "player_id": "player2",
+                "muted": true
+            }))
+            .send()
+            .await
+            .unwrap();
+        let message_resp = client
+            .post(&format!("http://{}/connections/{}/messages", address, connection.id))
+            .json(&json!({
+                "player_id": "player1",
+                "content": "Anyone there?"
+            }))
+            .send()
+            .await
+            .unwrap();
+        
+        // Assert
+        assert_eq!(mute_resp.status(), 200);
+        assert_eq!(message_resp.status(), 200);
+        let notifications: Vec<String> = client
+            .get(&format!("http://{}/players/player2/notifications", address))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert!(notifications.is_empty());
+    }
+
+    #[actix_web::test]
+    async fn test_send_message_enforces_content_limits() {
+        // Arrange
+        let address = spawn_app();
+        let client = reqwest::Client::new();
+        
+        let connection: Connection = client
+            .post(&format!("http://{}/connections", address))
+            .json(&json!({
+                "player_id": "player1"
+            }))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        
+        // Act
+        let empty_resp = client
+            .post(&format!("http://{}/connections/{}/messages", address, connection.id))
+            .json(&json!({
+                "player_id": "player1",
+                "content": "   "
+            }))
+            .send()
+            .await
+            .unwrap();
+        let long_resp = client
+            .post(&format!("http://{}/connections/{}/messages", address, connection.id))
+            .json(&json!({
+                "player_id": "player1",
+                "content": "a".repeat(5000)
+            }))
+            .send()
+            .await
+            .unwrap();
+        let control_resp = client
+            .post(&format!("http://{}/connections/{}/messages", address, connection.id))
+            .json(&json!({
+                "player_id": "player1",
+                "content": "hello\u{0007}\u{001b}[31m"
+            }))
+            .send()
+            .await
+            .unwrap();
+        
+        // Assert
+        assert_eq!(empty_resp.status(), 400);
+        let error: serde_json::Value = empty_resp.json().await.unwrap();
+        assert_eq!(error["code"], "message_empty");
+        
+        assert_eq!(long_resp.status(), 400);
+        let error: serde_json::Value = long_resp.json().await.unwrap();
+        assert_eq!(error["code"], "message_too_many_bytes");
+        
+        assert_eq!(control_resp.status(), 200);
+        let message: serde_json::Value = control_resp.json().await.unwrap();
+        assert_eq!(message["content"], "hello[31m");
+    }
+
+    #[actix_web::test]
+    async fn test_send_message_rejects_links() {
+        // Arrange
+        let address = spawn_app();
+        let client = reqwest::Client::new();
+        
+        let connection: Connection = client
+            .post(&format!("http://{}/connections", address))
+            .json(&json!({
+                "player_id": "player1"
+            }))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        
+        // Act
+        let message_resp = client
+            .post(&format!("http://{}/connections/{}/messages", address, connection.id))
+            .json(&json!({
+                "player_id": "player1",
+                "content": "free coins at https://example.com"
+            }))
+            .send()
+            .await
+            .unwrap();
+        
+        // Assert
+        assert_eq!(message_resp.status(), 400);
+        let error: serde_json::Value = message_resp.json().await.unwrap();
+        assert_eq!(error["code"], "message_rejected");
+    }
+
+    #[actix_web::test]
+    async fn test_edit_react_and_delete_message() {
+        // Arrange
+        let address = spawn_app();
+        let client = reqwest::Client::new();
+        
+        let connection: Connection = client
+            .post(&format!("http://{}/connections", address))
+            .json(&json!({
+                "player_id": "player1"
+            }))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        client
+            .post(&format!("http://{}/connections/link/{}/join", address, connection.link_id))
+            .json(&json!({
+                "player_id": "player2"
+            }))
+            .send()
+            .await
+            .unwrap();
+        let message: Message = client
+            .post(&format!("http://{}/connections/{}/messages", address, connection.id))
+            .json(&json!({
+                "player_id": "player1",
+                "content": "good gmae"
+            }))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        let message_url = format!("http://{}/connections/{}/messages/{}", address, connection.id, message.id);
+        
+        // Act
+        let foreign_edit_resp = client
+            .patch(&message_url)
+            .json(&json!({
+                "player_id": "player2",
+                "content": "bad game"
+            }))
+            .send()
+            .await
+            .unwrap();
+        let edit_resp = client
+            .patch(&message_url)
+            .json(&json!({
+                "player_id": "player1",
+                "content": "good game"
+            }))
+            .send()
+            .await
+            .unwrap();
+        let text_reaction_resp = client
+            .put(&format!("{}/reactions", message_url))
+            .json(&json!({
+                "player_id": "player2",
+                "emoji": "lol",
+                "reacted": true
+            }))
+            .send()
+            .await
+            .unwrap();
+        let reaction_resp = client
+            .put(&format!("{}/reactions", message_url))
+            .json(&json!({
+                "player_id": "player2",
+                "emoji": "🎉",
+                "reacted": true
+            }))
+            .send()
+            .await
+            .unwrap();
+        let delete_resp = client
+            .delete(&message_url)
+            .json(&json!({
+                "player_id": "player1"
+            }))
+            .send()
+            .await
+            .unwrap();
+        let edit_deleted_resp = client
+            .patch(&message_url)
+            .json(&json!({
+                "player_id": "player1",
+                "content": "again"
+            }))
+            .send()
+            .await
+            .unwrap();
+        
+        // Assert
+        assert_eq!(foreign_edit_resp.status(), 403);
+        
+        assert_eq!(edit_resp.status(), 200);
+        let edited: Message = edit_resp.json().await.unwrap();
+        assert_eq!(edited.content, "good game");
+        assert!(edited.edited_at.is_some());
+        
+        assert_eq!(text_reaction_resp.status(), 400);
+        assert_eq!(reaction_resp.status(), 200);
+        let reacted: Message = reaction_resp.json().await.unwrap();
+        assert_eq!(reacted.reactions["🎉"], vec!["player2".to_string()]);
+        
+        assert_eq!(delete_resp.status(), 200);
+        assert_eq!(edit_deleted_resp.status(), 400);
+        
+        // The deleted message stays in history as a tombstone
+        let history: Vec<Message> = client
+            .get(&format!("http://{}/connections/{}/messages?player_id=player2", address, connection.id))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(history.len(), 1);
+        assert!(history[0].deleted);
+        assert!(history[0].content.is_empty());
+        assert!(history[0].reactions.is_empty());
+        
+        let outsider_resp = client
+            .get(&format!("http://{}/connections/{}/messages?player_id=player3", address, connection.id))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(outsider_resp.status(), 400);
+    }
+
+    #[actix_web::test]
+    async fn test_delivery_and_read_receipts() {
+        // Arrange
+        let address = spawn_app();
+        let client = reqwest::Client::new();
+        
+        let connection: Connection = client
+            .post(&format!("http://{}/connections", address))
+            .json(&json!({
+                "player_id": "player1"
+            }))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        client
+            .post(&format!("http://{}/connections/link/{}/join", address, connection.link_id))
+            .json(&json!({
+                "player_id": "player2"
+            }))
+            .send()
+            .await
+            .unwrap();
+        let mut sent = Vec::new();
+        for content in ["first", "second"] {
+            let message: Message = client
+                .post(&format!("http://{}/connections/{}/messages", address, connection.id))
+                .json(&json!({
+                    "player_id": "player1",
+                    "content": content
+                }))
+                .send()
+                .await
+                .unwrap()
+                .json()
+                .await
+                .unwrap();
+            sent.push(message);
+        }
+        let history_url = |player: &str| {
+            format!("http://{}/connections/{}/messages?player_id={}", address, connection.id, player)
+        };
+        
+        // Act - player2 fetches history, then reads only the first message
+        let fetched: Vec<Message> = client
+            .get(&history_url("player2"))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        let read_resp = client
+            .post(&format!("http://{}/connections/{}/messages/read", address, connection.id))
+            .json(&json!({
+                "player_id": "player2",
+                "message_id": sent[0].id
+            }))
+            .send()
+            .await
+            .unwrap();
+        let unknown_resp = client
+            .post(&format!("http://{}/connections/{}/messages/read", address, connection.id))
+            .json(&json!({
+                "player_id": "player2",
+                "message_id": "missing"
+            }))
+            .send()
+            .await
+            .unwrap();
+        
+        // Assert
+        assert!(fetched.iter().all(|m| m.receipts["player2"].delivered_at.is_some()));
+        assert_eq!(read_resp.status(), 200);
+        assert_eq!(unknown_resp.status(), 404);
+        
+        let history: Vec<Message> = client
+            .get(&history_url("player1"))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert!(history[0].receipts["player2"].read_at.is_some());
+        assert!(history[1].receipts["player2"].read_at.is_none());
+        assert!(history[1].receipts["player2"].delivered_at.is_some());
+        // Senders don't get receipts for their own messages
+        assert!(!history[0].receipts.contains_key("player1"));
+    }
+
+    #[actix_web::test]
+    async fn test_presence_is_visible_to_members_only() {
+        // Arrange
+        let address = spawn_app();
+        let client = reqwest::Client::new();
+        
+        let connection: Connection = client
+            .post(&format!("http://{}/connections", address))
+            .json(&json!({
+                "player_id": "player1"
+            }))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        client
+            .post(&format!("http://{}/connections/link/{}/join", address, connection.link_id))
+            .json(&json!({
+                "player_id": "player2"
+            }))
+            .send()
+            .await
+            .unwrap();
+        
+        // Act
+        let member_resp = client
+            .get(&format!("http://{}/players/player1/presence?player_id=player2", address))
+            .send()
+            .await
+            .unwrap();
+        let stranger_resp = client
+            .get(&format!("http://{}/players/player1/presence?player_id=player3", address))
+            .send()
+            .await
+            .unwrap();
+        
+        // Assert
+        assert_eq!(member_resp.status(), 200);
+        let presence: serde_json::Value = member_resp.json().await.unwrap();
+        assert_eq!(presence["status"], "offline");
+        
+        assert_eq!(stranger_resp.status(), 403);
+    }
+
+    #[actix_web::test]
+    async fn test_metrics_endpoint() {
+        // Arrange
+        let address = spawn_app();
+        let client = reqwest::Client::new();
+        
+        let connection: Connection = client
+            .post(&format!("http://{}/connections", address))
+            .json(&json!({
+                "player_id": "player1"
+            }))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        client
+            .get(&format!("http://{}/connections/link/{}", address, connection.link_id))
+            .send()
+            .await
+            .unwrap();
+        
+        // Act
+        let resp = client
+            .get(&format!("http://{}/metrics", address))
+            .send()
+            .await
+            .unwrap();
+        
+        // Assert
+        assert_eq!(resp.status(), 200);
+        assert!(resp.headers()["content-type"].to_str().unwrap().starts_with("text/plain"));
+        let body = resp.text().await.unwrap();
+        assert!(body.contains("friends_connect_connections{status=\"pending\"}"));
+        assert!(body.contains("friends_connect_websocket_sessions"));
+        assert!(body.contains("friends_connect_notifications_queued"));
+        // Latency is labelled by route pattern rather than the raw path
+        assert!(body.contains("route=\"/connections/link/{link_id}\""));
+        assert!(!body.contains(&connection.link_id));
+    }
+
+    #[actix_web::test]
+    async fn test_readiness_fails_once_shutdown_begins() {
+        // Arrange
+        let (address, server) = spawn_app_without_event_bus();
+        let client = reqwest::Client::new();
+        
+        // Act
+        let healthz = client.get(&format!("http://{}/healthz", address)).send().await.unwrap();
+        let ready = client.get(&format!("http://{}/readyz", address)).send().await.unwrap();
+        server.health.begin_shutdown();
+        let draining = client.get(&format!("http://{}/readyz", address)).send().await.unwrap();
+        
+        // Assert
+        assert_eq!(healthz.status(), 200);
+        
+        assert_eq!(ready.status(), 200);
+        let body: serde_json::Value = ready.json().await.unwrap();
+        assert_eq!(body["status"], "ready");
+        assert_eq!(body["checks"]["storage"]["status"], "ok");
+        assert_eq!(body["checks"]["event_bus_producer"]["status"], "disabled");
+        
+        assert_eq!(draining.status(), 503);
+        let body: serde_json::Value = draining.json().await.unwrap();
+        assert_eq!(body["shutting_down"], true);
+    }
+
+    #[actix_web::test]
+    async fn test_link_preview_is_rate_limited() {
+        // Arrange
+        let address = spawn_app();
+        let client = reqwest::Client::new();
+        
+        // Act - Probe more links than the preview budget allows
+        let mut last_resp = None;
+        for i in 0..31 {
+            last_resp = Some(
+                client
+                    .get(&format!("http://{}/connections/link/guess-{}", address, i))
+                    .send()
+                    .await
+                    .unwrap(),
+            );
+        }
+        
+        // Assert
+        let last_resp = last_resp.unwrap();
+        assert_eq!(last_resp.status(), 429);
+        let retry_after: u64 = last_resp.headers()["Retry-After"].to_str().unwrap().parse().unwrap();
+        assert!(retry_after >= 1);
+    }
+
+    #[test]
+    fn test_server_new() {
+        let server = Server::new("127.0.0.1:8080");
+        assert_eq!(server.address, "127.0.0.1:8080");
+    }
+
+    #[actix_web::test]
+    async fn test_notifications_are_cleared_after_acknowledgment() {
+        // Arrange
+        let address = spawn_app();
+        let client = reqwest::Client::new();
+        
+        // Create connection with player1
+        let create_resp = client
+            .post(&format!("http://{}/connections", address))
+            .json(&json!({
+                "player_id": "player1"
+            }))
+            .send()
+            .await
+            .unwrap();
+        
+        let connection: Connection = create_resp.json().await.unwrap();
+        
+        // Join with player2 and send a message to generate notifications
+        client
+            .post(&format!("http://{}/connections/link/{}/join", address, connection.link_id))
             .json(&json!({
                 "player_id": "player2"
             }))
@@ -719,4 +2562,378 @@ mod tests {
             
         assert!(final_notifications.is_empty());
     }
-}
\ No newline at end of file
+
+    #[actix_web::test]
+    async fn test_poisoned_store_fails_requests_that_need_it() {
+        // Arrange
+        let (address, server) = spawn_app_without_event_bus();
+        let client = reqwest::Client::new();
+        let connection: Connection = client
+            .post(&format!("http://{}/connections", address))
+            .json(&json!({"player_id": "player1"}))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        
+        // A handler panicking mid-update poisons the messages store
+        let messages = server.messages.clone();
+        let _ = std::thread::spawn(move || {
+            let _guard = messages.write().unwrap();
+            panic!("handler panicked mid-update");
+        })
+        .join();
+        
+        // Act
+        let send = client
+            .post(&format!("http://{}/connections/{}/messages", address, connection.id))
+            .json(&json!({"player_id": "player1", "content": "hello"}))
+            .send()
+            .await
+            .unwrap();
+        let listing = client
+            .get(&format!("http://{}/players/player1/connections", address))
+            .send()
+            .await
+            .unwrap();
+        
+        // Assert
+        assert_eq!(send.status(), 500);
+        let body: serde_json::Value = send.json().await.unwrap();
+        assert_eq!(body["error"], "Internal server error");
+        
+        // Requests that don't touch the poisoned store still work
+        assert_eq!(listing.status(), 200);
+        let body: Vec<serde_json::Value> = listing.json().await.unwrap();
+        assert_eq!(body.len(), 1);
+    }
+
+    #[actix_web::test]
+    async fn test_server_keeps_serving_when_kafka_setup_fails() {
+        // Arrange: a property librdkafka rejects, so neither the producer nor
+        // the notification consumer can be created
+        let mut config = Config::default();
+        config.set("event_bus.backend.properties.socket.timeout.ms", "soon").unwrap();
+        let (address, _server) = spawn_app_with_config(config);
+        let client = reqwest::Client::new();
+        
+        // Act
+        let created = client
+            .post(&format!("http://{}/connections", address))
+            .json(&json!({"player_id": "player1"}))
+            .send()
+            .await
+            .unwrap();
+        let ready = client.get(&format!("http://{}/readyz", address)).send().await.unwrap();
+        
+        // Assert
+        assert_eq!(created.status(), 200);
+        assert_eq!(ready.status(), 503);
+        let body: serde_json::Value = ready.json().await.unwrap();
+        assert_eq!(body["checks"]["event_bus_producer"]["status"], "error");
+        assert_eq!(body["checks"]["notification_consumer"]["status"], "error");
+    }
+
+    const ADMIN_TOKEN: &str = "support-token-0123456789";
+
+    fn spawn_app_with_admin() -> (String, Server) {
+        let mut config = Config::default();
+        config.event_bus.kind = EventBusKind::None;
+        config.admin.token = ADMIN_TOKEN.to_string();
+        spawn_app_with_config(config)
+    }
+
+    #[actix_web::test]
+    async fn test_admin_api_requires_token() {
+        // Arrange
+        let (address, _server) = spawn_app_with_admin();
+        let (disabled_address, _disabled) = spawn_app_without_event_bus();
+        let client = reqwest::Client::new();
+        let url = format!("http://{}/admin/sessions", address);
+        
+        // Act
+        let missing = client.get(&url).send().await.unwrap();
+        let wrong = client.get(&url).bearer_auth("support-token-wrong").send().await.unwrap();
+        let valid = client.get(&url).bearer_auth(ADMIN_TOKEN).send().await.unwrap();
+        let disabled = client
+            .get(&format!("http://{}/admin/sessions", disabled_address))
+            .bearer_auth(ADMIN_TOKEN)
+            .send()
+            .await
+            .unwrap();
+        
+        // Assert
+        assert_eq!(missing.status(), 401);
+        assert_eq!(wrong.status(), 401);
+        let body: serde_json::Value = wrong.json().await.unwrap();
+        assert_eq!(body["error"], "Admin token required");
+        assert_eq!(valid.status(), 200);
+        let body: serde_json::Value = valid.json().await.unwrap();
+        assert_eq!(body["count"], 0);
+        // Without a configured token the API isn't mounted at all
+        assert_eq!(disabled.status(), 404);
+        // Player data requests answer, but can't be served without a token
+        let unconfigured = client
+            .get(&format!("http://{}/players/player1/export", disabled_address))
+            .bearer_auth(ADMIN_TOKEN)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(unconfigured.status(), 503);
+    }
+
+    #[actix_web::test]
+    async fn test_admin_can_find_and_close_connection() {
+        // Arrange
+        let (address, _server) = spawn_app_with_admin();
+        let client = reqwest::Client::new();
+        
+        let connection: Connection = client
+            .post(&format!("http://{}/connections", address))
+            .json(&json!({
+                "player_id": "player1"
+            }))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        client
+            .post(&format!("http://{}/connections/link/{}/join", address, connection.link_id))
+            .json(&json!({
+                "player_id": "player2"
+            }))
+            .send()
+            .await
+            .unwrap();
+        client
+            .post(&format!("http://{}/connections/{}/messages", address, connection.id))
+            .json(&json!({
+                "player_id": "player2",
+                "content": "hello"
+            }))
+            .send()
+            .await
+            .unwrap();
+        
+        // Act
+        let by_player: Vec<serde_json::Value> = client
+            .get(&format!("http://{}/admin/connections?player_id=player2", address))
+            .bearer_auth(ADMIN_TOKEN)
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        let by_link: Vec<serde_json::Value> = client
+            .get(&format!("http://{}/admin/connections?link_id={}", address, connection.link_id))
+            .bearer_auth(ADMIN_TOKEN)
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        let close_resp = client
+            .post(&format!("http://{}/admin/connections/{}/close", address, connection.id))
+            .bearer_auth(ADMIN_TOKEN)
+            .send()
+            .await
+            .unwrap();
+        let purge_resp = client
+            .delete(&format!("http://{}/admin/players/player1/notifications", address))
+            .bearer_auth(ADMIN_TOKEN)
+            .send()
+            .await
+            .unwrap();
+        
+        // Assert
+        assert_eq!(by_player.len(), 1);
+        assert_eq!(by_player[0]["id"], connection.id);
+        assert_eq!(by_player[0]["message_count"], 1);
+        assert_eq!(by_link.len(), 1);
+        
+        assert_eq!(close_resp.status(), 200);
+        let closed: Connection = close_resp.json().await.unwrap();
+        assert_eq!(closed.status, ConnectionStatus::Closed);
+        assert!(!closed.link_active);
+        
+        let message_resp = client
+            .post(&format!("http://{}/connections/{}/messages", address, connection.id))
+            .json(&json!({
+                "player_id": "player1",
+                "content": "still there?"
+            }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(message_resp.status(), 400);
+        
+        let purged: serde_json::Value = purge_resp.json().await.unwrap();
+        // The join and the new message
+        assert_eq!(purged["purged"], 2);
+        let notifications: Vec<String> = client
+            .get(&format!("http://{}/players/player1/notifications", address))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert!(notifications.is_empty());
+    }
+
+    #[actix_web::test]
+    async fn test_player_data_export_and_deletion() {
+        // Arrange
+        let (address, _server) = spawn_app_with_admin();
+        let client = reqwest::Client::new();
+        
+        let connection: Connection = client
+            .post(&format!("http://{}/connections", address))
+            .json(&json!({
+                "player_id": "player1"
+            }))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        client
+            .post(&format!("http://{}/connections/link/{}/join", address, connection.link_id))
+            .json(&json!({
+                "player_id": "player2"
+            }))
+            .send()
+            .await
+            .unwrap();
+        // An unrelated player who happens to share player1's display name
+        for player in ["player1", "player5"] {
+            client
+                .put(&format!("http://{}/players/{}/profile", address, player))
+                .json(&json!({
+                    "display_name": "Sam"
+                }))
+                .send()
+                .await
+                .unwrap();
+        }
+        let other: Connection = client
+            .post(&format!("http://{}/connections", address))
+            .json(&json!({
+                "player_id": "player4"
+            }))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        client
+            .post(&format!("http://{}/connections/link/{}/join", address, other.link_id))
+            .json(&json!({
+                "player_id": "player5"
+            }))
+            .send()
+            .await
+            .unwrap();
+        for (player, content) in [("player1", "hi from 1"), ("player2", "hi from 2")] {
+            client
+                .post(&format!("http://{}/connections/{}/messages", address, connection.id))
+                .json(&json!({
+                    "player_id": player,
+                    "content": content
+                }))
+                .send()
+                .await
+                .unwrap();
+        }
+        
+        // Act
+        let anonymous_resp = client
+            .delete(&format!("http://{}/players/player1", address))
+            .send()
+            .await
+            .unwrap();
+        let export_resp = client
+            .get(&format!("http://{}/players/player1/export", address))
+            .bearer_auth(ADMIN_TOKEN)
+            .send()
+            .await
+            .unwrap();
+        let delete_resp = client
+            .delete(&format!("http://{}/players/player1", address))
+            .bearer_auth(ADMIN_TOKEN)
+            .send()
+            .await
+            .unwrap();
+        
+        // Assert
+        assert_eq!(anonymous_resp.status(), 401);
+        assert_eq!(export_resp.status(), 200);
+        assert!(export_resp.headers()["content-disposition"]
+            .to_str()
+            .unwrap()
+            .starts_with("attachment"));
+        let archive: serde_json::Value = export_resp.json().await.unwrap();
+        assert_eq!(archive["connections"][0]["id"], connection.id);
+        assert_eq!(archive["messages"][&connection.id].as_array().unwrap().len(), 2);
+        assert!(!archive["notifications"].as_array().unwrap().is_empty());
+        
+        assert_eq!(delete_resp.status(), 200);
+        let deleted: serde_json::Value = delete_resp.json().await.unwrap();
+        assert_eq!(deleted["closed_connections"][0], connection.id);
+        assert_eq!(deleted["anonymised_messages"], 1);
+        
+        // The other member keeps the history, with player1 anonymised
+        let remaining: Vec<Connection> = client
+            .get(&format!("http://{}/players/player2/connections", address))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(remaining[0].status, ConnectionStatus::Closed);
+        assert!(!remaining[0].players.contains(&"player1".to_string()));
+        let history: Vec<Message> = client
+            .get(&format!("http://{}/connections/{}/messages?player_id=player2", address, connection.id))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert!(history[0].deleted && history[0].content.is_empty());
+        assert_ne!(history[0].from, "player1");
+        assert_eq!(history[1].content, "hi from 2");
+        
+        let archive: serde_json::Value = client
+            .get(&format!("http://{}/players/player1/export", address))
+            .bearer_auth(ADMIN_TOKEN)
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert!(archive["connections"].as_array().unwrap().is_empty());
+        assert!(archive["notifications"].as_array().unwrap().is_empty());
+        
+        // Notifications are matched by player and connection, not by name
+        let unrelated: Vec<String> = client
+            .get(&format!("http://{}/players/player4/notifications", address))
+            .send()
+            .await
+            .unwrap()
+            .json()
+            .await
+            .unwrap();
+        assert_eq!(unrelated, vec!["Player Sam joined your connection"]);
+    }
+}
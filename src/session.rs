@@ -0,0 +1,643 @@
+use actix::Recipient;
+use actix_web_actors::ws::CloseCode;
+use serde::Serialize;
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::RwLock;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+use crate::presence::Presence;
+use crate::socket_limits::{SessionOverflow, SocketLimits};
+use crate::store::recover;
+
+// How many pushed events are kept per player for resuming sockets
+pub const REPLAY_BUFFER_SIZE: usize = 256;
+
+// How long a player's buffer is kept after their last socket closes
+pub const REPLAY_TTL: Duration = Duration::from_secs(10 * 60);
+
+// An event pushed from the server to a player's open sockets
+#[derive(actix::Message, Debug, Clone)]
+#[rtype(result = "()")]
+pub struct ServerEvent {
+    pub event_type: String,
+    pub payload: serde_json::Value,
+    // Per-player sequence, stamped by the registry when the event is sent
+    pub seq: Option<u64>,
+}
+
+impl ServerEvent {
+    pub fn new(event_type: &str, payload: serde_json::Value) -> Self {
+        ServerEvent {
+            event_type: event_type.to_string(),
+            payload,
+            seq: None,
+        }
+    }
+}
+
+// Ask a socket to send a close frame and shut down
+#[derive(actix::Message, Debug, Clone)]
+#[rtype(result = "()")]
+pub struct CloseSession {
+    pub code: CloseCode,
+    pub reason: String,
+}
+
+// Activity is kept in atomics, as milliseconds since the registry's epoch,
+// so recording it on every frame only needs the read lock
+struct Session {
+    recipient: Recipient<ServerEvent>,
+    closer: Recipient<CloseSession>,
+    connected_at: Instant,
+    // Last sign of life, including heartbeat pongs
+    last_seen: AtomicU64,
+    // Last frame the player actually sent
+    last_active: AtomicU64,
+    // Set by the client when the player steps away
+    away: AtomicBool,
+}
+
+// A player's open sockets and recent events; kept for a while after the last
+// socket closes so a reconnecting client can catch up
+struct PlayerSessions {
+    sessions: HashMap<String, Session>,
+    // Names this run of sequences; a new one starts whenever the buffer is
+    // dropped, so old sequences can't be mistaken for new ones
+    stream: String,
+    last_seq: u64,
+    recent: VecDeque<ServerEvent>,
+    // When the last socket closed; None while any is open
+    closed_at: Option<Instant>,
+    // What the player's contacts were last told
+    announced: Presence,
+}
+
+// Each new entry starts a fresh stream
+impl Default for PlayerSessions {
+    fn default() -> Self {
+        PlayerSessions {
+            sessions: HashMap::new(),
+            stream: Uuid::new_v4().to_string(),
+            last_seq: 0,
+            recent: VecDeque::new(),
+            closed_at: None,
+            announced: Presence::default(),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Registration {
+    // `first` is true if this is the player's only open session
+    Accepted { first: bool },
+    // The player is at their session cap and new sessions are refused
+    Rejected,
+}
+
+// An open socket as the admin API shows it
+#[derive(Debug, Clone, Serialize)]
+pub struct SessionInfo {
+    pub player_id: String,
+    pub session_id: String,
+    pub connected_secs: u64,
+    // Since the player last sent a frame
+    pub idle_secs: u64,
+    pub away: bool,
+}
+
+// What a resuming client should do
+#[derive(Debug)]
+pub enum Resume {
+    // Events after the client's sequence, oldest first
+    Replay(Vec<ServerEvent>),
+    // The gap is no longer buffered; refetch state over HTTP
+    Resync,
+}
+
+// Live WebSocket sessions by player, so HTTP handlers can push events to them
+pub struct SessionRegistry {
+    players: RwLock<HashMap<String, PlayerSessions>>,
+    epoch: Instant,
+}
+
+impl Default for SessionRegistry {
+    fn default() -> Self {
+        SessionRegistry {
+            players: RwLock::default(),
+            epoch: Instant::now(),
+        }
+    }
+}
+
+impl SessionRegistry {
+    fn now_millis(&self) -> u64 {
+        self.epoch.elapsed().as_millis() as u64
+    }
+
+    // Time since a timestamp stored by `now_millis`
+    fn since(&self, stamp: &AtomicU64) -> Duration {
+        Duration::from_millis(self.now_millis().saturating_sub(stamp.load(Ordering::Relaxed)))
+    }
+
+    // Online if any live session is in use, away if all are idle or set away,
+    // offline if none has been heard from within `timeout`
+    fn presence_of(&self, player: &PlayerSessions, timeout: Duration, away_after: Duration) -> Presence {
+        let live: Vec<&Session> = player
+            .sessions
+            .values()
+            .filter(|session| self.since(&session.last_seen) <= timeout)
+            .collect();
+
+        if live.is_empty() {
+            Presence::Offline
+        } else if live.iter().any(|session| {
+            !session.away.load(Ordering::Relaxed) && self.since(&session.last_active) <= away_after
+        }) {
+            Presence::Online
+        } else {
+            Presence::Away
+        }
+    }
+
+    // Add a session, enforcing the per-player cap by evicting the oldest
+    // session or refusing this one
+    pub fn register(
+        &self,
+        player_id: &str,
+        session_id: &str,
+        recipient: Recipient<ServerEvent>,
+        closer: Recipient<CloseSession>,
+        limits: &SocketLimits,
+    ) -> Registration {
+        let now = self.now_millis();
+        let mut players = recover(self.players.write());
+        let player = players.entry(player_id.to_string()).or_default();
+
+        while player.sessions.len() >= limits.max_sessions_per_player {
+            if limits.session_overflow == SessionOverflow::RejectNew {
+                return Registration::Rejected;
+            }
+            let oldest = player
+                .sessions
+                .iter()
+                .min_by_key(|(_, session)| session.connected_at)
+                .map(|(id, _)| id.clone());
+            if let Some(session) = oldest.and_then(|id| player.sessions.remove(&id)) {
+                let _ = session.closer.try_send(CloseSession {
+                    code: CloseCode::Policy,
+                    reason: "Replaced by a newer session".to_string(),
+                });
+            }
+        }
+
+        player.sessions.insert(
+            session_id.to_string(),
+            Session {
+                recipient,
+                closer,
+                connected_at: Instant::now(),
+                last_seen: AtomicU64::new(now),
+                last_active: AtomicU64::new(now),
+                away: AtomicBool::new(false),
+            },
+        );
+        player.closed_at = None;
+        Registration::Accepted {
+            first: player.sessions.len() == 1,
+        }
+    }
+
+    // Returns true if that was the player's last open session
+    pub fn unregister(&self, player_id: &str, session_id: &str) -> bool {
+        let mut players = recover(self.players.write());
+        match players.get_mut(player_id) {
+            Some(player) => {
+                let last = player.sessions.remove(session_id).is_some() && player.sessions.is_empty();
+                if last {
+                    player.closed_at = Some(Instant::now());
+                }
+                last
+            }
+            None => false,
+        }
+    }
+
+    // Drop the buffers of players whose last socket closed more than `ttl`
+    // ago; returns how many went
+    pub fn prune(&self, ttl: Duration) -> usize {
+        let mut players = recover(self.players.write());
+        let before = players.len();
+        players.retain(|_, player| {
+            !player.sessions.is_empty() || player.closed_at.is_some_and(|at| at.elapsed() < ttl)
+        });
+        before - players.len()
+    }
+
+    // Record a sign of life; `active` is for frames the player sent rather than pongs
+    pub fn touch(&self, player_id: &str, session_id: &str, active: bool) {
+        let players = recover(self.players.read());
+        if let Some(session) = players.get(player_id).and_then(|p| p.sessions.get(session_id)) {
+            let now = self.now_millis();
+            session.last_seen.store(now, Ordering::Relaxed);
+            if active {
+                session.last_active.store(now, Ordering::Relaxed);
+            }
+        }
+    }
+
+    pub fn set_away(&self, player_id: &str, session_id: &str, away: bool) {
+        let players = recover(self.players.read());
+        if let Some(session) = players.get(player_id).and_then(|p| p.sessions.get(session_id)) {
+            session.away.store(away, Ordering::Relaxed);
+        }
+    }
+
+    pub fn presence(&self, player_id: &str, timeout: Duration, away_after: Duration) -> Presence {
+        recover(self.players.read())
+            .get(player_id)
+            .map_or(Presence::Offline, |player| self.presence_of(player, timeout, away_after))
+    }
+
+    // The player's presence if it differs from what their contacts were last
+    // told, recording it as told
+    pub fn announce(&self, player_id: &str, timeout: Duration, away_after: Duration) -> Option<Presence> {
+        // Most calls find nothing new, so check under the read lock first
+        {
+            let players = recover(self.players.read());
+            let player = players.get(player_id)?;
+            if self.presence_of(player, timeout, away_after) == player.announced {
+                return None;
+            }
+        }
+
+        let mut players = recover(self.players.write());
+        let player = players.get_mut(player_id)?;
+        let presence = self.presence_of(player, timeout, away_after);
+        if presence == player.announced {
+            return None;
+        }
+        player.announced = presence;
+        Some(presence)
+    }
+
+    // Every player whose presence changed since their contacts were last told,
+    // such as by going idle or timing out, recording the new presence as told
+    pub fn presence_changes(&self, timeout: Duration, away_after: Duration) -> Vec<(String, Presence)> {
+        let mut players = recover(self.players.write());
+        let mut changes = Vec::new();
+        for (player_id, player) in players.iter_mut() {
+            let presence = self.presence_of(player, timeout, away_after);
+            if presence != player.announced {
+                player.announced = presence;
+                changes.push((player_id.clone(), presence));
+            }
+        }
+        changes
+    }
+
+    // Open sessions across all players
+    pub fn session_count(&self) -> usize {
+        recover(self.players.read()).values().map(|p| p.sessions.len()).sum()
+    }
+
+    // Every open session, longest connected first
+    pub fn list(&self) -> Vec<SessionInfo> {
+        let players = recover(self.players.read());
+        let mut sessions: Vec<(Instant, SessionInfo)> = players
+            .iter()
+            .flat_map(|(player_id, player)| {
+                player.sessions.iter().map(move |(session_id, session)| {
+                    (
+                        session.connected_at,
+                        SessionInfo {
+                            player_id: player_id.clone(),
+                            session_id: session_id.clone(),
+                            connected_secs: session.connected_at.elapsed().as_secs(),
+                            idle_secs: self.since(&session.last_active).as_secs(),
+                            away: session.away.load(Ordering::Relaxed),
+                        },
+                    )
+                })
+            })
+            .collect();
+        sessions.sort_by_key(|(connected_at, _)| *connected_at);
+        sessions.into_iter().map(|(_, info)| info).collect()
+    }
+
+    // The stream the player's sequences belong to; None until they connect
+    pub fn stream(&self, player_id: &str) -> Option<String> {
+        recover(self.players.read())
+            .get(player_id)
+            .map(|player| player.stream.clone())
+    }
+
+    // The sequence of the newest event sent to the player
+    pub fn last_seq(&self, player_id: &str) -> u64 {
+        recover(self.players.read())
+            .get(player_id)
+            .map_or(0, |player| player.last_seq)
+    }
+
+    // Stamp the event with the player's next sequence, keep it for resuming,
+    // and push it to every socket they have open; false if none took it.
+    // Players with no socket open or recently closed get nothing buffered;
+    // they fetch state over HTTP when they connect.
+    pub fn send(&self, player_id: &str, mut event: ServerEvent) -> bool {
+        let mut players = recover(self.players.write());
+        let player = match players.get_mut(player_id) {
+            Some(player) => player,
+            None => return false,
+        };
+
+        player.last_seq += 1;
+        event.seq = Some(player.last_seq);
+        player.recent.push_back(event.clone());
+        while player.recent.len() > REPLAY_BUFFER_SIZE {
+            player.recent.pop_front();
+        }
+
+        let mut delivered = false;
+        for session in player.sessions.values() {
+            if session.recipient.try_send(event.clone()).is_ok() {
+                delivered = true;
+            }
+        }
+        delivered
+    }
+
+    // Push a short-lived event (like a typing indicator) that isn't sequenced
+    // or kept for resuming
+    pub fn send_ephemeral(&self, player_id: &str, event: ServerEvent) {
+        let players = recover(self.players.read());
+        if let Some(player) = players.get(player_id) {
+            for session in player.sessions.values() {
+                let _ = session.recipient.try_send(event.clone());
+            }
+        }
+    }
+
+    // Send every open socket a parting event and close it; returns how many
+    // sessions were asked to close
+    pub fn close_all(&self, mut notice: impl FnMut() -> ServerEvent, close: CloseSession) -> usize {
+        let players = recover(self.players.read());
+        let mut closed = 0;
+        for session in players.values().flat_map(|player| player.sessions.values()) {
+            let _ = session.recipient.try_send(notice());
+            if session.closer.try_send(close.clone()).is_ok() {
+                closed += 1;
+            }
+        }
+        closed
+    }
+
+    // Close the player's sockets and drop their buffered events; returns how
+    // many sessions were asked to close
+    pub fn forget(&self, player_id: &str, close: CloseSession) -> usize {
+        let removed = recover(self.players.write()).remove(player_id);
+        removed.map_or(0, |player| {
+            player
+                .sessions
+                .values()
+                .filter(|session| session.closer.try_send(close.clone()).is_ok())
+                .count()
+        })
+    }
+
+    // Drop matching events from every player's replay buffer; returns how many went
+    pub fn scrub(&self, mut matches: impl FnMut(&ServerEvent) -> bool) -> usize {
+        let mut players = recover(self.players.write());
+        let mut dropped = 0;
+        for player in players.values_mut() {
+            let before = player.recent.len();
+            player.recent.retain(|event| !matches(event));
+            dropped += before - player.recent.len();
+        }
+        dropped
+    }
+
+    // Events the player missed after `last_seen_seq` in `stream`, if they are
+    // all still buffered
+    pub fn resume(&self, player_id: &str, stream: Option<&str>, last_seen_seq: u64) -> Resume {
+        let players = recover(self.players.read());
+        let player = match players.get(player_id) {
+            Some(player) => player,
+            None if last_seen_seq == 0 => return Resume::Replay(Vec::new()),
+            None => return Resume::Resync,
+        };
+
+        // Sequences from before a restart or a dropped buffer mean nothing now
+        if last_seen_seq > 0 && stream != Some(player.stream.as_str()) {
+            return Resume::Resync;
+        }
+
+        // A sequence from the future means the server lost its state
+        if last_seen_seq > player.last_seq {
+            return Resume::Resync;
+        }
+
+        let oldest = player
+            .recent
+            .front()
+            .and_then(|event| event.seq)
+            .unwrap_or(player.last_seq + 1);
+        if last_seen_seq + 1 < oldest {
+            return Resume::Resync;
+        }
+
+        Resume::Replay(
+            player
+                .recent
+                .iter()
+                .filter(|event| event.seq.is_some_and(|seq| seq > last_seen_seq))
+                .cloned()
+                .collect(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    struct StubSession;
+
+    impl actix::Actor for StubSession {
+        type Context = actix::Context<Self>;
+    }
+
+    impl actix::Handler<ServerEvent> for StubSession {
+        type Result = ();
+        fn handle(&mut self, _: ServerEvent, _: &mut Self::Context) {}
+    }
+
+    impl actix::Handler<CloseSession> for StubSession {
+        type Result = ();
+        fn handle(&mut self, _: CloseSession, _: &mut Self::Context) {}
+    }
+
+    fn register(registry: &SessionRegistry, session_id: &str, limits: &SocketLimits) -> Registration {
+        use actix::Actor;
+        let addr = StubSession.start();
+        registry.register("player1", session_id, addr.clone().recipient(), addr.recipient(), limits)
+    }
+
+    #[actix_web::test]
+    async fn test_session_cap_evicts_or_rejects() {
+        let evict = SocketLimits {
+            max_sessions_per_player: 2,
+            ..SocketLimits::default()
+        };
+        let registry = SessionRegistry::default();
+
+        assert_eq!(register(&registry, "a", &evict), Registration::Accepted { first: true });
+        assert_eq!(register(&registry, "b", &evict), Registration::Accepted { first: false });
+        assert_eq!(register(&registry, "c", &evict), Registration::Accepted { first: false });
+        // "a" was evicted, so closing it doesn't leave the player offline
+        assert!(!registry.unregister("player1", "a"));
+
+        let reject = SocketLimits {
+            session_overflow: SessionOverflow::RejectNew,
+            ..evict
+        };
+        assert_eq!(register(&registry, "d", &reject), Registration::Rejected);
+    }
+
+    #[actix_web::test]
+    async fn test_presence_changes_are_reported_once() {
+        let registry = SessionRegistry::default();
+        let (timeout, away_after) = (Duration::from_secs(60), Duration::from_secs(300));
+        register(&registry, "a", &SocketLimits::default());
+
+        assert_eq!(registry.announce("player1", timeout, away_after), Some(Presence::Online));
+        assert_eq!(registry.announce("player1", timeout, away_after), None);
+
+        // Nothing announces this change, so the sweep finds it
+        registry.set_away("player1", "a", true);
+        assert_eq!(
+            registry.presence_changes(timeout, away_after),
+            vec![("player1".to_string(), Presence::Away)]
+        );
+        assert!(registry.presence_changes(timeout, away_after).is_empty());
+    }
+
+    #[actix_web::test]
+    async fn test_close_all_reaches_every_session() {
+        let registry = SessionRegistry::default();
+        let limits = SocketLimits::default();
+        register(&registry, "a", &limits);
+        register(&registry, "b", &limits);
+
+        let mut notices = 0;
+        let close = CloseSession {
+            code: CloseCode::Away,
+            reason: "Server going away".to_string(),
+        };
+        let closed = registry.close_all(
+            || {
+                notices += 1;
+                ServerEvent::new("server_going_away", json!({}))
+            },
+            close,
+        );
+
+        assert_eq!(closed, 2);
+        assert_eq!(notices, 2);
+    }
+
+    #[actix_web::test]
+    async fn test_scrub_drops_matching_buffered_events() {
+        let registry = SessionRegistry::default();
+        register(&registry, "a", &SocketLimits::default());
+        registry.send("player1", ServerEvent::new("new_message", json!({"from": "player2"})));
+        registry.send("player1", ServerEvent::new("new_message", json!({"from": "player3"})));
+
+        let dropped = registry.scrub(|event| event.payload["from"] == "player2");
+
+        assert_eq!(dropped, 1);
+        assert_eq!(replayed(resume(&registry, "player1", 1)), vec![2]);
+        // The dropped event can't be replayed, so a client that missed it resyncs
+        assert!(matches!(resume(&registry, "player1", 0), Resume::Resync));
+    }
+
+    // Resume within the player's current stream
+    fn resume(registry: &SessionRegistry, player_id: &str, last_seen_seq: u64) -> Resume {
+        registry.resume(player_id, registry.stream(player_id).as_deref(), last_seen_seq)
+    }
+
+    fn replayed(resume: Resume) -> Vec<u64> {
+        match resume {
+            Resume::Replay(events) => events.iter().filter_map(|event| event.seq).collect(),
+            Resume::Resync => panic!("expected a replay"),
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_events_are_buffered_while_offline() {
+        let registry = SessionRegistry::default();
+        register(&registry, "a", &SocketLimits::default());
+        assert!(registry.unregister("player1", "a"));
+
+        assert!(!registry.send("player1", ServerEvent::new("new_message", json!({}))));
+        assert!(!registry.send("player1", ServerEvent::new("new_message", json!({}))));
+
+        assert_eq!(registry.last_seq("player1"), 2);
+        assert_eq!(replayed(resume(&registry, "player1", 0)), vec![1, 2]);
+        assert_eq!(replayed(resume(&registry, "player1", 1)), vec![2]);
+        assert!(replayed(resume(&registry, "player1", 2)).is_empty());
+    }
+
+    #[actix_web::test]
+    async fn test_buffers_are_only_kept_for_recent_sessions() {
+        let registry = SessionRegistry::default();
+
+        // Never connected: nothing to keep
+        assert!(!registry.send("player2", ServerEvent::new("new_message", json!({}))));
+        assert_eq!(registry.last_seq("player2"), 0);
+
+        register(&registry, "a", &SocketLimits::default());
+        assert_eq!(registry.prune(Duration::ZERO), 0);
+        registry.unregister("player1", "a");
+        assert_eq!(registry.prune(REPLAY_TTL), 0);
+        assert_eq!(registry.prune(Duration::ZERO), 1);
+        assert!(!registry.send("player1", ServerEvent::new("new_message", json!({}))));
+        assert_eq!(registry.last_seq("player1"), 0);
+    }
+
+    #[actix_web::test]
+    async fn test_resume_from_a_dropped_stream_resyncs() {
+        let registry = SessionRegistry::default();
+        register(&registry, "a", &SocketLimits::default());
+        registry.send("player1", ServerEvent::new("new_message", json!({})));
+        let old_stream = registry.stream("player1");
+        registry.unregister("player1", "a");
+        registry.prune(Duration::ZERO);
+
+        // Sequences restart in a new stream
+        register(&registry, "b", &SocketLimits::default());
+        registry.send("player1", ServerEvent::new("new_message", json!({})));
+        registry.send("player1", ServerEvent::new("new_message", json!({})));
+        assert_ne!(registry.stream("player1"), old_stream);
+
+        assert!(matches!(registry.resume("player1", old_stream.as_deref(), 1), Resume::Resync));
+        assert!(matches!(registry.resume("player1", None, 1), Resume::Resync));
+        assert_eq!(replayed(resume(&registry, "player1", 1)), vec![2]);
+    }
+
+    #[actix_web::test]
+    async fn test_resume_requires_resync_when_gap_is_gone() {
+        let registry = SessionRegistry::default();
+        register(&registry, "a", &SocketLimits::default());
+        for _ in 0..REPLAY_BUFFER_SIZE + 5 {
+            registry.send("player1", ServerEvent::new("new_message", json!({})));
+        }
+
+        assert!(matches!(resume(&registry, "player1", 2), Resume::Resync));
+        assert_eq!(replayed(resume(&registry, "player1", 5)).len(), REPLAY_BUFFER_SIZE);
+        // Sequences the server never issued
+        assert!(matches!(resume(&registry, "player1", 10_000), Resume::Resync));
+        assert!(matches!(resume(&registry, "player2", 3), Resume::Resync));
+    }
+}
@@ -0,0 +1,95 @@
+use rand::Rng;
+
+// Base32 without the easily confused I/O and 0/1
+pub const DEFAULT_ALPHABET: &str = "ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+pub const DEFAULT_LENGTH: usize = 6;
+
+// Fewer possible codes than this and live links would soon use most of them,
+// so minting a free one would take many tries
+pub const MIN_CODE_SPACE: u64 = 1_000_000;
+
+// How short invite codes are generated
+#[derive(Debug, Clone)]
+pub struct ShortCodeConfig {
+    alphabet: Vec<char>,
+    length: usize,
+}
+
+impl Default for ShortCodeConfig {
+    fn default() -> Self {
+        ShortCodeConfig {
+            alphabet: DEFAULT_ALPHABET.chars().collect(),
+            length: DEFAULT_LENGTH,
+        }
+    }
+}
+
+impl ShortCodeConfig {
+    pub fn new(alphabet: &str, length: usize) -> Result<Self, String> {
+        let mut chars: Vec<char> = alphabet.chars().map(|c| c.to_ascii_uppercase()).collect();
+        chars.sort_unstable();
+        chars.dedup();
+
+        if chars.len() < 2 {
+            return Err("Short code alphabet needs at least 2 distinct characters".to_string());
+        }
+        if chars.iter().any(|c| !c.is_ascii_alphanumeric()) {
+            return Err("Short code alphabet may only contain letters and digits".to_string());
+        }
+        if length < 4 {
+            return Err("Short code length must be at least 4".to_string());
+        }
+        let space = u32::try_from(length)
+            .ok()
+            .and_then(|length| (chars.len() as u64).checked_pow(length));
+        if space.is_some_and(|space| space < MIN_CODE_SPACE) {
+            return Err(format!(
+                "Short codes of {} characters from {} allow fewer than {} codes",
+                length,
+                chars.len(),
+                MIN_CODE_SPACE
+            ));
+        }
+
+        Ok(ShortCodeConfig {
+            alphabet: chars,
+            length,
+        })
+    }
+
+    pub fn generate(&self) -> String {
+        let mut rng = rand::thread_rng();
+        (0..self.length)
+            .map(|_| self.alphabet[rng.gen_range(0..self.alphabet.len())])
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_default_codes_use_unambiguous_alphabet() {
+        let config = ShortCodeConfig::default();
+        let code = config.generate();
+
+        assert_eq!(code.len(), DEFAULT_LENGTH);
+        assert!(code.chars().all(|c| DEFAULT_ALPHABET.contains(c)));
+        assert!(!code.contains(['0', '1', 'O', 'I']));
+    }
+
+    #[test]
+    fn test_custom_alphabet_and_length() {
+        let config = ShortCodeConfig::new("ab", 20).unwrap();
+        let code = config.generate();
+
+        assert_eq!(code.len(), 20);
+        assert!(code.chars().all(|c| c == 'A' || c == 'B'));
+        assert!(ShortCodeConfig::new("a", 20).is_err());
+        assert!(ShortCodeConfig::new("ab-", 20).is_err());
+        assert!(ShortCodeConfig::new("0123456789", 3).is_err());
+        // 2^8 codes would run out quickly
+        assert!(ShortCodeConfig::new("ab", 8).is_err());
+    }
+}
@@ -0,0 +1,93 @@
+use actix_web::web;
+use actix_web_actors::ws::CloseCode;
+use rand::Rng;
+use rdkafka::producer::{FutureProducer, Producer};
+use serde_json::json;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use crate::session::{CloseSession, ServerEvent};
+
+// Clients are told to wait a random time in this range before reconnecting,
+// so a restart doesn't bring every socket back at the same moment
+const RECONNECT_AFTER_MS: std::ops::Range<u64> = 1_000..10_000;
+
+// Publishes spawned by `send_to_redpanda` that haven't finished yet
+static IN_FLIGHT_PUBLISHES: AtomicUsize = AtomicUsize::new(0);
+
+// Held for the life of a spawned publish
+pub(crate) struct PublishGuard;
+
+impl PublishGuard {
+    pub(crate) fn new() -> Self {
+        IN_FLIGHT_PUBLISHES.fetch_add(1, Ordering::SeqCst);
+        PublishGuard
+    }
+}
+
+impl Drop for PublishGuard {
+    fn drop(&mut self) {
+        IN_FLIGHT_PUBLISHES.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+// Resolves on Ctrl-C or, on unix, SIGTERM
+pub async fn wait_for_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = terminate.recv() => {}
+                }
+                return;
+            }
+            Err(err) => tracing::warn!(error = %err, "Could not listen for SIGTERM"),
+        }
+    }
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+// What each socket is sent before it is closed
+pub(crate) fn going_away_notice() -> ServerEvent {
+    let reconnect_after_ms = rand::thread_rng().gen_range(RECONNECT_AFTER_MS);
+    ServerEvent::new("server_going_away", json!({ "reconnect_after_ms": reconnect_after_ms }))
+}
+
+pub(crate) fn going_away_close() -> CloseSession {
+    CloseSession {
+        code: CloseCode::Away,
+        reason: "Server going away".to_string(),
+    }
+}
+
+fn remaining(deadline: Instant) -> Duration {
+    deadline.saturating_duration_since(Instant::now())
+}
+
+// Let publishes already handed to the producer report back, up to `deadline`
+pub(crate) async fn wait_for_publishes(deadline: Instant) {
+    while IN_FLIGHT_PUBLISHES.load(Ordering::SeqCst) > 0 {
+        if Instant::now() >= deadline {
+            tracing::warn!(
+                in_flight = IN_FLIGHT_PUBLISHES.load(Ordering::SeqCst),
+                "Gave up waiting for Redpanda publishes"
+            );
+            return;
+        }
+        actix_web::rt::time::sleep(Duration::from_millis(50)).await;
+    }
+}
+
+// Deliver anything still queued in librdkafka, up to `deadline`
+pub(crate) async fn flush_producer(producer: web::Data<FutureProducer>, deadline: Instant) {
+    let timeout = remaining(deadline);
+    // Flushing blocks, so keep it off the runtime
+    match web::block(move || producer.flush(timeout)).await {
+        Ok(Ok(())) => tracing::info!("Flushed the Redpanda producer"),
+        Ok(Err(err)) => tracing::warn!(error = %err, "Redpanda producer did not flush in time"),
+        Err(err) => tracing::warn!(error = %err, "Could not flush the Redpanda producer"),
+    }
+}
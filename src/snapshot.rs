@@ -0,0 +1,81 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use crate::block::{BlockList, MuteList};
+use crate::connection::{Connection, Message};
+use crate::friend_request::FriendRequest;
+use crate::notification::Notification;
+use crate::profile::PlayerProfile;
+
+// The in-memory stores, saved on shutdown so a restart doesn't lose them
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Snapshot {
+    // Each connection once; link ids are re-added on load
+    pub connections: Vec<Connection>,
+    pub messages: HashMap<String, Vec<Message>>,
+    pub notifications: HashMap<String, Vec<Notification>>,
+    pub profiles: HashMap<String, PlayerProfile>,
+    pub labels: HashMap<String, HashMap<String, String>>,
+    pub friend_requests: HashMap<String, FriendRequest>,
+    pub blocks: BlockList,
+    pub mutes: MuteList,
+}
+
+impl Snapshot {
+    // None if nothing has been saved at `path` yet
+    pub fn load(path: &Path) -> Result<Option<Snapshot>, String> {
+        if !path.exists() {
+            return Ok(None);
+        }
+        let text = fs::read_to_string(path)
+            .map_err(|err| format!("Could not read snapshot {}: {}", path.display(), err))?;
+        serde_json::from_str(&text)
+            .map(Some)
+            .map_err(|err| format!("Invalid snapshot {}: {}", path.display(), err))
+    }
+
+    // Written beside the target and renamed, so a crash mid-write leaves the
+    // previous snapshot intact
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        let text = serde_json::to_string(self).map_err(|err| format!("Could not encode snapshot: {}", err))?;
+        let partial = path.with_extension("partial");
+        fs::write(&partial, text)
+            .and_then(|()| fs::rename(&partial, path))
+            .map_err(|err| format!("Could not write snapshot {}: {}", path.display(), err))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_snapshot_round_trip() {
+        let dir = std::env::temp_dir().join(format!("friends-connect-{}", uuid::Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("snapshot.json");
+        assert!(Snapshot::load(&path).unwrap().is_none());
+
+        let connection = Connection::new("player1".to_string());
+        let mut snapshot = Snapshot::default();
+        snapshot.connections.push(connection.clone());
+        snapshot
+            .notifications
+            .insert("player1".to_string(), vec![Notification::new("hello".to_string())]);
+        snapshot.blocks.block("player1", "player2");
+        snapshot.save(&path).unwrap();
+
+        let loaded = Snapshot::load(&path).unwrap().unwrap();
+        assert_eq!(loaded.connections.len(), 1);
+        assert_eq!(loaded.connections[0].id, connection.id);
+        assert_eq!(loaded.notifications["player1"].len(), 1);
+        assert!(loaded.blocks.has_blocked("player1", "player2"));
+
+        fs::write(&path, "not json").unwrap();
+        assert!(Snapshot::load(&path).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,82 @@
+use serde::Deserialize;
+use std::time::Duration;
+
+pub const DEFAULT_HEARTBEAT_INTERVAL_SECS: u64 = 30;
+pub const DEFAULT_CLIENT_TIMEOUT_SECS: u64 = 60;
+pub const DEFAULT_IDLE_TIMEOUT_SECS: u64 = 60 * 60;
+pub const DEFAULT_MAX_FRAME_BYTES: usize = 64 * 1024;
+pub const DEFAULT_MAX_SESSIONS_PER_PLAYER: usize = 5;
+
+// What to do when a player opens more sockets than allowed
+#[derive(Debug, Clone, Copy, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum SessionOverflow {
+    EvictOldest,
+    RejectNew,
+}
+
+// Timeouts and caps for WebSocket sessions
+#[derive(Debug, Clone)]
+pub struct SocketLimits {
+    // How often the server pings each socket
+    pub heartbeat_interval: Duration,
+    // How long without a pong before a socket is dropped
+    pub client_timeout: Duration,
+    // How long a socket may go without a frame from the client; None disables it
+    pub idle_timeout: Option<Duration>,
+    pub max_frame_bytes: usize,
+    pub max_sessions_per_player: usize,
+    pub session_overflow: SessionOverflow,
+}
+
+impl Default for SocketLimits {
+    fn default() -> Self {
+        SocketLimits {
+            heartbeat_interval: Duration::from_secs(DEFAULT_HEARTBEAT_INTERVAL_SECS),
+            client_timeout: Duration::from_secs(DEFAULT_CLIENT_TIMEOUT_SECS),
+            idle_timeout: Some(Duration::from_secs(DEFAULT_IDLE_TIMEOUT_SECS)),
+            max_frame_bytes: DEFAULT_MAX_FRAME_BYTES,
+            max_sessions_per_player: DEFAULT_MAX_SESSIONS_PER_PLAYER,
+            session_overflow: SessionOverflow::EvictOldest,
+        }
+    }
+}
+
+impl SocketLimits {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.heartbeat_interval.is_zero() {
+            return Err("websocket.heartbeat_interval_secs must be greater than 0".to_string());
+        }
+        if self.client_timeout <= self.heartbeat_interval {
+            return Err("websocket.client_timeout_secs must be longer than the heartbeat interval".to_string());
+        }
+        if self.max_frame_bytes == 0 {
+            return Err("websocket.max_frame_bytes must be greater than 0".to_string());
+        }
+        if self.max_sessions_per_player == 0 {
+            return Err("websocket.max_sessions_per_player must be greater than 0".to_string());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_defaults_are_valid() {
+        assert!(SocketLimits::default().validate().is_ok());
+    }
+
+    #[test]
+    fn test_timeout_must_outlast_heartbeat() {
+        let limits = SocketLimits {
+            heartbeat_interval: Duration::from_secs(30),
+            client_timeout: Duration::from_secs(30),
+            ..SocketLimits::default()
+        };
+
+        assert!(limits.validate().is_err());
+    }
+}
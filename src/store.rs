@@ -0,0 +1,59 @@
+use actix_web::http::StatusCode;
+use actix_web::{HttpResponse, ResponseError};
+use serde_json::json;
+use std::fmt;
+use std::sync::{LockResult, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+// A store whose lock was poisoned by a panic mid-update. Requests that need
+// it get a 500 rather than data that may be half written; the rest of the
+// server keeps serving.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StoreError {
+    pub store: &'static str,
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "The {} store is unavailable", self.store)
+    }
+}
+
+impl std::error::Error for StoreError {}
+
+impl ResponseError for StoreError {
+    fn status_code(&self) -> StatusCode {
+        StatusCode::INTERNAL_SERVER_ERROR
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        HttpResponse::InternalServerError().json(json!({
+            "error": "Internal server error"
+        }))
+    }
+}
+
+// Lets helpers that build their own error responses use `?` on a lock
+impl From<StoreError> for HttpResponse {
+    fn from(err: StoreError) -> Self {
+        err.error_response()
+    }
+}
+
+fn poisoned(store: &'static str) -> StoreError {
+    tracing::error!(store, "Store lock is poisoned");
+    StoreError { store }
+}
+
+pub fn read<'a, T>(lock: &'a RwLock<T>, store: &'static str) -> Result<RwLockReadGuard<'a, T>, StoreError> {
+    lock.read().map_err(|_| poisoned(store))
+}
+
+pub fn write<'a, T>(lock: &'a RwLock<T>, store: &'static str) -> Result<RwLockWriteGuard<'a, T>, StoreError> {
+    lock.write().map_err(|_| poisoned(store))
+}
+
+// For bookkeeping that stays usable after a panic, like rate limit buckets
+// and session lists, where refusing every later request would be worse
+pub fn recover<G>(result: LockResult<G>) -> G {
+    result.unwrap_or_else(PoisonError::into_inner)
+}
@@ -0,0 +1,108 @@
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::{web, Error};
+use serde::Deserialize;
+use std::collections::HashMap;
+use tracing::field::Empty;
+use tracing::Span;
+use tracing_actix_web::{DefaultRootSpanBuilder, RootSpanBuilder};
+use tracing_subscriber::EnvFilter;
+
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    // Human readable lines
+    #[default]
+    Text,
+    // One JSON object per line, with the enclosing spans' fields
+    Json,
+}
+
+// Install the global subscriber. RUST_LOG picks the levels and defaults to info.
+pub fn init(format: LogFormat) {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    let builder = tracing_subscriber::fmt().with_env_filter(filter);
+    let result = match format {
+        LogFormat::Text => builder.try_init(),
+        LogFormat::Json => builder
+            .json()
+            .with_current_span(true)
+            .with_span_list(true)
+            .try_init(),
+    };
+    if let Err(err) = result {
+        eprintln!("Logging was already set up: {}", err);
+    }
+}
+
+// The player and connection a request is about, from its path, the
+// X-Player-Id header or the `player_id` query parameter
+fn request_subjects(request: &ServiceRequest) -> (Option<String>, Option<String>) {
+    let segments: Vec<&str> = request.path().trim_matches('/').split('/').collect();
+
+    let connection_id = match segments.as_slice() {
+        ["connections", "link", ..] => None,
+        ["connections", id, ..] => Some(id.to_string()),
+        _ => None,
+    };
+    let player_id = match segments.as_slice() {
+        ["players", id, ..] => Some(id.to_string()),
+        _ => request
+            .headers()
+            .get("X-Player-Id")
+            .and_then(|value| value.to_str().ok())
+            .map(str::to_string)
+            .or_else(|| {
+                web::Query::<HashMap<String, String>>::from_query(request.query_string())
+                    .ok()
+                    .and_then(|query| query.get("player_id").cloned())
+            }),
+    };
+
+    (player_id, connection_id)
+}
+
+// Request spans carry the request id plus the player and connection involved
+pub struct RequestSpan;
+
+impl RootSpanBuilder for RequestSpan {
+    fn on_request_start(request: &ServiceRequest) -> Span {
+        let span = tracing_actix_web::root_span!(request, player_id = Empty, connection_id = Empty);
+        let (player_id, connection_id) = request_subjects(request);
+        if let Some(player_id) = player_id {
+            span.record("player_id", player_id.as_str());
+        }
+        if let Some(connection_id) = connection_id {
+            span.record("connection_id", connection_id.as_str());
+        }
+        span
+    }
+
+    fn on_request_end<B: MessageBody>(span: Span, outcome: &Result<ServiceResponse<B>, Error>) {
+        DefaultRootSpanBuilder::on_request_end(span, outcome);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn test_request_subjects() {
+        let req = TestRequest::post().uri("/connections/abc/messages").to_srv_request();
+        assert_eq!(request_subjects(&req), (None, Some("abc".to_string())));
+
+        let req = TestRequest::get()
+            .uri("/connections/link/XYZ234")
+            .insert_header(("X-Player-Id", "player1"))
+            .to_srv_request();
+        assert_eq!(request_subjects(&req), (Some("player1".to_string()), None));
+
+        let req = TestRequest::get().uri("/ws?player_id=player2").to_srv_request();
+        assert_eq!(request_subjects(&req), (Some("player2".to_string()), None));
+
+        let req = TestRequest::get().uri("/players/player3/presence").to_srv_request();
+        assert_eq!(request_subjects(&req), (Some("player3".to_string()), None));
+    }
+}
@@ -1,19 +1,48 @@
-use actix::{Actor, StreamHandler, AsyncContext, ActorContext};
+use actix::{Actor, StreamHandler, AsyncContext, ActorContext, Handler};
 use actix_web::{web, Error, HttpRequest, HttpResponse};
 use actix_web_actors::ws;
-use rdkafka::producer::{FutureProducer, FutureRecord};
+use rdkafka::consumer::{CommitMode, Consumer, ConsumerContext, StreamConsumer};
+use rdkafka::producer::FutureProducer;
+use rdkafka::statistics::Statistics;
+use rdkafka::ClientContext;
+use rdkafka::error::KafkaResult;
 use rdkafka::ClientConfig;
 use serde::{Deserialize, Serialize};
 use std::time::{Duration, Instant};
 use uuid::Uuid;
 use std::collections::HashMap;
-use std::sync::RwLock;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock, RwLock};
+use tracing::field::Empty;
+use tracing::Span;
+
+use crate::block::{BlockList, MuteList};
+use crate::config::KafkaClientSettings;
+use crate::connection::{Connection, Message as ChatMessage};
+use crate::encoding::{Encoding, PROTOCOLS};
+use crate::health::Health;
+use crate::message::{deliver_message, mark_read, send_receipts, Outbox, ReceiptKind};
+use crate::message_limits::MessageLimits;
+use crate::metrics::metrics;
+use crate::moderation::FilterChain;
+use crate::rate_limit::{Budget, RateLimits};
+use crate::notification::{push_notification, Notification};
+use crate::presence::announce_presence;
+use crate::profile::PlayerProfile;
+use crate::server::send_to_redpanda;
+use crate::session::{CloseSession, Registration, Resume, ServerEvent, SessionRegistry};
+use crate::socket_limits::SocketLimits;
+use crate::store::{self, StoreError};
 
 // WebSocket message types
 #[derive(Serialize, Deserialize)]
 struct WsMessage {
     event_type: String,
     payload: serde_json::Value,
+    // Set on events pushed by the server, so clients can resume after a drop;
+    // replies to the client's own frames have none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    seq: Option<u64>,
 }
 
 // WebSocket connection actor
@@ -22,46 +51,327 @@ struct WebSocketConnection {
     player_id: String,
     connection_id: Option<String>,
     heartbeat: Instant,
-    producer: FutureProducer,
+    // Last frame the client sent, for the idle cutoff
+    last_active: Instant,
+    // False if the session cap turned this socket away
+    registered: bool,
+    // Carries the session, player and joined connection on every log line
+    span: Span,
+    limits: SocketLimits,
+    // How frames to and from the client are encoded
+    encoding: Encoding,
+    // None if the producer couldn't be created; the session works without events
+    producer: Option<FutureProducer>,
+    message_limits: MessageLimits,
+    moderation: web::Data<FilterChain>,
+    rate_limits: web::Data<RateLimits>,
+    sessions: web::Data<SessionRegistry>,
+    connections: web::Data<RwLock<HashMap<String, Connection>>>,
+    messages: web::Data<RwLock<HashMap<String, Vec<ChatMessage>>>>,
+    notifications: web::Data<RwLock<HashMap<String, Vec<Notification>>>>,
+    profiles: web::Data<RwLock<HashMap<String, PlayerProfile>>>,
+    blocks: web::Data<RwLock<BlockList>>,
+    mutes: web::Data<RwLock<MuteList>>,
 }
 
 impl WebSocketConnection {
-    pub fn new(player_id: String, redpanda_config: RedpandaConfig) -> Self {
-        let producer: FutureProducer = ClientConfig::new()
-            .set("bootstrap.servers", &redpanda_config.bootstrap_servers)
-            .set("sasl.mechanism", "SCRAM-SHA-256")
-            .set("security.protocol", "SASL_SSL")
-            .set("sasl.username", &redpanda_config.username)
-            .set("sasl.password", &redpanda_config.password)
-            .create()
-            .expect("Producer creation error");
+    pub fn new(
+        player_id: String,
+        redpanda_config: RedpandaConfig,
+        message_limits: MessageLimits,
+        moderation: web::Data<FilterChain>,
+        rate_limits: web::Data<RateLimits>,
+        sessions: web::Data<SessionRegistry>,
+        connections: web::Data<RwLock<HashMap<String, Connection>>>,
+        messages: web::Data<RwLock<HashMap<String, Vec<ChatMessage>>>>,
+        notifications: web::Data<RwLock<HashMap<String, Vec<Notification>>>>,
+        profiles: web::Data<RwLock<HashMap<String, PlayerProfile>>>,
+        blocks: web::Data<RwLock<BlockList>>,
+        mutes: web::Data<RwLock<MuteList>>,
+        limits: SocketLimits,
+        encoding: Encoding,
+    ) -> Self {
+        let id = Uuid::new_v4().to_string();
+        // Created inside the upgrade request's span, so it keeps the request id
+        let span = tracing::info_span!(
+            "ws_session",
+            session_id = %id,
+            player_id = %player_id,
+            connection_id = Empty,
+        );
+
+        let producer = match redpanda_config.client_config().create() {
+            Ok(producer) => Some(producer),
+            Err(err) => {
+                tracing::warn!(
+                    parent: &span,
+                    error = %err,
+                    "Failed to create Redpanda producer; this session's events won't be published"
+                );
+                None
+            }
+        };
 
         Self {
-            id: Uuid::new_v4().to_string(),
+            id,
             player_id,
             connection_id: None,
             heartbeat: Instant::now(),
+            last_active: Instant::now(),
+            registered: false,
+            span,
+            limits,
+            encoding,
             producer,
+            message_limits,
+            moderation,
+            rate_limits,
+            sessions,
+            connections,
+            messages,
+            notifications,
+            profiles,
+            blocks,
+            mutes,
         }
     }
 
-    // Send a message to Redpanda
-    fn send_to_redpanda(&self, topic: &str, key: &str, payload: &str) {
-        let producer = self.producer.clone();
-        let topic = topic.to_owned();
-        let key = key.to_owned();
-        let payload = payload.to_owned();
-
-        actix_web::rt::spawn(async move {
-            let record = FutureRecord::to(&topic)
-                .key(&key)
-                .payload(&payload);
-
-            match producer.send(record, Duration::from_secs(1)).await {
-                Ok(_) => (),
-                Err((err, _)) => eprintln!("Error sending to Redpanda: {:?}", err),
+    // Send a close frame with a reason and stop the actor
+    fn close(&self, ctx: &mut ws::WebsocketContext<Self>, code: ws::CloseCode, reason: &str) {
+        ctx.close(Some(ws::CloseReason {
+            code,
+            description: Some(reason.to_string()),
+        }));
+        ctx.stop();
+    }
+
+    fn send_error(&self, ctx: &mut ws::WebsocketContext<Self>, payload: serde_json::Value) {
+        self.send_event(ctx, ServerEvent::new("error", payload));
+    }
+
+    fn send_event(&self, ctx: &mut ws::WebsocketContext<Self>, event: ServerEvent) {
+        let message = WsMessage {
+            event_type: event.event_type,
+            payload: event.payload,
+            seq: event.seq,
+        };
+        match self.encoding.encode(&message) {
+            Ok(bytes) if self.encoding.is_binary() => ctx.binary(bytes),
+            Ok(bytes) => ctx.text(String::from_utf8(bytes).unwrap_or_default()),
+            Err(err) => tracing::error!(event_type = %message.event_type, error = %err, "Failed to encode event"),
+        }
+    }
+
+    // Tell the player's contacts if their presence changed
+    fn announce_presence(&self) {
+        // A poisoned store was already logged; contacts just miss this update
+        let _ = announce_presence(&self.sessions, &self.limits, &self.connections, &self.blocks, &self.player_id);
+    }
+
+    // Relay a typing frame to the other members of a connection the player is in
+    fn relay_typing(&self, event_type: &str, connection_id: &str) {
+        let connection = match store::read(&self.connections, "connections") {
+            Ok(conn_map) => match conn_map.get(connection_id) {
+                Some(conn) if conn.id == connection_id && conn.players.contains(&self.player_id) => conn.clone(),
+                _ => return,
+            },
+            Err(_) => return,
+        };
+        let blocks = match store::read(&self.blocks, "blocks") {
+            Ok(blocks) => blocks,
+            Err(_) => return,
+        };
+        let event = ServerEvent::new(event_type, serde_json::json!({
+            "connection_id": connection_id,
+            "player_id": self.player_id,
+        }));
+        for player in &connection.players {
+            if player != &self.player_id && !blocks.between(player, &self.player_id) {
+                self.sessions.send_ephemeral(player, event.clone());
             }
-        });
+        }
+    }
+
+    // Rate limit, decode and dispatch a frame; text frames are always JSON
+    fn receive(&mut self, bytes: &[u8], encoding: Encoding, ctx: &mut ws::WebsocketContext<Self>) {
+        // Frames share one budget per player across all their sockets
+        if let Err(retry_after) = self.rate_limits.check_player(Budget::WsFrame, &self.player_id) {
+            self.send_error(ctx, serde_json::json!({
+                "error": "Too many requests",
+                "code": "rate_limited",
+                "retry_after": retry_after.as_secs().max(1),
+            }));
+            return;
+        }
+        self.last_active = Instant::now();
+        self.sessions.touch(&self.player_id, &self.id, true);
+        // Coming back from idle
+        self.announce_presence();
+        
+        match encoding.decode::<WsMessage>(bytes) {
+            Ok(ws_msg) => self.handle_frame(ws_msg, ctx),
+            Err(err) => self.send_error(ctx, serde_json::json!({
+                "error": format!("Invalid frame: {}", err),
+                "code": "invalid_frame",
+            })),
+        }
+    }
+
+    // Act on one decoded frame from the client
+    fn handle_frame(&mut self, ws_msg: WsMessage, ctx: &mut ws::WebsocketContext<Self>) {
+        match ws_msg.event_type.as_str() {
+            "join_connection" => {
+                if let Some(conn_id) = ws_msg.payload.get("connection_id") {
+                    if let Some(conn_id_str) = conn_id.as_str() {
+                        self.connection_id = Some(conn_id_str.to_owned());
+                        self.span.record("connection_id", conn_id_str);
+
+                        // Send join event to Redpanda
+                        let join_event = serde_json::json!({
+                            "event": "join_connection",
+                            "player_id": self.player_id,
+                            "connection_id": conn_id_str,
+                            "timestamp": chrono::Utc::now().timestamp(),
+                        });
+
+                        self.send_to_redpanda(
+                            "connection-events", 
+                            conn_id_str,
+                            &join_event.to_string()
+                        );
+                    }
+                }
+            }
+            "send_message" => {
+                // Same path as the HTTP endpoint; defaults to the connection this socket joined
+                let conn_id = ws_msg
+                    .payload
+                    .get("connection_id")
+                    .and_then(|c| c.as_str())
+                    .map(str::to_owned)
+                    .or_else(|| self.connection_id.clone());
+                let content = ws_msg.payload.get("content").and_then(|c| c.as_str());
+
+                if let (Some(conn_id), Some(content)) = (conn_id, content) {
+                    let outbox = Outbox {
+                        connections: &self.connections,
+                        messages: &self.messages,
+                        notifications: &self.notifications,
+                        profiles: &self.profiles,
+                        blocks: &self.blocks,
+                        mutes: &self.mutes,
+                        sessions: &self.sessions,
+                        message_limits: &self.message_limits,
+                        moderation: &self.moderation,
+                        rate_limits: &self.rate_limits,
+                        producer: self.producer.as_ref(),
+                    };
+                    let sent = {
+                        let _entered = self.span.enter();
+                        deliver_message(&outbox, &conn_id, &self.player_id, content, "websocket")
+                    };
+                    match sent {
+                        Ok(message) => self.send_event(ctx, ServerEvent::new("message_sent", serde_json::json!({
+                            "connection_id": conn_id,
+                            "message": message,
+                        }))),
+                        Err(err) => self.send_error(ctx, err.to_json()),
+                    }
+                }
+            }
+            "resume" => {
+                // Replayed events may arrive after newer live ones;
+                // clients should order by seq and skip ones they've seen
+                let last_seq = ws_msg.payload.get("last_seq").and_then(|s| s.as_u64()).unwrap_or(0);
+                let stream = ws_msg.payload.get("stream").and_then(|s| s.as_str());
+                match self.sessions.resume(&self.player_id, stream, last_seq) {
+                    Resume::Replay(events) => {
+                        let replayed = events.len();
+                        for event in events {
+                            self.send_event(ctx, event);
+                        }
+                        self.send_event(ctx, ServerEvent::new("resumed", serde_json::json!({
+                            "replayed": replayed,
+                            "stream": self.sessions.stream(&self.player_id),
+                            "last_seq": self.sessions.last_seq(&self.player_id),
+                        })));
+                    }
+                    Resume::Resync => {
+                        self.send_event(ctx, ServerEvent::new("resync_required", serde_json::json!({
+                            "stream": self.sessions.stream(&self.player_id),
+                            "last_seq": self.sessions.last_seq(&self.player_id),
+                        })));
+                    }
+                }
+            }
+            "typing_start" | "typing_stop" => {
+                // Ephemeral: relayed to members, never stored or published
+                let conn_id = ws_msg
+                    .payload
+                    .get("connection_id")
+                    .and_then(|c| c.as_str())
+                    .map(str::to_owned)
+                    .or_else(|| self.connection_id.clone());
+                if let Some(conn_id) = conn_id {
+                    self.relay_typing(&ws_msg.event_type, &conn_id);
+                }
+            }
+            "set_presence" => {
+                let away = match ws_msg.payload.get("status").and_then(|s| s.as_str()) {
+                    Some("away") => true,
+                    Some("online") => false,
+                    _ => {
+                        self.send_error(ctx, serde_json::json!({
+                            "error": "Invalid presence status"
+                        }));
+                        return;
+                    }
+                };
+
+                self.sessions.set_away(&self.player_id, &self.id, away);
+                self.announce_presence();
+            }
+            "mark_read" => {
+                // Defaults to the connection this socket joined
+                let conn_id = ws_msg
+                    .payload
+                    .get("connection_id")
+                    .and_then(|c| c.as_str())
+                    .map(str::to_owned)
+                    .or_else(|| self.connection_id.clone());
+                let message_id = ws_msg.payload.get("message_id").and_then(|m| m.as_str());
+
+                if let (Some(conn_id), Some(message_id)) = (conn_id, message_id) {
+                    let at = chrono::Utc::now().timestamp();
+                    let marked = mark_read(&self.connections, &self.messages, &self.blocks, &conn_id, &self.player_id, message_id, at)
+                        .and_then(|marked| Ok((marked, store::read(&self.blocks, "blocks")?)));
+                    match marked {
+                        Ok((marked, blocks)) => send_receipts(
+                            &self.sessions,
+                            &blocks,
+                            self.producer.as_ref(),
+                            &conn_id,
+                            &self.player_id,
+                            ReceiptKind::Read,
+                            &marked,
+                            at,
+                        ),
+                        Err(err) => self.send_error(ctx, err.to_json()),
+                    }
+                }
+            }
+            _ => {
+                tracing::debug!(event_type = %ws_msg.event_type, "Ignoring unknown event type");
+            }
+        }
+    }
+
+    // Send a message to Redpanda, logged under this session's span
+    fn send_to_redpanda(&self, topic: &str, key: &str, payload: &str) {
+        if let Some(producer) = &self.producer {
+            let _entered = self.span.enter();
+            send_to_redpanda(producer, topic, key, payload);
+        }
     }
 }
 
@@ -69,8 +379,39 @@ impl Actor for WebSocketConnection {
     type Context = ws::WebsocketContext<Self>;
 
     fn started(&mut self, ctx: &mut Self::Context) {
+        let _entered = self.span.clone().entered();
+        // Let HTTP handlers push events to this socket
+        let registration = self.sessions.register(
+            &self.player_id,
+            &self.id,
+            ctx.address().recipient(),
+            ctx.address().recipient(),
+            &self.limits,
+        );
+        match registration {
+            Registration::Accepted { .. } => {
+                self.registered = true;
+                self.announce_presence();
+            }
+            Registration::Rejected => {
+                tracing::info!("Refusing WebSocket session over the per-player cap");
+                self.close(ctx, ws::CloseCode::Policy, "Too many sessions");
+                return;
+            }
+        }
+        
+        tracing::info!(encoding = ?self.encoding, "WebSocket session opened");
+
         // Start heartbeat process
         self.heartbeat(ctx);
+        
+        // Tell the client where its event sequence stands, for a later resume;
+        // resumes name the stream so sequences from a dropped one are refused
+        self.send_event(ctx, ServerEvent::new("hello", serde_json::json!({
+            "session_id": self.id,
+            "stream": self.sessions.stream(&self.player_id),
+            "last_seq": self.sessions.last_seq(&self.player_id),
+        })));
 
         // Announce user connection
         let connection_event = serde_json::json!({
@@ -87,6 +428,16 @@ impl Actor for WebSocketConnection {
     }
 
     fn stopped(&mut self, _: &mut Self::Context) {
+        if !self.registered {
+            return;
+        }
+        let _entered = self.span.clone().entered();
+        tracing::info!("WebSocket session closed");
+        
+        // Closing one of several sockets can still change presence
+        self.sessions.unregister(&self.player_id, &self.id);
+        self.announce_presence();
+        
         // Announce user disconnection
         let connection_event = serde_json::json!({
             "event": "user_disconnected",
@@ -102,70 +453,48 @@ impl Actor for WebSocketConnection {
     }
 }
 
+// Events pushed to this socket by the rest of the server
+impl Handler<ServerEvent> for WebSocketConnection {
+    type Result = ();
+
+    fn handle(&mut self, event: ServerEvent, ctx: &mut Self::Context) {
+        self.send_event(ctx, event);
+    }
+}
+
+impl Handler<CloseSession> for WebSocketConnection {
+    type Result = ();
+
+    fn handle(&mut self, close: CloseSession, ctx: &mut Self::Context) {
+        let _entered = self.span.clone().entered();
+        tracing::info!(reason = %close.reason, "Closing WebSocket session");
+        self.close(ctx, close.code, &close.reason);
+    }
+}
+
 // Handler for WebSocket messages
 impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WebSocketConnection {
     fn handle(&mut self, msg: Result<ws::Message, ws::ProtocolError>, ctx: &mut Self::Context) {
+        let _entered = self.span.clone().entered();
         match msg {
             Ok(ws::Message::Ping(msg)) => ctx.pong(&msg),
             Ok(ws::Message::Pong(_)) => {
                 self.heartbeat = Instant::now();
+                self.sessions.touch(&self.player_id, &self.id, false);
             }
-            Ok(ws::Message::Text(text)) => {
-                // Try to parse the message
-                if let Ok(ws_msg) = serde_json::from_str::<WsMessage>(&text) {
-                    match ws_msg.event_type.as_str() {
-                        "join_connection" => {
-                            if let Some(conn_id) = ws_msg.payload.get("connection_id") {
-                                if let Some(conn_id_str) = conn_id.as_str() {
-                                    self.connection_id = Some(conn_id_str.to_owned());
-                                    
-                                    // Send join event to Redpanda
-                                    let join_event = serde_json::json!({
-                                        "event": "join_connection",
-                                        "player_id": self.player_id,
-                                        "connection_id": conn_id_str,
-                                        "timestamp": chrono::Utc::now().timestamp(),
-                                    });
-                                    
-                                    self.send_to_redpanda(
-                                        "connection-events", 
-                                        conn_id_str,
-                                        &join_event.to_string()
-                                    );
-                                }
-                            }
-                        }
-                        "send_message" => {
-                            if let (Some(content), Some(conn_id)) = (
-                                ws_msg.payload.get("content").and_then(|c| c.as_str()),
-                                self.connection_id.as_ref(),
-                            ) {
-                                let message_event = serde_json::json!({
-                                    "event": "new_message",
-                                    "connection_id": conn_id,
-                                    "player_id": self.player_id,
-                                    "content": content,
-                                    "timestamp": chrono::Utc::now().timestamp(),
-                                });
-                                
-                                self.send_to_redpanda(
-                                    "connection-messages",
-                                    conn_id,
-                                    &message_event.to_string(),
-                                );
-                            }
-                        }
-                        _ => {
-                            eprintln!("Unknown event type: {}", ws_msg.event_type);
-                        }
-                    }
-                }
-            }
-            Ok(ws::Message::Binary(_)) => println!("Binary message received"),
+            Ok(ws::Message::Text(text)) => self.receive(text.as_bytes(), Encoding::Json, ctx),
+            Ok(ws::Message::Binary(bytes)) => self.receive(&bytes, self.encoding, ctx),
             Ok(ws::Message::Close(reason)) => {
                 ctx.close(reason);
                 ctx.stop();
             }
+            Err(ws::ProtocolError::Overflow) => {
+                self.close(ctx, ws::CloseCode::Size, "Frame too large");
+            }
+            Err(err) => {
+                tracing::warn!(error = %err, "WebSocket protocol error");
+                self.close(ctx, ws::CloseCode::Protocol, &err.to_string());
+            }
             _ => ctx.stop(),
         }
     }
@@ -173,23 +502,53 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WebSocketConnecti
 
 impl WebSocketConnection {
     fn heartbeat(&self, ctx: &mut ws::WebsocketContext<Self>) {
-        ctx.run_interval(Duration::from_secs(30), |act, ctx| {
-            if Instant::now().duration_since(act.heartbeat) > Duration::from_secs(60) {
-                println!("WebSocket heartbeat failed, disconnecting!");
-                ctx.stop();
+        ctx.run_interval(self.limits.heartbeat_interval, |act, ctx| {
+            let _entered = act.span.clone().entered();
+            if Instant::now().duration_since(act.heartbeat) > act.limits.client_timeout {
+                tracing::info!("WebSocket heartbeat failed, disconnecting");
+                act.close(ctx, ws::CloseCode::Away, "Heartbeat timeout");
                 return;
             }
+            if let Some(idle_timeout) = act.limits.idle_timeout {
+                if Instant::now().duration_since(act.last_active) > idle_timeout {
+                    act.close(ctx, ws::CloseCode::Normal, "Idle timeout");
+                    return;
+                }
+            }
             ctx.ping(b"");
         });
     }
 }
 
-// Redpanda configuration
-#[derive(Clone)]
+// Redpanda configuration for one role
+#[derive(Debug, Clone)]
 pub struct RedpandaConfig {
     pub bootstrap_servers: String,
-    pub username: String,
-    pub password: String,
+    pub client: KafkaClientSettings,
+}
+
+impl RedpandaConfig {
+    pub fn client_config(&self) -> ClientConfig {
+        let client = &self.client;
+        let protocol = client.effective_security_protocol();
+        let mut config = ClientConfig::new();
+        config
+            .set("bootstrap.servers", &self.bootstrap_servers)
+            .set("security.protocol", protocol.as_str());
+        if let Some(client_id) = &client.client_id {
+            config.set("client.id", client_id);
+        }
+        if protocol.uses_sasl() {
+            config
+                .set("sasl.mechanism", client.sasl_mechanism.as_str())
+                .set("sasl.username", &client.username)
+                .set("sasl.password", &client.password);
+        }
+        for (name, value) in &client.properties {
+            config.set(name, value);
+        }
+        config
+    }
 }
 
 // WebSocket connection handler
@@ -198,70 +557,224 @@ pub async fn ws_route(
     stream: web::Payload,
     query: web::Query<HashMap<String, String>>,
     redpanda_config: web::Data<RedpandaConfig>,
+    message_limits: web::Data<MessageLimits>,
+    moderation: web::Data<FilterChain>,
+    rate_limits: web::Data<RateLimits>,
+    sessions: web::Data<SessionRegistry>,
+    connections: web::Data<RwLock<HashMap<String, Connection>>>,
+    messages: web::Data<RwLock<HashMap<String, Vec<ChatMessage>>>>,
+    notifications: web::Data<RwLock<HashMap<String, Vec<Notification>>>>,
+    profiles: web::Data<RwLock<HashMap<String, PlayerProfile>>>,
+    blocks: web::Data<RwLock<BlockList>>,
+    mutes: web::Data<RwLock<MuteList>>,
+    socket_limits: web::Data<SocketLimits>,
 ) -> Result<HttpResponse, Error> {
+    let encoding = match Encoding::negotiate(&req, query.get("encoding").map(String::as_str)) {
+        Ok(encoding) => encoding,
+        Err(err) => return Ok(HttpResponse::BadRequest().json(serde_json::json!({ "error": err }))),
+    };
+    
     // Extract player_id from query params
     let player_id = query.get("player_id").cloned().unwrap_or_else(|| {
         Uuid::new_v4().to_string() // Generate a temp ID if none provided
     });
     
     // Create the WebSocket connection
-    let ws = WebSocketConnection::new(player_id, redpanda_config.get_ref().clone());
+    let ws = WebSocketConnection::new(
+        player_id,
+        redpanda_config.get_ref().clone(),
+        message_limits.get_ref().clone(),
+        moderation,
+        rate_limits,
+        sessions,
+        connections,
+        messages,
+        notifications,
+        profiles,
+        blocks,
+        mutes,
+        socket_limits.get_ref().clone(),
+        encoding,
+    );
     
     // Start the WebSocket connection
-    ws::start(ws, &req, stream)
+    ws::WsResponseBuilder::new(ws, &req, stream)
+        .protocols(&PROTOCOLS)
+        .frame_size(socket_limits.max_frame_bytes)
+        .start()
 }
 
-// Function to set up Redpanda consumer for notifications
+// Reports the notification consumer's statistics as metrics
+struct NotificationContext;
+
+impl ClientContext for NotificationContext {
+    fn stats(&self, statistics: Statistics) {
+        metrics().consumer_statistics(&statistics);
+    }
+}
+
+impl ConsumerContext for NotificationContext {}
+
+// Retries of the notification consumer back off from a second up to a minute
+const CONSUMER_RETRY_MIN: Duration = Duration::from_secs(1);
+const CONSUMER_RETRY_MAX: Duration = Duration::from_secs(60);
+
+// How long to wait after `failures` failed attempts in a row
+fn retry_delay(failures: u32) -> Duration {
+    CONSUMER_RETRY_MIN
+        .saturating_mul(2u32.saturating_pow(failures.saturating_sub(1)))
+        .min(CONSUMER_RETRY_MAX)
+}
+
+// The running notification consumer, kept so shutdown can commit where it got to
+pub struct NotificationConsumer {
+    // Empty until the consumer has been created and subscribed
+    consumer: Arc<OnceLock<StreamConsumer<NotificationContext>>>,
+    stopping: Arc<AtomicBool>,
+}
+
+impl NotificationConsumer {
+    // Stop taking messages and synchronously commit the offsets stored for
+    // those handled
+    pub async fn shutdown(self) {
+        self.stopping.store(true, Ordering::SeqCst);
+        let slot = self.consumer;
+        let committed = web::block(move || {
+            slot.get().map(|consumer| {
+                let result = consumer.commit_consumer_state(CommitMode::Sync);
+                consumer.unsubscribe();
+                result
+            })
+        })
+        .await;
+        match committed {
+            Ok(Some(Ok(()))) => tracing::info!("Committed notification consumer offsets"),
+            Ok(None) => tracing::info!("Notification consumer never started, nothing to commit"),
+            Ok(Some(Err(err))) => tracing::warn!(error = %err, "Could not commit notification consumer offsets"),
+            Err(err) => tracing::warn!(error = %err, "Could not commit notification consumer offsets"),
+        }
+    }
+}
+
+fn create_notification_consumer(client_config: &ClientConfig) -> KafkaResult<StreamConsumer<NotificationContext>> {
+    let consumer: StreamConsumer<NotificationContext> = client_config.create_with_context(NotificationContext)?;
+    consumer.subscribe(&["user-notifications"])?;
+    Ok(consumer)
+}
+
+// Queue the notification carried by a user-notifications message; payloads
+// that aren't a notification are skipped
+fn queue_notification(
+    notifications: &RwLock<HashMap<String, Vec<Notification>>>,
+    payload: Option<&[u8]>,
+) -> Result<(), StoreError> {
+    if let Some(payload) = payload {
+        if let Ok(payload_str) = std::str::from_utf8(payload) {
+            if let Ok(notification) = serde_json::from_str::<serde_json::Value>(payload_str) {
+                if let (Some(player_id), Some(content)) = (
+                    notification.get("player_id").and_then(|id| id.as_str()),
+                    notification.get("content").and_then(|c| c.as_str()),
+                ) {
+                    // Who and what it is about, so deleting a player can find it
+                    let about = |field: &str| notification.get(field).and_then(|v| v.as_str()).map(str::to_owned);
+                    push_notification(
+                        &mut *store::write(notifications, "notifications")?,
+                        player_id,
+                        Notification {
+                            player_id: about("from_player_id"),
+                            connection_id: about("connection_id"),
+                            ..Notification::new(content.to_owned())
+                        },
+                    );
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// Start consuming notifications in the background. Failures to connect or
+// receive are retried with backoff and reported through readiness rather
+// than taking the server down.
 pub async fn setup_notification_consumer(
     redpanda_config: RedpandaConfig,
-    notifications: web::Data<RwLock<HashMap<String, Vec<String>>>>,
-) {
-    use rdkafka::config::ClientConfig;
-    use rdkafka::consumer::{CommitMode, Consumer, StreamConsumer};
+    notifications: web::Data<RwLock<HashMap<String, Vec<Notification>>>>,
+    health: web::Data<Health>,
+) -> NotificationConsumer {
     use rdkafka::message::Message;
     
-    let consumer: StreamConsumer = ClientConfig::new()
-        .set("bootstrap.servers", &redpanda_config.bootstrap_servers)
-        .set("sasl.mechanism", "SCRAM-SHA-256")
-        .set("security.protocol", "SASL_SSL")
-        .set("sasl.username", &redpanda_config.username)
-        .set("sasl.password", &redpanda_config.password)
-        .set("group.id", "friends-connect-server")
-        .set("auto.offset.reset", "earliest")
-        .create()
-        .expect("Consumer creation failed");
-    
-    consumer
-        .subscribe(&["user-notifications"])
-        .expect("Topic subscription failed");
+    let mut client_config = redpanda_config.client_config();
+    // Extra properties may pick another group or offset policy
+    for (name, value) in [
+        ("group.id", "friends-connect-server"),
+        ("auto.offset.reset", "earliest"),
+        // How often librdkafka reports statistics, including consumer lag
+        ("statistics.interval.ms", "15000"),
+    ] {
+        if client_config.get(name).is_none() {
+            client_config.set(name, value);
+        }
+    }
+    // Offsets are stored by hand once a notification is queued, so commits
+    // never move past one that was received but not handled
+    client_config.set("enable.auto.offset.store", "false");
     
+    let slot = Arc::new(OnceLock::new());
+    let stopping = Arc::new(AtomicBool::new(false));
+    let handle = NotificationConsumer {
+        consumer: slot.clone(),
+        stopping: stopping.clone(),
+    };
     actix_web::rt::spawn(async move {
+        let mut failures = 0;
+        let consumer = loop {
+            if stopping.load(Ordering::SeqCst) {
+                return;
+            }
+            match create_notification_consumer(&client_config) {
+                Ok(consumer) => break slot.get_or_init(|| consumer),
+                Err(err) => {
+                    failures += 1;
+                    let delay = retry_delay(failures);
+                    tracing::error!(error = %err, retry_in_secs = delay.as_secs(), "Failed to start the notification consumer");
+                    health.set_consumer_subscribed(false);
+                    actix_web::rt::time::sleep(delay).await;
+                }
+            }
+        };
+        health.set_consumer_subscribed(true);
+        failures = 0;
+
         loop {
-            match consumer.recv().await {
+            let received = consumer.recv().await;
+            // Anything arriving during shutdown is left for the next consumer;
+            // its offset was never stored, so the final commit stops before it
+            if stopping.load(Ordering::SeqCst) {
+                break;
+            }
+            match received {
                 Ok(msg) => {
-                    if let Some(payload) = msg.payload() {
-                        if let Ok(payload_str) = std::str::from_utf8(payload) {
-                            if let Ok(notification) = serde_json::from_str::<serde_json::Value>(payload_str) {
-                                if let (Some(player_id), Some(content)) = (
-                                    notification.get("player_id").and_then(|id| id.as_str()),
-                                    notification.get("content").and_then(|c| c.as_str()),
-                                ) {
-                                    let mut notifications_lock = notifications.write().unwrap();
-                                    notifications_lock
-                                        .entry(player_id.to_owned())
-                                        .or_insert_with(Vec::new)
-                                        .push(content.to_owned());
-                                }
+                    failures = 0;
+                    health.set_consumer_subscribed(true);
+                    match queue_notification(&notifications, msg.payload()) {
+                        Ok(()) => {
+                            if let Err(err) = consumer.store_offset_from_message(&msg) {
+                                tracing::warn!(error = %err, "Failed to store notification offset");
                             }
                         }
+                        Err(err) => tracing::error!(error = %err, "Dropped a notification"),
                     }
-                    consumer.commit_message(&msg, CommitMode::Async).unwrap();
                 }
                 Err(e) => {
-                    eprintln!("Error while receiving from Redpanda: {:?}", e);
-                    actix_web::rt::time::sleep(Duration::from_secs(1)).await;
+                    failures += 1;
+                    let delay = retry_delay(failures);
+                    tracing::warn!(error = %e, retry_in_secs = delay.as_secs(), "Error while receiving from Redpanda");
+                    // Not ready again until a message comes through
+                    health.set_consumer_subscribed(false);
+                    actix_web::rt::time::sleep(delay).await;
                 }
             }
         }
     });
+    handle
 }
\ No newline at end of file